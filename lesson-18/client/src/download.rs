@@ -0,0 +1,124 @@
+//! Attachment downloads started by `.get <id> [dest]`
+//!
+//! The server sends attachment content in chunks of up to `ATTACHMENT_CHUNK_SIZE` bytes, one chunk
+//! per request. Every received chunk is appended to the target file and the next one is requested
+//! right away, the finished file is checked against the SHA-256 of the attachment.
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use flume::WeakSender;
+use library::blob_store::BlobStore;
+use library::{
+    create_download_file, AttachmentChunk, AttachmentRequest, DataProcessingError, MessageType,
+};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Downloads requested by the user, with their destination, until the first chunk arrives
+#[derive(Default)]
+pub(crate) struct Downloads {
+    requested: Mutex<HashMap<String, Option<PathBuf>>>,
+}
+
+impl Downloads {
+    /// Remembers where the attachment goes, `None` writes it into the files dir
+    pub(crate) fn request(&self, id: &str, dest: Option<PathBuf>) {
+        self.requested.lock().unwrap().insert(id.to_string(), dest);
+    }
+
+    /// Forgets the request, returns its destination if the attachment was requested
+    pub(crate) fn take(&self, id: &str) -> Option<Option<PathBuf>> {
+        self.requested.lock().unwrap().remove(id)
+    }
+}
+
+/// Files of downloads in progress, owned by the task reading from server
+pub(crate) struct DownloadFiles {
+    open: HashMap<String, (File, PathBuf)>,
+}
+
+impl DownloadFiles {
+    pub(crate) fn new() -> Self {
+        DownloadFiles {
+            open: HashMap::new(),
+        }
+    }
+
+    /// Writes the chunk and requests the next one through `tx`, a failed download is abandoned
+    pub(crate) async fn handle_chunk(
+        &mut self,
+        chunk: AttachmentChunk,
+        downloads: &Downloads,
+        files_dir: &Path,
+        tx: &WeakSender<MessageType>,
+    ) {
+        let id = chunk.attachment.id.clone();
+        if let Err(e) = self.write_chunk(chunk, downloads, files_dir, tx).await {
+            log::error!("Download of attachment {} failed: {}", id, e);
+            if let Some((_, path)) = self.open.remove(&id) {
+                let _ = fs::remove_file(path).await;
+            }
+        }
+    }
+
+    async fn write_chunk(
+        &mut self,
+        chunk: AttachmentChunk,
+        downloads: &Downloads,
+        files_dir: &Path,
+        tx: &WeakSender<MessageType>,
+    ) -> Result<(), DataProcessingError> {
+        let attachment = &chunk.attachment;
+        if chunk.offset == 0 {
+            let Some(dest) = downloads.take(&attachment.id) else {
+                log::warn!("Attachment {} was not requested", attachment.id);
+                return Ok(());
+            };
+            let file = create_download_file(dest.as_deref(), &attachment.name, files_dir).await?;
+            log::info!(
+                "Downloading {} ({} bytes) to {:?}",
+                attachment.name,
+                attachment.size,
+                file.1
+            );
+            self.open.insert(attachment.id.clone(), file);
+        }
+        let Some((file, path)) = self.open.get_mut(&attachment.id) else {
+            log::warn!("Attachment {} is not being downloaded", attachment.id);
+            return Ok(());
+        };
+        file.write_all(&chunk.data).await?;
+
+        if chunk.is_last() {
+            file.flush().await?;
+            // A corrupted file stays open, so it is removed along with the failed download
+            if BlobStore::hash(&fs::read(&path).await?) != attachment.hash {
+                return Err(DataProcessingError::ChecksumMismatch(
+                    path.display().to_string(),
+                ));
+            }
+            let path = path.clone();
+            self.open.remove(&attachment.id);
+            log::info!("Attachment {} written to: {:?}", attachment.name, path);
+            return Ok(());
+        }
+        // No progress would request the same chunk forever
+        if chunk.data.is_empty() {
+            return Err(DataProcessingError::InvalidFormat);
+        }
+        let next = AttachmentRequest::Download {
+            id: attachment.id.clone(),
+            offset: chunk.offset + chunk.data.len() as u64,
+        };
+        // Gone once the user quits
+        let sent = tx
+            .upgrade()
+            .is_some_and(|tx| tx.send(MessageType::AttachmentRequest(next)).is_ok());
+        if !sent {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+        }
+        Ok(())
+    }
+}
@@ -5,23 +5,74 @@
 //! # Example
 //! 
 //! ```bash
-//! ./client 127.0.0.1 8080 <guid>
+//! ./client --host chat.example.com --port 8080 --uid <guid>
+//! ./client --host ::1 --config chat.toml --set logging.level=debug
+//! ./client --help
 //! ```
 //! 
+//! Settings are read from the layered config, see `library::config`; named flags take precedence.
+//! 
 //! A simple client application to send messages to server, broadcasted to other clients.
 //! The messages can be both text or files/images.
-use std::{env, error::Error};
-
-use library::get_addr;
+use std::error::Error;
 
+use clap::Parser;
+use library::address::with_host_port;
+use library::config::{AppConfig, ConfigArgs};
+use uuid::Uuid;
 
-use crate::main_multi::start_multithreaded;
+use crate::main_multi::{start_multithreaded, ClientOptions};
+mod download;
 mod main_multi;
 
+/// Chat client - sends what you type to everyone connected to the chat server
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Hostname, IPv4 or IPv6 address of the server [default: from client.server_address]
+    #[arg(long)]
+    host: Option<String>,
+    /// Port of the server [default: from client.server_address]
+    #[arg(long)]
+    port: Option<u16>,
+    /// UID of this client [default: client.uid, or a newly generated one]
+    #[arg(long)]
+    uid: Option<Uuid>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    simple_logger::SimpleLogger::new().env().init().unwrap();    
-    // This runs lib function to parse hostname and port, or use default
+    let mut args = Args::parse();
+    if let Some(uid) = args.uid {
+        args.config.set("client.uid", uid.to_string());
+    }
+    let config = AppConfig::load_or_exit(&args.config);
+    let address = with_host_port(
+        &config.client.server_address,
+        args.host.as_deref(),
+        args.port,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    simple_logger::SimpleLogger::new()
+        .with_level(config.log_level())
+        .env()
+        .init()
+        .unwrap();
+
+    let options = ClientOptions {
+        address,
+        uid: match &config.client.uid {
+            Some(uid) => Uuid::try_parse(uid)?,
+            None => Uuid::new_v4(),
+        },
+        files_dir: config.storage.files_dir,
+        max_frame_size: config.limits.max_frame_size,
+    };
     // It is inherited from previous design, using two threads - one to read from stdin, one to send data to server
-    start_multithreaded(get_addr(env::args().collect()).unwrap()).await
+    start_multithreaded(options).await
 }
@@ -0,0 +1,19 @@
+-- Tables as created by lesson-15 and later, existing databases are adopted as they are
+CREATE TABLE IF NOT EXISTS users (
+    uid TEXT NOT NULL UNIQUE PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    message BLOB,
+    FOREIGN KEY(uid) REFERENCES users(uid)
+);
+
+CREATE TABLE IF NOT EXISTS message_views (
+    id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    FOREIGN KEY(id) REFERENCES messages(id),
+    FOREIGN KEY(uid) REFERENCES users(uid)
+);
@@ -0,0 +1,10 @@
+-- Messages are encoded back into `message` blobs by `migrations::restore_legacy_messages` first
+DROP INDEX IF EXISTS messages_uid;
+DROP INDEX IF EXISTS messages_kind;
+
+ALTER TABLE messages DROP COLUMN attachment_id;
+ALTER TABLE messages DROP COLUMN body;
+ALTER TABLE messages DROP COLUMN kind;
+
+DROP INDEX IF EXISTS attachments_hash;
+DROP TABLE IF EXISTS attachments;
@@ -0,0 +1,23 @@
+-- Messages are stored in columns instead of a single bincode blob, so they can be filtered in SQL.
+-- Existing blobs are converted by `migrations::convert_legacy_messages` right after this script,
+-- `message` stays set only for rows that could not be decoded.
+CREATE TABLE attachments (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    data BLOB NOT NULL
+);
+
+CREATE INDEX attachments_hash ON attachments (hash);
+
+-- kind: text, image, file, error, auth or shutdown (see `MessageKind`)
+ALTER TABLE messages ADD COLUMN kind TEXT;
+-- Text of text/error/auth/shutdown messages
+ALTER TABLE messages ADD COLUMN body TEXT;
+-- Content of image/file messages, points to attachments(id)
+ALTER TABLE messages ADD COLUMN attachment_id TEXT;
+
+CREATE INDEX messages_kind ON messages (kind);
+CREATE INDEX messages_uid ON messages (uid);
@@ -0,0 +1,18 @@
+-- Contents are read back from the blob store into `attachments.data` by
+-- `migrations::restore_inline_attachments` first, blob files are left in place
+CREATE TABLE attachments_old (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    data BLOB NOT NULL
+);
+
+INSERT INTO attachments_old (id, name, mime, size, hash, data)
+    SELECT id, name, mime, size, hash, data FROM attachments;
+DROP TABLE attachments;
+ALTER TABLE attachments_old RENAME TO attachments;
+CREATE INDEX attachments_hash ON attachments (hash);
+
+DROP TABLE blobs;
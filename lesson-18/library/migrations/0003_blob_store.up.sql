@@ -0,0 +1,27 @@
+-- Attachment contents move from `attachments.data` into the blob store (files named by SHA-256),
+-- done by `migrations::move_attachments_to_blobs` right after this script. `data` stays set only
+-- until the content is written to the blob store.
+CREATE TABLE attachments_new (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    data BLOB
+);
+
+INSERT INTO attachments_new (id, name, mime, size, hash, data)
+    SELECT id, name, mime, size, hash, data FROM attachments;
+DROP TABLE attachments;
+ALTER TABLE attachments_new RENAME TO attachments;
+CREATE INDEX attachments_hash ON attachments (hash);
+
+-- Stored blobs and the number of attachments referencing them
+CREATE TABLE blobs (
+    hash TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    refs INTEGER NOT NULL
+);
+
+INSERT INTO blobs (hash, size, refs)
+    SELECT hash, MAX(size), COUNT(*) FROM attachments GROUP BY hash;
@@ -0,0 +1,6 @@
+-- Blobs referenced only by thumbnails are left with no references, their files are removed by
+-- `db_client::collect_garbage` at the next start
+UPDATE blobs SET refs = refs - (SELECT COUNT(*) FROM thumbnails WHERE thumbnails.hash = blobs.hash);
+
+DROP TABLE thumbnails;
+DROP TABLE images;
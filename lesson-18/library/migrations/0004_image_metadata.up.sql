@@ -0,0 +1,20 @@
+-- Dimensions and original format of image attachments. Images stored before this version are
+-- processed by `migrations::add_image_metadata` right after this script, undecodable ones get no row.
+CREATE TABLE images (
+    attachment_id TEXT PRIMARY KEY,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    format TEXT NOT NULL
+);
+
+-- Downscaled PNG copies of images, contents are in the blob store and counted in `blobs.refs`
+CREATE TABLE thumbnails (
+    id TEXT PRIMARY KEY,
+    attachment_id TEXT NOT NULL,
+    max_size INTEGER NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL
+);
+CREATE INDEX thumbnails_attachment_id ON thumbnails (attachment_id);
@@ -0,0 +1,2 @@
+ALTER TABLE images DROP COLUMN duration_ms;
+ALTER TABLE images DROP COLUMN frames;
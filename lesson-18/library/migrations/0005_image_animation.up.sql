@@ -0,0 +1,4 @@
+-- Frame count and loop length of animated images, still images have a single frame. Animations
+-- recorded before this version are updated by `migrations::add_animation_metadata`.
+ALTER TABLE images ADD COLUMN frames INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE images ADD COLUMN duration_ms INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN quota_bytes;
+ALTER TABLE users DROP COLUMN used_bytes;
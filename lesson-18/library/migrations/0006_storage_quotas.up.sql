@@ -0,0 +1,12 @@
+-- Bytes of attachments of the messages each user sent, kept up to date by `db_client` whenever
+-- an attachment is stored or deleted. Attachments of legacy messages are counted as they are
+-- converted by `migrations::convert_legacy_messages`.
+ALTER TABLE users ADD COLUMN used_bytes INTEGER NOT NULL DEFAULT 0;
+-- Storage quota set by an admin, NULL for the default of the server, 0 for unlimited
+ALTER TABLE users ADD COLUMN quota_bytes INTEGER;
+
+UPDATE users SET used_bytes = (
+    SELECT COALESCE(SUM(a.size), 0) FROM messages m
+        JOIN attachments a ON a.id = m.attachment_id
+        WHERE m.uid = users.uid
+);
@@ -0,0 +1,14 @@
+-- Full-text index of text messages and of names and captions of files and images, see
+-- `library::search`. Rows are added by `db_client::save_message` and removed together with their
+-- messages, `message_id` points to messages(id).
+CREATE VIRTUAL TABLE message_search USING fts5(
+    body,
+    name,
+    message_id UNINDEXED,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO message_search (body, name, message_id)
+    SELECT m.body, a.name, m.id FROM messages m
+        LEFT JOIN attachments a ON a.id = m.attachment_id
+        WHERE m.kind = 'text' OR a.id IS NOT NULL;
@@ -0,0 +1,76 @@
+//! Network addresses given on command line or in config
+//!
+//! Addresses are kept as `host:port` strings, where host is a hostname, IPv4 or IPv6 address
+//! (IPv6 in brackets, e.g. `[::1]:11111`). They are resolved only when a connection is made or
+//! a listener is bound, so hostnames work both for the server and the client.
+use std::io;
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AddressError {
+    #[error("Invalid address `{0}`, expected <host>:<port>")]
+    Invalid(String),
+    #[error("Cannot resolve `{0}`: {1}")]
+    Resolve(String, io::Error),
+    #[error("No address found for `{0}`")]
+    NotFound(String),
+}
+
+/// Joins host and port into `host:port`, putting IPv6 addresses into brackets
+pub fn join_host_port(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+/// Splits `host:port` (or `[ipv6]:port`) into host and port
+pub fn split_host_port(address: &str) -> Result<(&str, u16), AddressError> {
+    let invalid = || AddressError::Invalid(address.to_string());
+    let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']').ok_or_else(invalid)?,
+        // Unbracketed IPv6 would be ambiguous
+        None if host.contains(':') => return Err(invalid()),
+        None => host,
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(invalid());
+    }
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    Ok((host, port))
+}
+
+/// Replaces host and/or port of `address` (e.g. from `--host` / `--port` flags), keeping the other part
+pub fn with_host_port(
+    address: &str,
+    host: Option<&str>,
+    port: Option<u16>,
+) -> Result<String, AddressError> {
+    let (default_host, default_port) = split_host_port(address)?;
+    let joined = join_host_port(host.unwrap_or(default_host), port.unwrap_or(default_port));
+    // Validate the host given on command line
+    split_host_port(&joined)?;
+    Ok(joined)
+}
+
+/// Resolves `host:port` (DNS names included) to socket addresses, in order returned by resolver
+pub async fn resolve_all(address: &str) -> Result<Vec<SocketAddr>, AddressError> {
+    let (host, port) = split_host_port(address)?;
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| AddressError::Resolve(address.to_string(), e))?
+        .collect();
+    match addrs.is_empty() {
+        true => Err(AddressError::NotFound(address.to_string())),
+        false => Ok(addrs),
+    }
+}
+
+/// Resolves `host:port` to the first socket address found
+pub async fn resolve(address: &str) -> Result<SocketAddr, AddressError> {
+    Ok(resolve_all(address).await?[0])
+}
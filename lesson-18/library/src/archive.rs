@@ -0,0 +1,362 @@
+//! Export and import of users, messages and attachments in a portable format
+//!
+//! An archive is a directory, or the same directory packed into a `.tar` file:
+//! - `users.jsonl` - one `User` per line
+//! - `messages.jsonl` - one `ArchivedMessage` per line, oldest first
+//! - `attachments/` - content of files and images, laid out as a `BlobStore`
+//!
+//! Import is idempotent: messages are identified by their ID and those stored already are skipped,
+//! so an archive can be imported again, or into a store holding part of it. Users are created as
+//! needed and get the quota set in the archive, storage they use is counted from the imported
+//! attachments. Attachments get new IDs.
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::blob_store::BlobStore;
+use crate::envelope;
+use crate::store::{Store, StoreError};
+use crate::{AttachmentInfo, Message, MessageType, User};
+
+pub const USERS_FILE: &str = "users.jsonl";
+pub const MESSAGES_FILE: &str = "messages.jsonl";
+pub const ATTACHMENTS_DIR: &str = "attachments";
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("{file} line {line}: {error}")]
+    Invalid {
+        file: &'static str,
+        line: usize,
+        error: String,
+    },
+    #[error("{0} exists already")]
+    Exists(PathBuf),
+    #[error("Cannot serialize: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("{0}")]
+    Store(#[from] StoreError),
+}
+
+/// Which users and messages are exported, everything by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportFilter {
+    /// Only these users and their messages, all users if empty
+    pub uids: Vec<String>,
+    /// Only messages sent at or after, milliseconds since UNIX epoch
+    pub since: Option<u64>,
+    /// Only messages sent at or before, milliseconds since UNIX epoch
+    pub until: Option<u64>,
+}
+
+impl ExportFilter {
+    /// True if the user passes the filter
+    pub fn accepts_user(&self, uid: &str) -> bool {
+        self.uids.is_empty() || self.uids.iter().any(|filter| filter == uid)
+    }
+
+    /// True if the message passes the filter
+    pub fn accepts(&self, message: &Message) -> bool {
+        let time = message.timestamp.parse::<u64>().unwrap_or_default();
+        self.accepts_user(&message.uid)
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+    }
+}
+
+/// Line of `messages.jsonl` - a `Message`, content of its attachment is stored separately
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchivedMessage {
+    pub id: String,
+    pub uid: String,
+    pub timestamp: String,
+    /// `MessageKind` of the message, e.g. `text` or `file`
+    pub kind: String,
+    /// Text, or caption of a file or image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// File or image, its content is in the attachments directory under `sha256`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<AttachmentInfo>,
+}
+
+impl ArchivedMessage {
+    /// Archived message with the content of its attachment, if any
+    pub fn new(message: &Message) -> (Self, Option<&[u8]>) {
+        let (body, _, data) = envelope::message_parts(&message.message);
+        let attachment = match &message.message {
+            MessageType::Image(info, _) | MessageType::File(info, _) => Some(info.clone()),
+            _ => None,
+        };
+        let archived = ArchivedMessage {
+            id: message.id.clone(),
+            uid: message.uid.clone(),
+            timestamp: message.timestamp.clone(),
+            kind: message.message.kind().as_str().to_string(),
+            body: body.map(|body| body.into_owned()),
+            attachment,
+        };
+        (archived, data)
+    }
+
+    /// Message back, with `data` as the content of its attachment
+    pub fn into_message(self, data: Option<Vec<u8>>) -> Result<Message, String> {
+        let name = self.attachment.as_ref().map(|info| info.name.clone());
+        let message = envelope::message_from_parts(&self.kind, self.body, name, data)
+            .map_err(|e| e.to_string())?;
+        if let (Some(archived), MessageType::Image(info, _) | MessageType::File(info, _)) =
+            (&self.attachment, &message)
+        {
+            if archived.sha256 != info.sha256 {
+                return Err(format!(
+                    "content of {} does not match its SHA-256",
+                    info.name
+                ));
+            }
+        }
+        Ok(Message {
+            id: self.id,
+            uid: self.uid,
+            timestamp: self.timestamp,
+            message,
+            attachment_id: None,
+        })
+    }
+}
+
+/// What was exported or imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ArchiveReport {
+    pub users: u64,
+    pub messages: u64,
+    pub attachments: u64,
+    /// Size of the attachments
+    pub bytes: u64,
+    /// Messages not imported as they were stored already
+    pub skipped: u64,
+}
+
+impl Display for ArchiveReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} users, {} messages, {} attachments ({} bytes)",
+            self.users, self.messages, self.attachments, self.bytes
+        )?;
+        if self.skipped > 0 {
+            write!(f, ", {} messages stored already", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes users and messages passing the filter to a new archive, a `.tar` file or a directory
+pub async fn export(
+    store: &dyn Store,
+    path: &Path,
+    filter: &ExportFilter,
+) -> Result<ArchiveReport, ArchiveError> {
+    if !is_tar(path) {
+        return export_dir(store, path, filter).await;
+    }
+    if fs::try_exists(path).await? {
+        return Err(ArchiveError::Exists(path.to_path_buf()));
+    }
+    // Next to the archive, so there is room for the attachments
+    let dir = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    let report = match export_dir(store, &dir, filter).await {
+        Ok(report) => pack(&dir, path).await.map(|_| report),
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_dir_all(&dir).await;
+    report
+}
+
+/// Reads an archive written by `export`, a `.tar` file or a directory, into the store
+pub async fn import(store: &dyn Store, path: &Path) -> Result<ArchiveReport, ArchiveError> {
+    if !is_tar(path) {
+        return import_dir(store, path).await;
+    }
+    let dir = std::env::temp_dir().join(format!("chat-import-{}", Uuid::new_v4()));
+    let report = match unpack(path, &dir).await {
+        Ok(_) => import_dir(store, &dir).await,
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_dir_all(&dir).await;
+    report
+}
+
+fn is_tar(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tar"))
+}
+
+async fn export_dir(
+    store: &dyn Store,
+    dir: &Path,
+    filter: &ExportFilter,
+) -> Result<ArchiveReport, ArchiveError> {
+    if fs::try_exists(dir.join(MESSAGES_FILE)).await? {
+        return Err(ArchiveError::Exists(dir.join(MESSAGES_FILE)));
+    }
+    fs::create_dir_all(dir).await?;
+    let mut report = ArchiveReport::default();
+
+    let mut users = String::new();
+    for user in store.users().await? {
+        if filter.accepts_user(&user.uid) {
+            users.push_str(&serde_json::to_string(&user)?);
+            users.push('\n');
+            report.users += 1;
+        }
+    }
+    fs::write(dir.join(USERS_FILE), users).await?;
+
+    let attachments = BlobStore::new(dir.join(ATTACHMENTS_DIR));
+    let mut messages: Vec<Message> = store
+        .messages()
+        .await?
+        .into_iter()
+        .filter(|message| filter.accepts(message))
+        .collect();
+    messages.sort_by_key(|message| message.timestamp.parse::<u64>().unwrap_or_default());
+    let mut lines = String::new();
+    for message in &messages {
+        let (archived, data) = ArchivedMessage::new(message);
+        if let Some(data) = data {
+            attachments.put(data).await?;
+            report.attachments += 1;
+            report.bytes += data.len() as u64;
+        }
+        lines.push_str(&serde_json::to_string(&archived)?);
+        lines.push('\n');
+        report.messages += 1;
+    }
+    fs::write(dir.join(MESSAGES_FILE), lines).await?;
+    Ok(report)
+}
+
+async fn import_dir(store: &dyn Store, dir: &Path) -> Result<ArchiveReport, ArchiveError> {
+    let mut report = ArchiveReport::default();
+    let mut known = HashSet::new();
+    for (line, user) in read_lines::<User>(dir, USERS_FILE).await? {
+        let uid = parse_uid(&user.uid, USERS_FILE, line)?;
+        store.auth_user(uid).await?;
+        if user.quota_bytes.is_some() {
+            store.set_quota(&user.uid, user.quota_bytes).await?;
+        }
+        known.insert(user.uid);
+        report.users += 1;
+    }
+
+    let attachments = BlobStore::new(dir.join(ATTACHMENTS_DIR));
+    for (line, archived) in read_lines::<ArchivedMessage>(dir, MESSAGES_FILE).await? {
+        let invalid = |error: String| ArchiveError::Invalid {
+            file: MESSAGES_FILE,
+            line,
+            error,
+        };
+        // Not listed in users.jsonl
+        if !known.contains(&archived.uid) {
+            store
+                .auth_user(parse_uid(&archived.uid, MESSAGES_FILE, line)?)
+                .await?;
+            known.insert(archived.uid.clone());
+        }
+        let data = match &archived.attachment {
+            Some(info) => Some(
+                attachments
+                    .get(&info.sha256)
+                    .await
+                    .map_err(|e| invalid(format!("content of {}: {}", info.name, e)))?,
+            ),
+            None => None,
+        };
+        let size = data.as_ref().map(|data| data.len() as u64);
+        let message = archived.into_message(data).map_err(invalid)?;
+        if !store.import_message(&message).await? {
+            report.skipped += 1;
+            continue;
+        }
+        report.messages += 1;
+        if let Some(size) = size {
+            report.attachments += 1;
+            report.bytes += size;
+        }
+    }
+    Ok(report)
+}
+
+/// Parsed non-empty lines of a JSON Lines file of the archive, with their line numbers
+async fn read_lines<T: DeserializeOwned>(
+    dir: &Path,
+    file: &'static str,
+) -> Result<Vec<(usize, T)>, ArchiveError> {
+    let path = dir.join(file);
+    let content = fs::read_to_string(&path)
+        .await
+        .map_err(|e| ArchiveError::Read(path, e))?;
+    let mut items = Vec::new();
+    for (i, text) in content.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let item = serde_json::from_str(text).map_err(|e| ArchiveError::Invalid {
+            file,
+            line: i + 1,
+            error: e.to_string(),
+        })?;
+        items.push((i + 1, item));
+    }
+    Ok(items)
+}
+
+fn parse_uid(uid: &str, file: &'static str, line: usize) -> Result<Uuid, ArchiveError> {
+    uid.parse().map_err(|e| ArchiveError::Invalid {
+        file,
+        line,
+        error: format!("invalid uid {}: {}", uid, e),
+    })
+}
+
+/// Packs the content of `dir` into a new tar file, nothing is left behind on failure
+async fn pack(dir: &Path, path: &Path) -> Result<(), ArchiveError> {
+    let (dir, path) = (dir.to_path_buf(), path.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let result = std::fs::File::create(&path).and_then(|file| {
+            let mut builder = tar::Builder::new(file);
+            builder.append_dir_all(".", &dir)?;
+            builder.into_inner()?.sync_all()
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&path);
+        }
+        result
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(())
+}
+
+/// Unpacks a tar file into `dir`, entries pointing outside of it are skipped
+async fn unpack(path: &Path, dir: &Path) -> Result<(), ArchiveError> {
+    let (path, dir) = (path.to_path_buf(), dir.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path).map_err(|e| ArchiveError::Read(path, e))?;
+        tar::Archive::new(file).unpack(&dir)?;
+        Ok(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
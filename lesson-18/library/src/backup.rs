@@ -0,0 +1,259 @@
+//! Online backup and verified restore of the SQLite database together with its blob store
+//!
+//! A backup is a new directory with:
+//! - `chat.db` - consistent copy of the database, written by `VACUUM INTO` while it stays in use
+//! - `blobs/` - contents of attachments and thumbnails the copy refers to, laid out as a `BlobStore`
+//!
+//! Restore needs the server (and webapp) stopped. The backup is checked first - `PRAGMA
+//! integrity_check`, a schema version this binary can open, and every referenced blob present
+//! with its SHA-256 - and only then swapped in. Database and blob store it replaces are kept next
+//! to them, with a `.before-restore-<timestamp>` suffix.
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{Pool, Sqlite};
+use thiserror::Error;
+use tokio::fs;
+
+use crate::blob_store::BlobStore;
+use crate::db_client::DatabaseConfig;
+use crate::get_timestamp;
+use crate::migrations::{self, MigrationError, BLOB_STORE_VERSION};
+use crate::store::StoreBackend;
+
+pub const DATABASE_FILE: &str = "chat.db";
+pub const BLOBS_DIR: &str = "blobs";
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Migration(#[from] MigrationError),
+    #[error("{0} exists already")]
+    Exists(PathBuf),
+    #[error("Backup is damaged: {0}")]
+    Damaged(String),
+    #[error("{0} store cannot be backed up or restored")]
+    Unsupported(StoreBackend),
+}
+
+/// What a backup holds
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BackupReport {
+    /// Size of the database copy
+    pub database_bytes: u64,
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    /// Referenced blobs which were gone, removed with their messages while the backup was made
+    pub missing_blobs: u64,
+}
+
+impl Display for BackupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "database of {} bytes, {} blobs ({} bytes)",
+            self.database_bytes, self.blobs, self.blob_bytes
+        )?;
+        if self.missing_blobs > 0 {
+            write!(f, ", {} blobs missing", self.missing_blobs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the database and blobs it refers to into a new directory, without stopping its users
+///
+/// Blobs are copied after the database, one deleted meanwhile is counted in `missing_blobs` and
+/// makes the backup fail `verify` - run it again then.
+pub async fn backup(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+    dir: &Path,
+) -> Result<BackupReport, BackupError> {
+    if fs::try_exists(dir).await? {
+        return Err(BackupError::Exists(dir.to_path_buf()));
+    }
+    fs::create_dir_all(dir).await?;
+    let database = dir.join(DATABASE_FILE);
+    sqlx::query("VACUUM INTO ?")
+        .bind(database.to_string_lossy())
+        .execute(pool)
+        .await?;
+
+    let mut report = BackupReport {
+        database_bytes: fs::metadata(&database).await?.len(),
+        ..Default::default()
+    };
+    let copy = open_copy(&database).await?;
+    let hashes = referenced_blobs(&copy).await;
+    copy.close().await;
+    let target = BlobStore::new(dir.join(BLOBS_DIR));
+    for hash in hashes? {
+        let path = target.path(&hash)?;
+        fs::create_dir_all(path.parent().expect("blob path has a parent")).await?;
+        match fs::copy(blobs.path(&hash)?, &path).await {
+            Ok(size) => {
+                report.blobs += 1;
+                report.blob_bytes += size;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warn!("Blob {} is gone, not backed up", hash);
+                report.missing_blobs += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(report)
+}
+
+/// Checks a backup directory, see the module docs
+pub async fn verify(dir: &Path) -> Result<BackupReport, BackupError> {
+    let database = dir.join(DATABASE_FILE);
+    if !fs::try_exists(&database).await? {
+        return Err(BackupError::Damaged(format!(
+            "{} not found",
+            database.display()
+        )));
+    }
+    let pool = open_copy(&database).await?;
+    let report = verify_copy(&pool, &BlobStore::new(dir.join(BLOBS_DIR))).await;
+    pool.close().await;
+    Ok(BackupReport {
+        database_bytes: fs::metadata(&database).await?.len(),
+        ..report?
+    })
+}
+
+/// Replaces the configured database and blob store with a verified backup
+///
+/// Nothing may use the database meanwhile. Pending migrations are applied on the next start.
+pub async fn restore(dir: &Path, config: &DatabaseConfig) -> Result<BackupReport, BackupError> {
+    let Some(database) = database_file(config)? else {
+        return Err(BackupError::Unsupported(config.backend));
+    };
+    let report = verify(dir).await?;
+    let suffix = format!("before-restore-{}", get_timestamp());
+
+    // Copied next to the targets first, so the swap itself is just renaming
+    let blobs_tmp = sibling(&config.blobs_dir, "restore.tmp");
+    let database_tmp = sibling(&database, "restore.tmp");
+    let copied = async {
+        copy_blobs(
+            &BlobStore::new(dir.join(BLOBS_DIR)),
+            &BlobStore::new(&blobs_tmp),
+        )
+        .await?;
+        fs::copy(dir.join(DATABASE_FILE), &database_tmp).await
+    }
+    .await;
+    if let Err(e) = copied {
+        let _ = fs::remove_dir_all(&blobs_tmp).await;
+        let _ = fs::remove_file(&database_tmp).await;
+        return Err(e.into());
+    }
+
+    if fs::try_exists(&config.blobs_dir).await? {
+        fs::rename(&config.blobs_dir, sibling(&config.blobs_dir, &suffix)).await?;
+    }
+    fs::rename(&blobs_tmp, &config.blobs_dir).await?;
+    // A write-ahead log left behind would be applied to the restored database
+    for extension in ["", "-wal", "-shm"] {
+        let path = with_suffix(&database, extension);
+        if fs::try_exists(&path).await? {
+            fs::rename(&path, with_suffix(&sibling(&database, &suffix), extension)).await?;
+        }
+    }
+    fs::rename(&database_tmp, &database).await?;
+    Ok(report)
+}
+
+/// Integrity, schema version and blobs of a database copy
+async fn verify_copy(pool: &Pool<Sqlite>, blobs: &BlobStore) -> Result<BackupReport, BackupError> {
+    let problems: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(pool)
+        .await?;
+    if problems != ["ok"] {
+        return Err(BackupError::Damaged(problems.join("; ")));
+    }
+    migrations::check_version(pool).await?;
+    let mut report = BackupReport::default();
+    for hash in referenced_blobs(pool).await? {
+        let data = blobs
+            .get(&hash)
+            .await
+            .map_err(|e| BackupError::Damaged(format!("blob {}: {}", hash, e)))?;
+        if BlobStore::hash(&data) != hash {
+            return Err(BackupError::Damaged(format!(
+                "blob {} does not match its SHA-256",
+                hash
+            )));
+        }
+        report.blobs += 1;
+        report.blob_bytes += data.len() as u64;
+    }
+    Ok(report)
+}
+
+/// Hashes of blobs in the `blobs` table, none before the blob store was introduced
+async fn referenced_blobs(pool: &Pool<Sqlite>) -> Result<Vec<String>, BackupError> {
+    if migrations::schema_version(pool).await? < BLOB_STORE_VERSION {
+        return Ok(Vec::new());
+    }
+    Ok(sqlx::query_scalar("SELECT hash FROM blobs")
+        .fetch_all(pool)
+        .await?)
+}
+
+async fn copy_blobs(from: &BlobStore, to: &BlobStore) -> io::Result<()> {
+    for hash in from.hashes().await? {
+        let path = to.path(&hash)?;
+        fs::create_dir_all(path.parent().expect("blob path has a parent")).await?;
+        fs::copy(from.path(&hash)?, path).await?;
+    }
+    fs::create_dir_all(to.root()).await
+}
+
+/// Opens a database copy, writable as checking FTS5 tables needs it, without a write-ahead log
+async fn open_copy(database: &Path) -> Result<Pool<Sqlite>, sqlx::Error> {
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(
+            SqliteConnectOptions::new()
+                .filename(database)
+                .journal_mode(SqliteJournalMode::Delete),
+        )
+        .await
+}
+
+/// Database file of the configured SQLite store, `None` for one kept in memory
+fn database_file(config: &DatabaseConfig) -> Result<Option<PathBuf>, BackupError> {
+    if config.backend != StoreBackend::Sqlite
+        || config.url.contains(":memory:")
+        || config.url.contains("mode=memory")
+    {
+        return Ok(None);
+    }
+    let options = SqliteConnectOptions::from_str(&config.url)?;
+    Ok(Some(options.get_filename().into_owned()))
+}
+
+/// `path` with `.<suffix>` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    with_suffix(path, &format!(".{}", suffix))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    // Without a trailing slash
+    let mut name = OsString::from(path.components().as_path().as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,134 @@
+//! Content-addressed storage of attachment contents
+//!
+//! Every blob is stored once under `<root>/<first 2 hex chars>/<SHA-256 hex>`, no matter how many
+//! messages reference it. References are counted in the `blobs` table of the database (see
+//! `db_client`), a blob is removed once no attachment points to it anymore.
+//!
+//! `put` skips content stored already, so a blob written for a new reference must not be removed
+//! for an old one meanwhile. Writers hold `adding` from `put` until the reference is committed,
+//! removals hold `removing` from releasing the last reference until the file is gone.
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+/// Files named by the SHA-256 of their content
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+    /// Shared by clones, so all users of one store in the process see the same lock
+    lock: Arc<RwLock<()>>,
+}
+
+impl BlobStore {
+    /// Store under `root`, relative paths are resolved against current dir. Nothing is created until
+    /// the first blob is written.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        BlobStore {
+            root: root.into(),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Held while blobs are written and their references committed, by any number of writers.
+    /// Take it before the database transaction, so the order is always the same.
+    pub async fn adding(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().await
+    }
+
+    /// Held alone while references are released and blobs left without one removed.
+    /// Take it before the database transaction, so the order is always the same.
+    pub async fn removing(&self) -> RwLockWriteGuard<'_, ()> {
+        self.lock.write().await
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// SHA-256 of data, hex encoded - the key of the blob
+    pub fn hash(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    /// Path of the blob, fails for anything that is not a SHA-256 hex digest
+    pub fn path(&self, hash: &str) -> io::Result<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid blob hash {:?}", hash),
+            ));
+        }
+        let hash = hash.to_ascii_lowercase();
+        Ok(self.root.join(&hash[..2]).join(hash))
+    }
+
+    /// Stores data unless the same content is stored already, returns its hash
+    pub async fn put(&self, data: &[u8]) -> io::Result<String> {
+        let hash = Self::hash(data);
+        let path = self.path(&hash)?;
+        if fs::try_exists(&path).await? {
+            return Ok(hash);
+        }
+        let dir = path.parent().expect("blob path has a parent");
+        fs::create_dir_all(dir).await?;
+        // Written under a temporary name first, so a blob is either complete or missing
+        let tmp = dir.join(format!(".{}.{}.tmp", hash, Uuid::new_v4()));
+        fs::write(&tmp, data).await?;
+        if let Err(e) = fs::rename(&tmp, &path).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        Ok(hash)
+    }
+
+    /// Content of the blob
+    pub async fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(hash)?).await
+    }
+
+    /// Up to `len` bytes of the blob starting at `offset`, empty at or beyond its end
+    pub async fn read_range(&self, hash: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.path(hash)?).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = Vec::new();
+        file.take(len as u64).read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Removes the blob, a missing blob is not an error
+    pub async fn remove(&self, hash: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(hash)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hashes of all stored blobs
+    pub async fn hashes(&self) -> io::Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        let mut dirs = match fs::read_dir(&self.root).await {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(hashes),
+            Err(e) => return Err(e),
+        };
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = fs::read_dir(dir.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name().to_string_lossy().to_string();
+                if self.path(&name).is_ok() {
+                    hashes.push(name);
+                }
+            }
+        }
+        Ok(hashes)
+    }
+}
@@ -0,0 +1,634 @@
+//! Layered configuration shared by server, webapp and client
+//!
+//! All three binaries read the same typed TOML config. Values are layered, each layer overriding the previous one:
+//! 1. built-in defaults
+//! 2. config file - `--config <file>`, `CHAT_CONFIG` env variable or `chat.toml` in current dir (if it exists)
+//! 3. environment variables - `CHAT__<SECTION>__<KEY>`, e.g. `CHAT__SERVER__ADDRESS=0.0.0.0:11111`
+//!    (`DATABASE_URL` is honored as well, as it is used by sqlx)
+//! 4. command line - `--set <section>.<key>=<value>`, e.g. `--set limits.max_frame_size=1048576`,
+//!    and named flags of each binary like `--host` / `--port`
+//!
+//! # Example
+//! ```toml
+//! [server]
+//! address = "127.0.0.1:11111"
+//!
+//! [database]
+//! url = "sqlite:./local.db"
+//! ```
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteJournalMode;
+use thiserror::Error;
+use toml::Value;
+
+use crate::address::split_host_port;
+use crate::db_client::DatabaseConfig;
+use crate::images::{ImagePolicy, OutputFormat};
+use crate::mime::{self, UploadPolicy};
+use crate::retention::{RetentionPolicy, RetentionRule};
+use crate::store::StoreBackend;
+
+/// Env variable with path to the config file
+pub const CONFIG_ENV: &str = "CHAT_CONFIG";
+/// Config file used when no other is given, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "chat.toml";
+/// Prefix of env variables overriding config values
+pub const ENV_PREFIX: &str = "CHAT__";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Cannot read config file {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Cannot parse config file {0:?}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid override `{0}`, expected <section>.<key>=<value>")]
+    Override(String),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+    #[error("Invalid config:\n  {}", .0.join("\n  "))]
+    Validation(Vec<String>),
+}
+
+/// Complete configuration of the chat application
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub server: ServerSection,
+    pub database: DatabaseSection,
+    pub storage: StorageSection,
+    pub limits: LimitsSection,
+    pub images: ImagesSection,
+    pub uploads: UploadsSection,
+    pub retention: RetentionSection,
+    pub moderation: ModerationSection,
+    pub metrics: MetricsSection,
+    pub logging: LoggingSection,
+    pub client: ClientSection,
+    pub webapp: WebappSection,
+}
+
+/// Chat server settings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    /// Address the chat server listens on, `host:port` (hostname, IPv4 or `[IPv6]`)
+    pub address: String,
+    /// How many messages can wait in a single client's outbox
+    pub outbox_capacity: usize,
+    /// `drop_oldest`, `disconnect` or `block`
+    pub slow_consumer_policy: String,
+    /// How long to wait for outboxes to be flushed when shutting down
+    pub shutdown_grace_secs: u64,
+    /// Message of the day, sent to every client after it authenticates
+    pub motd: Option<String>,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        ServerSection {
+            address: "127.0.0.1:11111".to_string(),
+            outbox_capacity: 64,
+            slow_consumer_policy: "drop_oldest".to_string(),
+            shutdown_grace_secs: 10,
+            motd: None,
+        }
+    }
+}
+
+/// Database settings, see `DatabaseConfig`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    /// `sqlite`, or `memory` to keep everything in memory (nothing is persisted)
+    pub backend: String,
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub journal_mode: String,
+    pub busy_timeout_ms: u64,
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for DatabaseSection {
+    fn default() -> Self {
+        DatabaseSection {
+            backend: "sqlite".to_string(),
+            url: "sqlite:./local.db".to_string(),
+            max_connections: 10,
+            min_connections: 1,
+            journal_mode: "wal".to_string(),
+            busy_timeout_ms: 5000,
+            idle_timeout_secs: 600,
+        }
+    }
+}
+
+/// Where received files and images are stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    /// Client: received files, relative paths are resolved against current dir
+    pub files_dir: PathBuf,
+    /// Server: contents of attachments, stored once per SHA-256 (see `blob_store`)
+    pub blobs_dir: PathBuf,
+    /// Server: bytes of attachments each user can store, 0 for unlimited. Admins can set
+    /// another quota for a single user in the webapp.
+    pub user_quota_bytes: u64,
+    /// Server: where the webapp writes backups of the database and blobs (see `backup`)
+    pub backups_dir: PathBuf,
+}
+
+impl Default for StorageSection {
+    fn default() -> Self {
+        StorageSection {
+            files_dir: PathBuf::from("files"),
+            blobs_dir: PathBuf::from("blobs"),
+            user_quota_bytes: 0,
+            backups_dir: PathBuf::from("backups"),
+        }
+    }
+}
+
+/// Protocol limits
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    /// Largest frame (serialized message) accepted from the other side, in bytes
+    pub max_frame_size: usize,
+    /// Messages a single client can send per second on average, 0 means unlimited
+    pub messages_per_sec: f64,
+    /// Messages a client can send at once before the rate limit kicks in
+    pub message_burst: u32,
+    /// Chunk requests of downloads a single client can send per second on average, 0 means
+    /// unlimited. Requests over the limit are answered later, not refused.
+    pub attachment_requests_per_sec: f64,
+    /// Chunk requests answered at once before the limit kicks in
+    pub attachment_request_burst: u32,
+}
+
+impl Default for LimitsSection {
+    fn default() -> Self {
+        LimitsSection {
+            max_frame_size: crate::DEFAULT_MAX_FRAME_SIZE,
+            messages_per_sec: 0.0,
+            message_burst: 10,
+            attachment_requests_per_sec: 0.0,
+            attachment_request_burst: 64,
+        }
+    }
+}
+
+/// What images the server accepts and how it stores them, see `images::ImagePolicy`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesSection {
+    /// Larger images are downscaled to fit, keeping the aspect ratio
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Larger images are rejected
+    pub max_bytes: usize,
+    /// Re-encode every image, dropping EXIF (GPS position, camera details) and other metadata
+    pub strip_metadata: bool,
+    /// `original`, `png`, `jpeg` or `webp` (lossless)
+    pub format: String,
+    /// 1 - 100
+    pub jpeg_quality: u8,
+}
+
+impl Default for ImagesSection {
+    fn default() -> Self {
+        let policy = ImagePolicy::default();
+        ImagesSection {
+            max_width: policy.max_width,
+            max_height: policy.max_height,
+            max_bytes: policy.max_bytes,
+            strip_metadata: policy.strip_metadata,
+            format: policy.format.to_string(),
+            jpeg_quality: policy.jpeg_quality,
+        }
+    }
+}
+
+/// Which files and images the server accepts by their content type, see `mime::UploadPolicy`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsSection {
+    /// MIME types (like `application/pdf` or `image/*`) accepted, any type if empty
+    pub allowed_types: Vec<String>,
+    /// MIME types refused even if allowed, executables by default
+    pub denied_types: Vec<String>,
+}
+
+impl Default for UploadsSection {
+    fn default() -> Self {
+        let policy = UploadPolicy::default();
+        UploadsSection {
+            allowed_types: policy.allowed_types,
+            denied_types: policy.denied_types,
+        }
+    }
+}
+
+/// How long the server keeps messages, see `retention::RetentionPolicy`. 0 means no limit.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSection {
+    /// Messages older than this are deleted
+    pub max_age_days: u64,
+    /// Only this many newest messages of each user are kept
+    pub max_messages_per_user: u64,
+    /// Messages with a file or image older than this are deleted
+    pub attachment_max_age_days: u64,
+    /// Only this many newest messages with a file or image of each user are kept
+    pub max_attachments_per_user: u64,
+    /// How often expired messages are deleted
+    pub interval_secs: u64,
+}
+
+impl Default for RetentionSection {
+    fn default() -> Self {
+        RetentionSection {
+            max_age_days: 0,
+            max_messages_per_user: 0,
+            attachment_max_age_days: 0,
+            max_attachments_per_user: 0,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+/// Clients not allowed on the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationSection {
+    /// UIDs refused on authentication
+    pub banned_uids: Vec<String>,
+    /// IP addresses refused on connect
+    pub banned_ips: Vec<String>,
+}
+
+/// Prometheus metrics endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    pub enabled: bool,
+    /// Address of the `/metrics` endpoint scraped by Prometheus
+    pub address: String,
+}
+
+impl Default for MetricsSection {
+    fn default() -> Self {
+        MetricsSection {
+            enabled: true,
+            address: "172.17.0.1:8001".to_string(),
+        }
+    }
+}
+
+/// Logging settings, `RUST_LOG` env variable still takes precedence
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub level: String,
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        LoggingSection {
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// Client settings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSection {
+    /// Address of the chat server to connect to, `host:port` (hostname, IPv4 or `[IPv6]`)
+    pub server_address: String,
+    /// UID of the client, a new one is generated if not set
+    pub uid: Option<String>,
+}
+
+impl Default for ClientSection {
+    fn default() -> Self {
+        ClientSection {
+            server_address: "127.0.0.1:11111".to_string(),
+            uid: None,
+        }
+    }
+}
+
+/// Webapp settings; anything not set is taken from Rocket.toml / Rocket defaults
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebappSection {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub template_dir: Option<PathBuf>,
+}
+
+/// Config related command line arguments, flattened into the argument parser of each binary
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
+pub struct ConfigArgs {
+    /// Config file [default: $CHAT_CONFIG or ./chat.toml if it exists]
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Override a config value, can be repeated
+    #[arg(long = "set", value_name = "SECTION.KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+}
+
+impl ConfigArgs {
+    /// Overrides a config value, taking precedence over `--set`
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        self.overrides.push((key.to_string(), value.into()));
+    }
+}
+
+/// Splits `section.key=value`
+pub fn parse_override(input: &str) -> Result<(String, String), ConfigError> {
+    match input.split_once('=') {
+        Some((key, value)) if key.contains('.') => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(ConfigError::Override(input.to_string())),
+    }
+}
+
+impl AppConfig {
+    /// Loads config like `load`, on error prints what is wrong and exits the process
+    pub fn load_or_exit(cli: &ConfigArgs) -> AppConfig {
+        match AppConfig::load(cli) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Loads config from all layers (defaults, file, env, command line) and validates it
+    pub fn load(cli: &ConfigArgs) -> Result<AppConfig, ConfigError> {
+        let env_overrides: Vec<(String, String)> = env::vars()
+            .filter_map(|(name, value)| env_override(&name).map(|key| (key, value)))
+            .collect();
+        let file = match &cli.config {
+            Some(path) => Some(path.clone()),
+            None => match env::var(CONFIG_ENV) {
+                Ok(path) => Some(PathBuf::from(path)),
+                Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+            },
+        };
+        AppConfig::load_layers(file.as_deref(), &env_overrides, &cli.overrides)
+    }
+
+    /// Builds config from given layers, without looking at the process environment
+    pub fn load_layers(
+        file: Option<&Path>,
+        env_overrides: &[(String, String)],
+        cli_overrides: &[(String, String)],
+    ) -> Result<AppConfig, ConfigError> {
+        let mut config = Value::try_from(AppConfig::default())
+            .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        if let Some(path) = file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+            let layer: Value =
+                toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+            merge(&mut config, layer);
+        }
+        for (key, value) in env_overrides.iter().chain(cli_overrides) {
+            set_value(&mut config, key, value)?;
+        }
+        let config: AppConfig = config
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Invalid(e.message().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks all values, reporting every problem found at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        if let Err(e) = split_host_port(&self.server.address) {
+            errors.push(format!("server.address: {}", e));
+        }
+        if self.server.outbox_capacity == 0 {
+            errors.push("server.outbox_capacity: must be greater than 0".to_string());
+        }
+        if let Err(e) = StoreBackend::from_str(&self.database.backend) {
+            errors.push(format!("database.backend: {}", e));
+        }
+        if self.database.url.is_empty() {
+            errors.push("database.url: must not be empty".to_string());
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections: must be greater than 0".to_string());
+        }
+        if self.database.min_connections > self.database.max_connections {
+            errors.push("database.min_connections: must not exceed max_connections".to_string());
+        }
+        if SqliteJournalMode::from_str(&self.database.journal_mode).is_err() {
+            errors.push(format!(
+                "database.journal_mode: unknown mode {}",
+                self.database.journal_mode
+            ));
+        }
+        if self.limits.max_frame_size == 0 {
+            errors.push("limits.max_frame_size: must be greater than 0".to_string());
+        }
+        if self.limits.messages_per_sec.is_nan() || self.limits.messages_per_sec < 0.0 {
+            errors.push("limits.messages_per_sec: must not be negative".to_string());
+        }
+        if self.limits.message_burst == 0 {
+            errors.push("limits.message_burst: must be greater than 0".to_string());
+        }
+        let requests_per_sec = self.limits.attachment_requests_per_sec;
+        if requests_per_sec.is_nan() || requests_per_sec < 0.0 {
+            errors.push("limits.attachment_requests_per_sec: must not be negative".to_string());
+        }
+        if self.limits.attachment_request_burst == 0 {
+            errors.push("limits.attachment_request_burst: must be greater than 0".to_string());
+        }
+        if self.images.max_width == 0 || self.images.max_height == 0 {
+            errors.push("images.max_width, images.max_height: must be greater than 0".to_string());
+        }
+        if self.images.max_bytes == 0 {
+            errors.push("images.max_bytes: must be greater than 0".to_string());
+        }
+        if let Err(e) = OutputFormat::from_str(&self.images.format) {
+            errors.push(format!("images.format: {}", e));
+        }
+        if !(1..=100).contains(&self.images.jpeg_quality) {
+            errors.push("images.jpeg_quality: must be between 1 and 100".to_string());
+        }
+        for pattern in self
+            .uploads
+            .allowed_types
+            .iter()
+            .chain(&self.uploads.denied_types)
+        {
+            if let Err(e) = mime::validate_type_pattern(pattern) {
+                errors.push(format!("uploads: {}", e));
+            }
+        }
+        if self.retention.interval_secs == 0 {
+            errors.push("retention.interval_secs: must be greater than 0".to_string());
+        }
+        for uid in &self.moderation.banned_uids {
+            if uuid::Uuid::try_parse(uid).is_err() {
+                errors.push(format!("moderation.banned_uids: invalid UID {}", uid));
+            }
+        }
+        for ip in &self.moderation.banned_ips {
+            if ip.parse::<IpAddr>().is_err() {
+                errors.push(format!("moderation.banned_ips: invalid IP address {}", ip));
+            }
+        }
+        if self.metrics.enabled && self.metrics.address.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "metrics.address: invalid address {}",
+                self.metrics.address
+            ));
+        }
+        if LevelFilter::from_str(&self.logging.level).is_err() {
+            errors.push(format!(
+                "logging.level: unknown level {}",
+                self.logging.level
+            ));
+        }
+        if let Err(e) = split_host_port(&self.client.server_address) {
+            errors.push(format!("client.server_address: {}", e));
+        }
+        if let Some(uid) = &self.client.uid {
+            if uuid::Uuid::try_parse(uid).is_err() {
+                errors.push(format!("client.uid: invalid UID {}", uid));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Validation(errors)),
+        }
+    }
+
+    /// Database pool settings
+    pub fn database_config(&self) -> DatabaseConfig {
+        DatabaseConfig {
+            backend: StoreBackend::from_str(&self.database.backend).unwrap_or_default(),
+            url: self.database.url.clone(),
+            max_connections: self.database.max_connections,
+            min_connections: self.database.min_connections,
+            journal_mode: SqliteJournalMode::from_str(&self.database.journal_mode)
+                .unwrap_or(SqliteJournalMode::Wal),
+            busy_timeout: Duration::from_millis(self.database.busy_timeout_ms),
+            idle_timeout: Duration::from_secs(self.database.idle_timeout_secs),
+            blobs_dir: self.storage.blobs_dir.clone(),
+        }
+    }
+
+    /// Image policy of the server
+    pub fn image_policy(&self) -> ImagePolicy {
+        ImagePolicy {
+            max_width: self.images.max_width,
+            max_height: self.images.max_height,
+            max_bytes: self.images.max_bytes,
+            strip_metadata: self.images.strip_metadata,
+            format: OutputFormat::from_str(&self.images.format).unwrap_or_default(),
+            jpeg_quality: self.images.jpeg_quality,
+        }
+    }
+
+    /// Content types the server accepts
+    pub fn upload_policy(&self) -> UploadPolicy {
+        UploadPolicy {
+            allowed_types: self.uploads.allowed_types.clone(),
+            denied_types: self.uploads.denied_types.clone(),
+        }
+    }
+
+    /// Which messages the server keeps
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        let days = |days: u64| (days > 0).then(|| Duration::from_secs(days * 24 * 60 * 60));
+        let count = |count: u64| (count > 0).then_some(count);
+        RetentionPolicy {
+            messages: RetentionRule {
+                max_age: days(self.retention.max_age_days),
+                max_per_user: count(self.retention.max_messages_per_user),
+            },
+            attachments: RetentionRule {
+                max_age: days(self.retention.attachment_max_age_days),
+                max_per_user: count(self.retention.max_attachments_per_user),
+            },
+        }
+    }
+
+    /// Log level, `Info` if not valid
+    pub fn log_level(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.logging.level).unwrap_or(LevelFilter::Info)
+    }
+}
+
+/// Maps `CHAT__SECTION__KEY` env variable name to `section.key`
+fn env_override(name: &str) -> Option<String> {
+    if name == "DATABASE_URL" {
+        return Some("database.url".to_string());
+    }
+    let key = name.strip_prefix(ENV_PREFIX)?;
+    Some(key.to_lowercase().replace("__", "."))
+}
+
+/// Recursively merges `layer` into `base`, tables are merged key by key, other values replaced
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Table(base), Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+/// Sets value at dotted path. String values stay strings, anything else is parsed as TOML value.
+fn set_value(config: &mut Value, key: &str, value: &str) -> Result<(), ConfigError> {
+    let (path, last) = match key.rsplit_once('.') {
+        Some((path, last)) => (path, last),
+        None => return Err(ConfigError::Override(format!("{}={}", key, value))),
+    };
+    let mut table = config;
+    for part in path.split('.') {
+        table = table
+            .as_table_mut()
+            .ok_or_else(|| ConfigError::Override(format!("{}={}", key, value)))?
+            .entry(part)
+            .or_insert_with(|| Value::Table(Default::default()));
+    }
+    let table = table
+        .as_table_mut()
+        .ok_or_else(|| ConfigError::Override(format!("{}={}", key, value)))?;
+    let parsed = match table.get(last) {
+        Some(Value::String(_)) => Value::String(value.to_string()),
+        _ => toml::from_str::<toml::Table>(&format!("v = {}", value))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("v"))
+            .unwrap_or_else(|| Value::String(value.to_string())),
+    };
+    table.insert(last.to_string(), parsed);
+    Ok(())
+}
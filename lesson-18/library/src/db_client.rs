@@ -3,65 +3,111 @@
 //! The functions in this file are used to communicate with the database
 //! Most functionality is tested as well.
 //! 
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::blob_store::BlobStore;
+use crate::envelope::{self, EnvelopeError};
+use crate::images::{self, ImageInfo, Thumbnail};
+use crate::migrations::{self, MigrationError};
+use crate::retention::{PurgeReport, RetentionPolicy};
+use crate::search::{self, SearchHit, SearchQuery};
+use crate::store::{StoreBackend, StoreError, StoreResult};
 use crate::{
-    deserialize_message_as_bin, get_timestamp, serialize_message_as_bin, Message, MessageType, User,
+    get_timestamp, Attachment, AttachmentChunk, AttachmentInfo, Message, MessageType, StorageQuota,
+    User,
 };
-use sqlx::sqlite::{SqlitePoolOptions, SqliteQueryResult};
-use sqlx::{Pool, Sqlite};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{Pool, Row, Sqlite};
 use uuid::Uuid;
 
-/// Init function for database, returns a Pool used to connect to the database for further functions
-pub async fn setup_database_pool() -> Result<Pool<Sqlite>, sqlx::Error> {
-    let pool = SqlitePoolOptions::new()
-        .connect(&env::var("DATABASE_URL").unwrap())
-        .await?;
-
-    // Creating tables if they don't exist
-    sqlx::query!(
-        "CREATE TABLE IF NOT EXISTS users (
-            uid TEXT NOT NULL UNIQUE PRIMARY KEY
-         )",
-    )
-    .execute(&pool)
-    .await?;
+/// Settings of the database connection pool
+///
+/// One pool is meant to be created at startup and shared (cloned) by all tasks using the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseConfig {
+    /// Store implementation, the remaining settings apply to SQLite only
+    pub backend: StoreBackend,
+    /// Database URL, e.g. `sqlite:./local.db`
+    pub url: String,
+    /// Maximum number of open connections
+    pub max_connections: u32,
+    /// Number of connections kept open even when idle
+    pub min_connections: u32,
+    /// Journal mode, WAL allows readers to work alongside a writer
+    pub journal_mode: SqliteJournalMode,
+    /// How long to wait for a locked database before failing with "database is locked"
+    pub busy_timeout: Duration,
+    /// Idle connections are closed after this time
+    pub idle_timeout: Duration,
+    /// Root of the blob store keeping attachment contents, relative paths are resolved against current dir
+    pub blobs_dir: PathBuf,
+}
 
-    /*     sqlx::query!(
-        "DROP TABLE MESSAGES",
-    )
-    .execute(&pool)
-    .await?; */
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: StoreBackend::default(),
+            url: "sqlite:./local.db".to_string(),
+            max_connections: 10,
+            min_connections: 1,
+            journal_mode: SqliteJournalMode::Wal,
+            busy_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(10 * 60),
+            blobs_dir: PathBuf::from("blobs"),
+        }
+    }
+}
 
-    sqlx::query!(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id TEXT PRIMARY KEY,
-            uid TEXT NOT NULL,
-            timestamp TEXT NOT NULL,
-            message BLOB,
-            FOREIGN KEY(uid) REFERENCES users(uid)
-         )",
-    )
-    .execute(&pool)
-    .await?;
+impl DatabaseConfig {
+    /// Default settings with URL taken from `DATABASE_URL` env variable (if set).
+    /// Binaries use `config::AppConfig::database_config` instead.
+    pub fn from_env() -> Self {
+        let mut config = DatabaseConfig::default();
+        if let Ok(url) = env::var("DATABASE_URL") {
+            config.url = url;
+        }
+        config
+    }
+}
 
-    sqlx::query!(
-        "CREATE TABLE IF NOT EXISTS message_views (
-            id TEXT PRIMARY KEY,
-            uid TEXT NOT NULL,
-            FOREIGN KEY(id) REFERENCES messages(id),
-            FOREIGN KEY(uid) REFERENCES users(uid)
-         )",
-    )
-    .execute(&pool)
-    .await?;
+/// Init function for database, returns a Pool used to connect to the database for further functions
+///
+/// Uses `DatabaseConfig::from_env()`, see `setup_database_pool_with` for explicit settings.
+pub async fn setup_database_pool() -> Result<Pool<Sqlite>, MigrationError> {
+    setup_database_pool_with(&DatabaseConfig::from_env()).await
+}
 
+/// Init function for database with explicit settings, returns a Pool shared by all connections
+///
+/// Pending schema migrations are applied, a database migrated by a newer binary is refused.
+pub async fn setup_database_pool_with(
+    config: &DatabaseConfig,
+) -> Result<Pool<Sqlite>, MigrationError> {
+    let pool = connect_database_pool(config).await?;
+    migrations::migrate_up(&pool, &BlobStore::new(&config.blobs_dir)).await?;
     Ok(pool)
 }
 
+/// Opens the pool without touching the schema, the database file is created if missing
+pub async fn connect_database_pool(config: &DatabaseConfig) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(&config.url)?
+        .create_if_missing(true)
+        .journal_mode(config.journal_mode)
+        .busy_timeout(config.busy_timeout);
+    SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .idle_timeout(config.idle_timeout)
+        .connect_with(options)
+        .await
+}
+
 /// Authenticate user - save it's UID into DB
-pub async fn auth_client(pool: &Pool<Sqlite>, uid: Uuid) -> Result<String, Box<dyn Error>> {
+pub async fn auth_client(pool: &Pool<Sqlite>, uid: Uuid) -> Result<String, sqlx::Error> {
     // Insert user if not exists
     let uid = uid.to_string();
     sqlx::query!(
@@ -77,67 +123,132 @@ pub async fn auth_client(pool: &Pool<Sqlite>, uid: Uuid) -> Result<String, Box<d
 
 /// Returns a list of all users
 pub async fn get_users(db: &Pool<Sqlite>) -> Result<Vec<User>, sqlx::Error> {
-    let raw_users = sqlx::query!("SELECT uid FROM users").fetch_all(db).await?;
+    let raw_users: Vec<(String, i64, Option<i64>)> =
+        sqlx::query_as("SELECT uid, used_bytes, quota_bytes FROM users")
+            .fetch_all(db)
+            .await?;
 
     let mut users = Vec::new();
-    for raw_user in raw_users {
-        users.push(User { uid: raw_user.uid })
+    for (uid, used_bytes, quota_bytes) in raw_users {
+        users.push(User {
+            uid,
+            used_bytes,
+            quota_bytes,
+        })
     }
     Ok(users)
 }
 
+/// Returns a single user, `None` if unknown
+pub async fn get_user<'e, E>(db: E, uid: &str) -> Result<Option<User>, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    let raw_user: Option<(String, i64, Option<i64>)> =
+        sqlx::query_as("SELECT uid, used_bytes, quota_bytes FROM users WHERE uid = ?")
+            .bind(uid)
+            .fetch_optional(db)
+            .await?;
+    Ok(raw_user.map(|(uid, used_bytes, quota_bytes)| User {
+        uid,
+        used_bytes,
+        quota_bytes,
+    }))
+}
+
+/// Sets storage quota of a user, `None` for the default of the server. Returns false for unknown user
+pub async fn set_user_quota(
+    db: &Pool<Sqlite>,
+    uid: &str,
+    quota_bytes: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query("UPDATE users SET quota_bytes = ? WHERE uid = ?")
+        .bind(quota_bytes)
+        .bind(uid)
+        .execute(db)
+        .await?;
+    Ok(updated.rows_affected() > 0)
+}
+
 /// Delete a single user and all it's messages
-pub async fn delete_user(uid: String, db: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
-    // Delete also any messages sent by this user
+pub async fn delete_user(
+    uid: String,
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<(), sqlx::Error> {
+    let _removing = blobs.removing().await;
+    let mut tx = db.begin().await?;
+    // Delete also any messages sent by this user, together with their attachments
+    let attachment_ids: Vec<String> = sqlx::query_scalar(
+        "SELECT attachment_id FROM messages WHERE uid = $1 AND attachment_id IS NOT NULL",
+    )
+    .bind(&uid)
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut hashes = delete_images(&mut tx, &attachment_ids).await?;
+    let attachment_hashes: Vec<String> = sqlx::query_scalar(
+        "DELETE FROM attachments WHERE id IN
+         (SELECT attachment_id FROM messages WHERE uid = $1 AND attachment_id IS NOT NULL)
+         RETURNING hash",
+    )
+    .bind(&uid)
+    .fetch_all(&mut *tx)
+    .await?;
+    hashes.extend(attachment_hashes);
+    let unreferenced = release_blobs(&mut tx, &hashes).await?;
+
+    sqlx::query("DELETE FROM message_views WHERE uid = $1 OR id IN (SELECT id FROM messages WHERE uid = $1)")
+        .bind(&uid)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "DELETE FROM message_search WHERE message_id IN (SELECT id FROM messages WHERE uid = $1)",
+    )
+    .bind(&uid)
+    .execute(&mut *tx)
+    .await?;
     sqlx::query("DELETE FROM messages WHERE uid = $1")
         .bind(&uid)
-        .execute(db)
+        .execute(&mut *tx)
         .await?;
 
     sqlx::query("DELETE FROM users WHERE uid = $1")
         .bind(uid)
-        .execute(db)
+        .execute(&mut *tx)
         .await?;
 
+    tx.commit().await?;
+    remove_blobs(blobs, &unreferenced).await;
     Ok(())
 }
 
 
-/// Save a message user sent to db as binary data
+/// Save a message user sent to db, returns ID of the message
+///
+/// Text is stored in `body`, content of files and images goes into `blobs` and `attachments`.
+/// With `default_quota_bytes`, an attachment exceeding the quota of the user (the default unless an
+/// admin set another one, 0 for unlimited) is refused with `StoreError::QuotaExceeded`.
 pub async fn save_message(
     pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
     uid: String,
     message: &MessageType,
-) -> Result<SqliteQueryResult, sqlx::Error> {
-    // Insert user if not exists
-
-    /*let uid = sqlx::query!(
-           "INSERT INTO users (uid) VALUES (?)
-            ON CONFLICT(uid) DO UPDATE SET uid=excluded.uid
-            RETURNING uid",
-           uid
-       )
-       .fetch_one(&pool)
-       .await?
-       .uid;
-    */
-    // Insert message
-    let ser_message = serialize_message_as_bin(message).unwrap();
+    default_quota_bytes: Option<u64>,
+) -> StoreResult<String> {
     let message_id: String = Uuid::new_v4().to_string();
     let time = get_timestamp();
-    let res = sqlx::query!(
-        "INSERT INTO messages (id, uid, timestamp, message) VALUES (?, ?, ?, ?)",
-        message_id,
-        uid,
-        time,
-        ser_message
+    match insert_message(
+        pool,
+        blobs,
+        &message_id,
+        &uid,
+        &time,
+        message,
+        default_quota_bytes,
     )
-    .execute(pool)
-    .await;
-    match res {
-        Ok(res) => {
-            Ok(res)
-        }
+    .await
+    {
+        Ok(_) => Ok(message_id),
         Err(err) => {
             log::error!("Error saving message: {}", err);
             Err(err)
@@ -145,81 +256,736 @@ pub async fn save_message(
     }
 }
 
-/// Returns a list of all messages
-pub async fn get_messages_all(db: &Pool<Sqlite>) -> Result<Vec<Message>, sqlx::Error> {
-    let raw_messages = sqlx::query!("SELECT id, uid, timestamp, message FROM messages")
-        .fetch_all(db)
+/// Save a message exported from another database, keeping its ID and timestamp
+///
+/// Returns false, storing nothing, if a message with the same ID exists already. Its attachment
+/// gets a new ID. Quotas do not apply.
+pub async fn import_message(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+    message: &Message,
+) -> StoreResult<bool> {
+    insert_message(
+        pool,
+        blobs,
+        &message.id,
+        &message.uid,
+        &message.timestamp,
+        &message.message,
+        None,
+    )
+    .await
+}
+
+/// Inserts message with all its parts unless its ID is taken, returns whether it was inserted
+///
+/// With `default_quota_bytes`, the quota of the user is checked once the insert holds the write
+/// lock of the database, so uploads stored meanwhile are counted.
+async fn insert_message(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+    message_id: &str,
+    uid: &str,
+    time: &str,
+    message: &MessageType,
+    default_quota_bytes: Option<u64>,
+) -> StoreResult<bool> {
+    // Nothing is stored unless both the message and its attachment are
+    let _adding = blobs.adding().await;
+    let mut tx = pool.begin().await?;
+    let inserted = sqlx::query(
+        "INSERT INTO messages (id, uid, timestamp) VALUES (?, ?, ?) ON CONFLICT(id) DO NOTHING",
+    )
+    .bind(message_id)
+    .bind(uid)
+    .bind(time)
+    .execute(&mut *tx)
+    .await?;
+    if inserted.rows_affected() == 0 {
+        return Ok(false);
+    }
+    if let (Some(default_bytes), MessageType::Image(_, data) | MessageType::File(_, data)) =
+        (default_quota_bytes, message)
+    {
+        // Dropping the transaction rolls the insert back
+        if let Some(user) = get_user(&mut *tx, uid).await? {
+            let quota = StorageQuota::of(&user, default_bytes);
+            if !quota.allows(data.len() as u64) {
+                return Err(StoreError::QuotaExceeded(data.len() as u64, quota));
+            }
+        }
+    }
+    insert_message_columns(&mut tx, blobs, message_id, message).await?;
+    index_message(&mut tx, message_id, message).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Fills `kind`, `body` and `attachment_id` of an existing message row, clears the legacy `message` blob
+pub(crate) async fn insert_message_columns(
+    conn: &mut SqliteConnection,
+    blobs: &BlobStore,
+    message_id: &str,
+    message: &MessageType,
+) -> Result<(), sqlx::Error> {
+    let (body, _, _) = envelope::message_parts(message);
+    let attachment_id = match message {
+        MessageType::Image(info, data) | MessageType::File(info, data) => {
+            let attachment_id = insert_attachment(&mut *conn, blobs, info, data).await?;
+            add_used_bytes(&mut *conn, message_id, data.len() as i64).await?;
+            if let MessageType::Image(..) = message {
+                insert_image(&mut *conn, blobs, &attachment_id, data).await?;
+            }
+            Some(attachment_id)
+        }
+        _ => None,
+    };
+    sqlx::query(
+        "UPDATE messages SET kind = ?, body = ?, attachment_id = ?, message = NULL WHERE id = ?",
+    )
+    .bind(message.kind().as_str())
+    .bind(body)
+    .bind(attachment_id)
+    .bind(message_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Adds text of a message, or name and caption of its attachment, to the search index
+async fn index_message(
+    conn: &mut SqliteConnection,
+    message_id: &str,
+    message: &MessageType,
+) -> Result<(), sqlx::Error> {
+    let (body, name) = match message {
+        MessageType::Text(text) => (Some(text.as_str()), None),
+        MessageType::Image(info, _) | MessageType::File(info, _) => {
+            (info.caption.as_deref(), Some(info.name.as_str()))
+        }
+        _ => return Ok(()),
+    };
+    sqlx::query("INSERT INTO message_search (body, name, message_id) VALUES (?, ?, ?)")
+        .bind(body)
+        .bind(name)
+        .bind(message_id)
+        .execute(&mut *conn)
         .await?;
+    Ok(())
+}
 
-    let mut messages = Vec::new();
-    for raw_msg in raw_messages {
-        let msg = &raw_msg.message;
-        let message_type = deserialize_message_as_bin(msg.as_ref().unwrap());
-        if let Ok(message_type) = message_type {
-            let id = &raw_msg.id.unwrap_or_else(|| "default_id".to_string());
-            let uid = &raw_msg.uid;
-            let timestamp = &raw_msg.timestamp;
-
-            messages.push(Message {
-                id: id.to_string(),
-                uid: uid.to_string(),
-                timestamp: timestamp.to_string(),
-                message: message_type,
-            });
-        } else {
-            log::error!(
-                "Error deserializing message: {}",
-                message_type.err().unwrap()
-            );
+/// Message ID, uid, timestamp, attachment ID and the snippet with highlights marked
+type SearchRow = (String, String, String, Option<String>, String);
+
+/// Messages matching the query, best matches first
+///
+/// Snippets come from the text or caption, or the attachment name, whichever matches better.
+pub async fn search_messages(
+    db: &Pool<Sqlite>,
+    query: &SearchQuery,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let terms = query.terms();
+    if terms.is_empty() {
+        return Ok(Vec::new());
+    }
+    // char(1) and char(2) are search::HIGHLIGHT_START and HIGHLIGHT_END
+    let rows: Vec<SearchRow> = sqlx::query_as(
+        "SELECT m.id, m.uid, m.timestamp, m.attachment_id,
+         snippet(message_search, -1, char(1), char(2), '…', 16)
+         FROM message_search s JOIN messages m ON m.id = s.message_id
+         WHERE message_search MATCH ?
+         AND (? IS NULL OR m.uid = ?)
+         AND (? IS NULL OR CAST(m.timestamp AS INTEGER) >= ?)
+         AND (? IS NULL OR CAST(m.timestamp AS INTEGER) <= ?)
+         ORDER BY rank LIMIT ?",
+    )
+    .bind(search::fts_query(&terms))
+    .bind(&query.uid)
+    .bind(&query.uid)
+    .bind(query.since.map(|since| since as i64))
+    .bind(query.since.map(|since| since as i64))
+    .bind(query.until.map(|until| until as i64))
+    .bind(query.until.map(|until| until as i64))
+    .bind(query.limit)
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(message_id, uid, timestamp, attachment_id, marked)| {
+            let (snippet, highlights) = SearchHit::unmark(&marked);
+            SearchHit {
+                message_id,
+                uid,
+                timestamp,
+                attachment_id,
+                snippet,
+                highlights,
+            }
+        })
+        .collect())
+}
+
+/// Stores content of a file or image, returns ID of the attachment
+///
+/// The content is written to the blob store once, further attachments with the same content only
+/// add a reference to it.
+async fn insert_attachment(
+    conn: &mut SqliteConnection,
+    blobs: &BlobStore,
+    info: &AttachmentInfo,
+    data: &[u8],
+) -> Result<String, sqlx::Error> {
+    let id = Uuid::new_v4().to_string();
+    let hash = blobs.put(data).await?;
+    sqlx::query("INSERT INTO attachments (id, name, mime, size, hash) VALUES (?, ?, ?, ?, ?)")
+        .bind(&id)
+        .bind(&info.name)
+        .bind(&info.mime)
+        .bind(data.len() as i64)
+        .bind(&hash)
+        .execute(&mut *conn)
+        .await?;
+    add_blob_reference(&mut *conn, &hash, data.len()).await?;
+    Ok(id)
+}
+
+/// Adds `delta` (negative when freeing space) to the storage used by the sender of a message
+async fn add_used_bytes(
+    conn: &mut SqliteConnection,
+    message_id: &str,
+    delta: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE users SET used_bytes = MAX(used_bytes + ?, 0)
+         WHERE uid = (SELECT uid FROM messages WHERE id = ?)",
+    )
+    .bind(delta)
+    .bind(message_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Counts one more reference to a stored blob
+async fn add_blob_reference(
+    conn: &mut SqliteConnection,
+    hash: &str,
+    size: usize,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO blobs (hash, size, refs) VALUES (?, ?, 1)
+         ON CONFLICT(hash) DO UPDATE SET refs = refs + 1",
+    )
+    .bind(hash)
+    .bind(size as i64)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Records metadata of an image attachment and stores its thumbnails
+///
+/// An image that cannot be decoded is stored without them, it is still delivered as sent.
+pub(crate) async fn insert_image(
+    conn: &mut SqliteConnection,
+    blobs: &BlobStore,
+    attachment_id: &str,
+    data: &[u8],
+) -> Result<(), sqlx::Error> {
+    let image = match images::process_image(data) {
+        Ok(image) => image,
+        Err(e) => {
+            log::warn!("Image {} has no thumbnails: {}", attachment_id, e);
+            return Ok(());
         }
+    };
+    sqlx::query(
+        "INSERT INTO images (attachment_id, width, height, format, frames, duration_ms)
+         VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(attachment_id)
+    .bind(image.width)
+    .bind(image.height)
+    .bind(&image.format)
+    .bind(image.frames)
+    .bind(image.duration_ms as i64)
+    .execute(&mut *conn)
+    .await?;
+    for (thumbnail, png) in &image.thumbnails {
+        let hash = blobs.put(png).await?;
+        sqlx::query(
+            "INSERT INTO thumbnails (id, attachment_id, max_size, width, height, size, hash)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(images::thumbnail_id(attachment_id, thumbnail.max_size))
+        .bind(attachment_id)
+        .bind(thumbnail.max_size)
+        .bind(thumbnail.width)
+        .bind(thumbnail.height)
+        .bind(thumbnail.size)
+        .bind(&hash)
+        .execute(&mut *conn)
+        .await?;
+        add_blob_reference(&mut *conn, &hash, png.len()).await?;
     }
+    Ok(())
+}
 
-    Ok(messages)
+/// Deletes metadata and thumbnails of image attachments, returns hashes of the thumbnails
+async fn delete_images(
+    conn: &mut SqliteConnection,
+    attachment_ids: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut hashes = Vec::new();
+    for attachment_id in attachment_ids {
+        let deleted: Vec<String> =
+            sqlx::query_scalar("DELETE FROM thumbnails WHERE attachment_id = ? RETURNING hash")
+                .bind(attachment_id)
+                .fetch_all(&mut *conn)
+                .await?;
+        hashes.extend(deleted);
+        sqlx::query("DELETE FROM images WHERE attachment_id = ?")
+            .bind(attachment_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(hashes)
 }
 
-/// Returns a list of all messages of given user
-pub async fn get_messages_user(db: &Pool<Sqlite>, uid: String) -> Result<Vec<Message>, sqlx::Error> {
-    let raw_messages = sqlx::query!("SELECT id, uid, timestamp, message FROM messages where uid = ?", uid)
-        .fetch_all(db)
+/// Drops one reference for each hash (of deleted attachments), returns hashes no longer referenced
+async fn release_blobs(
+    conn: &mut SqliteConnection,
+    hashes: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut unreferenced = Vec::new();
+    for hash in hashes {
+        let released: Option<String> = sqlx::query_scalar(
+            "UPDATE blobs SET refs = refs - 1 WHERE hash = ? AND refs > 0 RETURNING hash",
+        )
+        .bind(hash)
+        .fetch_optional(&mut *conn)
         .await?;
+        if released.is_none() {
+            log::warn!("Blob {} was not referenced", hash);
+        }
+    }
+    for hash in hashes {
+        let deleted: Option<String> =
+            sqlx::query_scalar("DELETE FROM blobs WHERE hash = ? AND refs = 0 RETURNING hash")
+                .bind(hash)
+                .fetch_optional(&mut *conn)
+                .await?;
+        unreferenced.extend(deleted);
+    }
+    Ok(unreferenced)
+}
+
+/// Removes files of unreferenced blobs, after the transaction releasing them is committed
+async fn remove_blobs(blobs: &BlobStore, hashes: &[String]) {
+    for hash in hashes {
+        match blobs.remove(hash).await {
+            Ok(_) => log::debug!("Removed unreferenced blob {}", hash),
+            // Left for `collect_garbage`
+            Err(e) => log::warn!("Cannot remove blob {}: {}", hash, e),
+        }
+    }
+}
+
+/// Removes blobs no attachment refers to, including files left behind by failed saves, returns their number
+///
+/// Saves through the same `BlobStore` wait for it, saves of other processes do not - servers call
+/// it at startup.
+pub async fn collect_garbage(db: &Pool<Sqlite>, blobs: &BlobStore) -> Result<usize, sqlx::Error> {
+    let _removing = blobs.removing().await;
+    let mut unreferenced: Vec<String> =
+        sqlx::query_scalar("DELETE FROM blobs WHERE refs <= 0 RETURNING hash")
+            .fetch_all(db)
+            .await?;
+    let referenced: HashSet<String> = sqlx::query_scalar("SELECT hash FROM blobs")
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .collect();
+    for hash in blobs.hashes().await? {
+        if !referenced.contains(&hash) && !unreferenced.contains(&hash) {
+            unreferenced.push(hash);
+        }
+    }
+    remove_blobs(blobs, &unreferenced).await;
+    if !unreferenced.is_empty() {
+        log::info!("Removed {} unreferenced blobs", unreferenced.len());
+    }
+    Ok(unreferenced.len())
+}
+
+/// Stored message which cannot be decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnreadableMessage {
+    pub id: String,
+    pub uid: String,
+    pub timestamp: String,
+    pub error: EnvelopeError,
+}
+
+/// Messages joined with their attachments, optionally only of given user, each decoded separately
+async fn fetch_messages(
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+    uid: Option<String>,
+) -> Result<Vec<Result<Message, UnreadableMessage>>, sqlx::Error> {
+    let mut query = "SELECT m.id, m.uid, m.timestamp, m.kind, m.body, m.message, m.attachment_id,
+         a.name, a.hash, a.data
+         FROM messages m LEFT JOIN attachments a ON a.id = m.attachment_id"
+        .to_string();
+    if uid.is_some() {
+        query.push_str(" WHERE m.uid = ?");
+    }
+    let raw_messages = sqlx::query(&query).bind(uid).fetch_all(db).await?;
 
     let mut messages = Vec::new();
     for raw_msg in raw_messages {
-        let msg = &raw_msg.message;
-        let message_type = deserialize_message_as_bin(msg.as_ref().unwrap());
-        if let Ok(message_type) = message_type {
-            let id = &raw_msg.id.unwrap_or_else(|| "default_id".to_string());
-            let uid = &raw_msg.uid;
-            let timestamp = &raw_msg.timestamp;
-
-            messages.push(Message {
-                id: id.to_string(),
-                uid: uid.to_string(),
-                timestamp: timestamp.to_string(),
+        let id: Option<String> = raw_msg.try_get("id")?;
+        let id = id.unwrap_or_else(|| "default_id".to_string());
+        let uid: String = raw_msg.try_get("uid")?;
+        let timestamp: String = raw_msg.try_get("timestamp")?;
+        let kind: Option<String> = raw_msg.try_get("kind")?;
+        let message_type = match kind {
+            Some(kind) => {
+                let hash: Option<String> = raw_msg.try_get("hash")?;
+                let mut data: Option<Vec<u8>> = raw_msg.try_get("data")?;
+                if let (Some(hash), None) = (&hash, &data) {
+                    data = match blobs.get(hash).await {
+                        Ok(data) => Some(data),
+                        Err(e) => {
+                            log::warn!("Cannot read blob {}: {}", hash, e);
+                            None
+                        }
+                    };
+                }
+                envelope::message_from_parts(
+                    &kind,
+                    raw_msg.try_get("body")?,
+                    raw_msg.try_get("name")?,
+                    data,
+                )
+            }
+            // Not converted by the migration, only the blob is there
+            None => {
+                let blob: Option<Vec<u8>> = raw_msg.try_get("message")?;
+                envelope::decode(&blob.unwrap_or_default())
+            }
+        };
+        messages.push(match message_type {
+            Ok(message_type) => Ok(Message {
+                id,
+                uid,
+                timestamp,
                 message: message_type,
+                attachment_id: raw_msg.try_get("attachment_id")?,
+            }),
+            Err(error) => Err(UnreadableMessage {
+                id,
+                uid,
+                timestamp,
+                error,
+            }),
+        });
+    }
+
+    Ok(messages)
+}
+
+/// Drops messages which cannot be decoded, they are logged (see `check_messages` to list them)
+fn readable_messages(messages: Vec<Result<Message, UnreadableMessage>>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .filter_map(|message| match message {
+            Ok(message) => Some(message),
+            Err(unreadable) => {
+                log::error!(
+                    "Error deserializing message {}: {}",
+                    unreadable.id,
+                    unreadable.error
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns a list of all messages
+pub async fn get_messages_all(
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<Vec<Message>, sqlx::Error> {
+    Ok(readable_messages(fetch_messages(db, blobs, None).await?))
+}
+
+/// Returns a list of all messages of given user
+pub async fn get_messages_user(
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+    uid: String,
+) -> Result<Vec<Message>, sqlx::Error> {
+    Ok(readable_messages(
+        fetch_messages(db, blobs, Some(uid)).await?,
+    ))
+}
+
+/// Decodes all stored messages, returns the number of messages and those which cannot be decoded
+pub async fn check_messages(
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<(usize, Vec<UnreadableMessage>), sqlx::Error> {
+    let messages = fetch_messages(db, blobs, None).await?;
+    let total = messages.len();
+    Ok((
+        total,
+        messages.into_iter().filter_map(Result::err).collect(),
+    ))
+}
+
+/// Returns a list of all attachments (without their content)
+pub async fn get_attachments(db: &Pool<Sqlite>) -> Result<Vec<Attachment>, sqlx::Error> {
+    let raw_attachments: Vec<(String, String, String, i64, String)> =
+        sqlx::query_as("SELECT id, name, mime, size, hash FROM attachments")
+            .fetch_all(db)
+            .await?;
+    let mut images = get_images(db).await?;
+
+    Ok(raw_attachments
+        .into_iter()
+        .map(|(id, name, mime, size, hash)| Attachment {
+            image: images.remove(&id),
+            id,
+            name,
+            mime,
+            size,
+            hash,
+        })
+        .collect())
+}
+
+/// Metadata of all images with their thumbnails, by attachment ID
+async fn get_images(db: &Pool<Sqlite>) -> Result<HashMap<String, ImageInfo>, sqlx::Error> {
+    let raw_images: Vec<ImageRow> = sqlx::query_as(
+        "SELECT attachment_id, width, height, format, frames, duration_ms FROM images",
+    )
+    .fetch_all(db)
+    .await?;
+    let raw_thumbnails: Vec<(String, String, u32, u32, u32, i64)> = sqlx::query_as(
+        "SELECT id, attachment_id, max_size, width, height, size FROM thumbnails ORDER BY max_size",
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut images: HashMap<String, ImageInfo> = raw_images
+        .into_iter()
+        .map(
+            |(attachment_id, width, height, format, frames, duration_ms)| {
+                let info = ImageInfo {
+                    width,
+                    height,
+                    format,
+                    frames,
+                    duration_ms: duration_ms as u64,
+                    thumbnails: Vec::new(),
+                };
+                (attachment_id, info)
+            },
+        )
+        .collect();
+    for (id, attachment_id, max_size, width, height, size) in raw_thumbnails {
+        if let Some(image) = images.get_mut(&attachment_id) {
+            image.thumbnails.push(Thumbnail {
+                id,
+                max_size,
+                width,
+                height,
+                size,
             });
-        } else {
-            log::error!(
-                "Error deserializing message: {}",
-                message_type.err().unwrap()
-            );
         }
     }
+    Ok(images)
+}
 
-    Ok(messages)
+/// `images` columns, attachment ID first
+type ImageRow = (String, u32, u32, String, u32, i64);
+
+/// Attachment columns followed by its content, unless moved to the blob store
+type AttachmentRow = (String, String, String, i64, String, Option<Vec<u8>>);
+
+/// Up to `len` bytes of attachment content starting at `offset`, `None` if there is no such attachment
+pub async fn read_attachment(
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+    id: &str,
+    offset: u64,
+    len: usize,
+) -> Result<Option<AttachmentChunk>, sqlx::Error> {
+    let mut row: Option<AttachmentRow> =
+        sqlx::query_as("SELECT id, name, mime, size, hash, data FROM attachments WHERE id = ?")
+            .bind(id)
+            .fetch_optional(db)
+            .await?;
+    if row.is_none() {
+        row = read_thumbnail_row(db, id).await?;
+    }
+    let Some((id, name, mime, size, hash, inline)) = row else {
+        return Ok(None);
+    };
+    let data = match inline {
+        // Not moved to the blob store yet
+        Some(inline) => {
+            let start = (offset as usize).min(inline.len());
+            let end = start.saturating_add(len).min(inline.len());
+            inline[start..end].to_vec()
+        }
+        None => blobs.read_range(&hash, offset, len).await?,
+    };
+    Ok(Some(AttachmentChunk {
+        attachment: Attachment {
+            id,
+            name,
+            mime,
+            size,
+            hash,
+            image: None,
+        },
+        offset,
+        data,
+    }))
 }
 
-/// Delete a single message using message ID
-pub async fn delete_message(id: String, db: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
-    // Delete also any messages sent by this user
-    sqlx::query("DELETE FROM messages WHERE id = $1")
-        .bind(&id)
-        .execute(db)
-        .await?;
+/// Thumbnail as an attachment row, named after its image
+async fn read_thumbnail_row(
+    db: &Pool<Sqlite>,
+    id: &str,
+) -> Result<Option<AttachmentRow>, sqlx::Error> {
+    let row: Option<(String, String, u32, i64, String)> = sqlx::query_as(
+        "SELECT t.id, a.name, t.max_size, t.size, t.hash
+             FROM thumbnails t JOIN attachments a ON a.id = t.attachment_id
+             WHERE t.id = ?",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|(id, name, max_size, size, hash)| {
+        let name = images::thumbnail_name(&name, max_size);
+        (id, name, "image/png".to_string(), size, hash, None)
+    }))
+}
 
+/// Delete a single message using message ID, its content is removed once no other message refers to it
+pub async fn delete_message(
+    id: String,
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<(), sqlx::Error> {
+    delete_messages(&[id], db, blobs).await?;
     Ok(())
 }
 
+/// Messages deleted in one transaction by `delete_messages`
+const DELETE_BATCH_SIZE: usize = 100;
+
+/// Deletes messages together with their attachments, search index entries and views
+pub async fn delete_messages(
+    ids: &[String],
+    db: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<PurgeReport, sqlx::Error> {
+    let mut report = PurgeReport::default();
+    for batch in ids.chunks(DELETE_BATCH_SIZE) {
+        let _removing = blobs.removing().await;
+        let mut tx = db.begin().await?;
+        let mut hashes = Vec::new();
+        for id in batch {
+            // Delete also the attachment of the message
+            let attachment_ids: Vec<String> = sqlx::query_scalar(
+                "SELECT attachment_id FROM messages WHERE id = $1 AND attachment_id IS NOT NULL",
+            )
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?;
+            hashes.extend(delete_images(&mut tx, &attachment_ids).await?);
+            let deleted: Vec<(String, i64)> = sqlx::query_as(
+                "DELETE FROM attachments WHERE id =
+                 (SELECT attachment_id FROM messages WHERE id = $1)
+                 RETURNING hash, size",
+            )
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?;
+            let freed: i64 = deleted.iter().map(|(_, size)| size).sum();
+            add_used_bytes(&mut tx, id, -freed).await?;
+            report.attachments += deleted.len() as u64;
+            report.bytes += freed as u64;
+            hashes.extend(deleted.into_iter().map(|(hash, _)| hash));
+
+            sqlx::query("DELETE FROM message_views WHERE id = $1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM message_search WHERE message_id = $1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            let deleted = sqlx::query("DELETE FROM messages WHERE id = $1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            report.messages += deleted.rows_affected();
+        }
+        let unreferenced = release_blobs(&mut tx, &hashes).await?;
+        tx.commit().await?;
+        remove_blobs(blobs, &unreferenced).await;
+        report.blobs += unreferenced.len() as u64;
+    }
+    Ok(report)
+}
+
+/// IDs of messages the policy does not keep at `now_ms`
+pub async fn expired_messages(
+    db: &Pool<Sqlite>,
+    policy: &RetentionPolicy,
+    now_ms: u64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut expired = HashSet::new();
+    for (rule, attachments_only) in policy.rules() {
+        let applies = match attachments_only {
+            true => "attachment_id IS NOT NULL",
+            false => "TRUE",
+        };
+        if let Some(cutoff) = rule.cutoff_ms(now_ms) {
+            let query = format!(
+                "SELECT id FROM messages WHERE {} AND CAST(timestamp AS INTEGER) < ?",
+                applies
+            );
+            let ids: Vec<String> = sqlx::query_scalar(&query)
+                .bind(cutoff as i64)
+                .fetch_all(db)
+                .await?;
+            expired.extend(ids);
+        }
+        if let Some(max_per_user) = rule.max_per_user {
+            // Newest first, messages sent in the same millisecond in the order they were stored
+            let query = format!(
+                "SELECT id FROM (SELECT id, ROW_NUMBER() OVER
+                 (PARTITION BY uid ORDER BY CAST(timestamp AS INTEGER) DESC, rowid DESC) AS n
+                 FROM messages WHERE {}) WHERE n > ?",
+                applies
+            );
+            let ids: Vec<String> = sqlx::query_scalar(&query)
+                .bind(max_per_user as i64)
+                .fetch_all(db)
+                .await?;
+            expired.extend(ids);
+        }
+    }
+    Ok(expired.into_iter().collect())
+}
+
 /*
 #[derive(sqlx::FromRow, Debug)]
 struct AllMessages{
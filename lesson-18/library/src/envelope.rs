@@ -0,0 +1,226 @@
+//! Versioned binary format of stored messages
+//!
+//! Every encoded message starts with `MAGIC` followed by a format version byte, the payload is decoded
+//! by the decoder of that version. Data without the header is version 0 - the plain bincode encoding of
+//! `MessageType` used before the envelope existed (bincode identifies variants by position, so that
+//! layout is frozen in `MessageV0`).
+//!
+//! Version 1 identifies messages by the name of their kind, so adding or reordering `MessageType`
+//! variants does not break decoding of stored data. When the payload changes, add a new version with
+//! its own decoder and keep the old ones. The `AttachmentInfo` of files and images is stored as their
+//! name and caption (in `body`), the rest is computed from the content when decoding.
+use std::borrow::Cow;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{images, AttachmentInfo, MessageKind, MessageType};
+
+/// Start of every versioned envelope, never a valid start of version 0 data
+pub const MAGIC: &[u8; 3] = b"CHM";
+/// Version written by `encode`
+pub const CURRENT_VERSION: u8 = 1;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EnvelopeError {
+    #[error("Data is empty or truncated")]
+    Truncated,
+    #[error("Format version {0} is newer than this binary supports")]
+    UnsupportedVersion(u8),
+    #[error("Corrupted data of format version {version}: {reason}")]
+    Corrupted { version: u8, reason: String },
+    #[error("Unknown message kind {0}")]
+    UnknownKind(String),
+    #[error("Invalid body of {0} message: {1}")]
+    InvalidBody(MessageKind, String),
+    #[error("Content of {0} message is missing")]
+    MissingContent(MessageKind),
+    #[error("Cannot encode message: {0}")]
+    Encode(String),
+}
+
+/// `MessageType` as it was encoded before the envelope, variants must stay in this order
+#[derive(Serialize, Deserialize)]
+enum MessageV0 {
+    Text(String),
+    Image(Vec<u8>),
+    File(String, Vec<u8>),
+    Error(String),
+    Auth(String),
+    Shutdown(String),
+}
+
+/// Payload of version 1
+#[derive(Serialize, Deserialize)]
+struct MessageV1 {
+    kind: String,
+    body: Option<String>,
+    name: Option<String>,
+    data: Option<Vec<u8>>,
+}
+
+/// Encodes message in the current format version
+pub fn encode(message: &MessageType) -> Result<Vec<u8>, EnvelopeError> {
+    let (body, name, data) = message_parts(message);
+    let payload = MessageV1 {
+        kind: message.kind().to_string(),
+        body: body.map(Cow::into_owned),
+        name: name.map(str::to_string),
+        data: data.map(<[u8]>::to_vec),
+    };
+    let mut encoded = MAGIC.to_vec();
+    encoded.push(CURRENT_VERSION);
+    bincode_options(usize::MAX)
+        .serialize_into(&mut encoded, &payload)
+        .map_err(|e| EnvelopeError::Encode(e.to_string()))?;
+    Ok(encoded)
+}
+
+/// Encodes message in version 0, readable by binaries older than the envelope
+pub fn encode_v0(message: &MessageType) -> Result<Vec<u8>, EnvelopeError> {
+    let legacy = match message.clone() {
+        MessageType::AttachmentRequest(_)
+        | MessageType::AttachmentResponse(_)
+        | MessageType::SearchRequest(_)
+        | MessageType::SearchResponse(_) => {
+            return Err(EnvelopeError::Encode(format!(
+                "{} message cannot be stored in format version 0",
+                message.kind()
+            )))
+        }
+        MessageType::Text(text) => MessageV0::Text(text),
+        MessageType::Image(_, data) => MessageV0::Image(data),
+        MessageType::File(info, data) => MessageV0::File(info.name, data),
+        MessageType::Error(text) => MessageV0::Error(text),
+        MessageType::Auth(text) => MessageV0::Auth(text),
+        MessageType::Shutdown(text) => MessageV0::Shutdown(text),
+    };
+    bincode_options(usize::MAX)
+        .serialize(&legacy)
+        .map_err(|e| EnvelopeError::Encode(e.to_string()))
+}
+
+/// Decodes message of any known format version
+pub fn decode(data: &[u8]) -> Result<MessageType, EnvelopeError> {
+    match data.strip_prefix(MAGIC.as_slice()) {
+        Some([1, payload @ ..]) => decode_v1(payload),
+        Some([version, ..]) => Err(EnvelopeError::UnsupportedVersion(*version)),
+        Some([]) => Err(EnvelopeError::Truncated),
+        None => decode_v0(data),
+    }
+}
+
+fn decode_v0(data: &[u8]) -> Result<MessageType, EnvelopeError> {
+    if data.is_empty() {
+        return Err(EnvelopeError::Truncated);
+    }
+    let legacy: MessageV0 = deserialize(0, data)?;
+    Ok(match legacy {
+        MessageV0::Text(text) => MessageType::Text(text),
+        MessageV0::Image(data) => MessageType::Image(
+            AttachmentInfo::new(&images::image_name(&data), &data, None),
+            data,
+        ),
+        MessageV0::File(name, data) => {
+            MessageType::File(AttachmentInfo::new(&name, &data, None), data)
+        }
+        MessageV0::Error(text) => MessageType::Error(text),
+        MessageV0::Auth(text) => MessageType::Auth(text),
+        MessageV0::Shutdown(text) => MessageType::Shutdown(text),
+    })
+}
+
+fn decode_v1(payload: &[u8]) -> Result<MessageType, EnvelopeError> {
+    let message: MessageV1 = deserialize(1, payload)?;
+    message_from_parts(&message.kind, message.body, message.name, message.data)
+}
+
+/// Text, attachment name and attachment content of a message, as stored in separate fields
+///
+/// Protocol messages about attachments and searches keep their content as JSON text, text of files and images is
+/// their caption.
+pub fn message_parts(message: &MessageType) -> (Option<Cow<'_, str>>, Option<&str>, Option<&[u8]>) {
+    match message {
+        MessageType::Text(text)
+        | MessageType::Error(text)
+        | MessageType::Auth(text)
+        | MessageType::Shutdown(text) => (Some(Cow::Borrowed(text)), None, None),
+        MessageType::AttachmentRequest(request) => (to_json(request), None, None),
+        MessageType::AttachmentResponse(response) => (to_json(response), None, None),
+        MessageType::SearchRequest(query) => (to_json(query), None, None),
+        MessageType::SearchResponse(hits) => (to_json(hits), None, None),
+        MessageType::Image(info, data) | MessageType::File(info, data) => (
+            info.caption.as_deref().map(Cow::Borrowed),
+            Some(&info.name),
+            Some(data),
+        ),
+    }
+}
+
+/// Builds message back from its kind and parts, see `message_parts`
+pub fn message_from_parts(
+    kind: &str,
+    body: Option<String>,
+    name: Option<String>,
+    data: Option<Vec<u8>>,
+) -> Result<MessageType, EnvelopeError> {
+    let kind: MessageKind = kind
+        .parse()
+        .map_err(|_| EnvelopeError::UnknownKind(kind.to_string()))?;
+    // Caption of files and images, text of the other messages
+    Ok(match kind {
+        MessageKind::Text => MessageType::Text(body.unwrap_or_default()),
+        MessageKind::Error => MessageType::Error(body.unwrap_or_default()),
+        MessageKind::Auth => MessageType::Auth(body.unwrap_or_default()),
+        MessageKind::Shutdown => MessageType::Shutdown(body.unwrap_or_default()),
+        MessageKind::AttachmentRequest => {
+            MessageType::AttachmentRequest(from_json(kind, &body.unwrap_or_default())?)
+        }
+        MessageKind::AttachmentResponse => {
+            MessageType::AttachmentResponse(from_json(kind, &body.unwrap_or_default())?)
+        }
+        MessageKind::SearchRequest => {
+            MessageType::SearchRequest(from_json(kind, &body.unwrap_or_default())?)
+        }
+        MessageKind::SearchResponse => {
+            MessageType::SearchResponse(from_json(kind, &body.unwrap_or_default())?)
+        }
+        MessageKind::Image => {
+            let data = data.ok_or(EnvelopeError::MissingContent(kind))?;
+            let name = name.unwrap_or_else(|| images::image_name(&data));
+            MessageType::Image(AttachmentInfo::new(&name, &data, body), data)
+        }
+        MessageKind::File => {
+            let data = data.ok_or(EnvelopeError::MissingContent(kind))?;
+            let name = name.unwrap_or_default();
+            MessageType::File(AttachmentInfo::new(&name, &data, body), data)
+        }
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Option<Cow<'static, str>> {
+    // Serializing plain data structures into a string cannot fail
+    serde_json::to_string(value).ok().map(Cow::Owned)
+}
+
+fn from_json<'a, T: Deserialize<'a>>(kind: MessageKind, body: &'a str) -> Result<T, EnvelopeError> {
+    serde_json::from_str(body).map_err(|e| EnvelopeError::InvalidBody(kind, e.to_string()))
+}
+
+/// Encoding of `bincode::serialize`, limited so a corrupted length cannot allocate more than the data
+fn bincode_options(limit: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+}
+
+fn deserialize<'a, T: Deserialize<'a>>(version: u8, data: &'a [u8]) -> Result<T, EnvelopeError> {
+    bincode_options(data.len())
+        .deserialize(data)
+        .map_err(|e| EnvelopeError::Corrupted {
+            version,
+            reason: e.to_string(),
+        })
+}
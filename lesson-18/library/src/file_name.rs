@@ -0,0 +1,88 @@
+//! Validation of file names received from other peers
+//!
+//! Names come from `MessageType::File` and are used to create files in the storage root, so anything
+//! that could leave the root, hide the file or misbehave on some platform is rejected instead of being
+//! silently rewritten - the sender gets to know why.
+use thiserror::Error;
+
+/// Longest accepted name in bytes, the common file system limit
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Names Windows reserves for devices, with any extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters invalid in names on Windows (`:` also selects NTFS alternate data streams)
+const FORBIDDEN_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FileNameError {
+    #[error("name is empty")]
+    Empty,
+    #[error("name is longer than {MAX_FILE_NAME_LEN} bytes")]
+    TooLong,
+    #[error("name contains a path separator")]
+    PathSeparator,
+    #[error("name refers to a directory")]
+    Directory,
+    #[error("name starts with a dot")]
+    Hidden,
+    #[error("name contains control or bidirectional formatting characters")]
+    ControlCharacter,
+    #[error("name contains character {0:?}")]
+    ForbiddenCharacter(char),
+    #[error("name ends with a dot or space")]
+    TrailingDotOrSpace,
+    #[error("{0} is a reserved device name")]
+    Reserved(String),
+}
+
+/// Checks a received file name, returns it if it can be used as is inside the storage root
+pub fn sanitize_file_name(name: &str) -> Result<&str, FileNameError> {
+    if name.trim().is_empty() {
+        return Err(FileNameError::Empty);
+    }
+    if name.len() > MAX_FILE_NAME_LEN {
+        return Err(FileNameError::TooLong);
+    }
+    if name == "." || name == ".." {
+        return Err(FileNameError::Directory);
+    }
+    // Covers absolute paths, `../` and Windows drive/UNC paths as well
+    if name.contains(['/', '\\']) {
+        return Err(FileNameError::PathSeparator);
+    }
+    if name.chars().any(is_control) {
+        return Err(FileNameError::ControlCharacter);
+    }
+    if let Some(c) = name.chars().find(|c| FORBIDDEN_CHARS.contains(c)) {
+        return Err(FileNameError::ForbiddenCharacter(c));
+    }
+    if name.starts_with('.') {
+        return Err(FileNameError::Hidden);
+    }
+    if name.ends_with(['.', ' ']) {
+        return Err(FileNameError::TrailingDotOrSpace);
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return Err(FileNameError::Reserved(stem.to_string()));
+    }
+    Ok(name)
+}
+
+/// Name with ` (n)` inserted before the extension, used when the name is taken already
+pub fn numbered_file_name(name: &str, n: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{} ({}).{}", stem, n, ext),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// Control characters and the invisible ones changing text direction (can disguise an extension)
+fn is_control(c: char) -> bool {
+    c.is_control()
+        || matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
@@ -0,0 +1,390 @@
+//! Metadata and thumbnails of received images
+//!
+//! Every stored image gets its dimensions and original format recorded, and a PNG thumbnail for each
+//! of `THUMBNAIL_SIZES`, kept next to the original (see `db_client` and the `images`/`thumbnails`
+//! tables). A thumbnail is an attachment of its own, its ID is made from the ID of the image, so
+//! clients can ask for it without listing attachments first.
+//!
+//! Before that, the server makes received images comply with its `ImagePolicy` - limited byte size,
+//! downscaled to the maximum dimensions, re-encoded into the configured format. Re-encoding never
+//! keeps EXIF (or any other) metadata, so GPS position and camera details are stripped with it.
+//!
+//! Animated images (GIF, APNG, animated WebP) stay animated: the policy downscales every frame and
+//! re-encodes them as an animated GIF, the only animated format the `image` crate can write. An
+//! animation needing no change is kept as it was sent. Thumbnails show the first frame.
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::Cursor;
+use std::str::FromStr;
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::imageops::FilterType;
+use image::{AnimationDecoder, DynamicImage, Frame, Frames, ImageError, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::DataProcessingError;
+
+/// Longest side of generated thumbnails in pixels, smaller images are never upscaled
+pub const THUMBNAIL_SIZES: [u32; 2] = [128, 512];
+
+/// Format images are re-encoded into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Format the image was sent in
+    #[default]
+    Original,
+    Png,
+    /// With `ImagePolicy::jpeg_quality`, transparency is lost
+    Jpeg,
+    /// Lossless WebP
+    WebP,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "original" => Ok(OutputFormat::Original),
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::WebP),
+            other => Err(format!(
+                "unknown image format {}, expected original, png, jpeg or webp",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Original => write!(f, "original"),
+            OutputFormat::Png => write!(f, "png"),
+            OutputFormat::Jpeg => write!(f, "jpeg"),
+            OutputFormat::WebP => write!(f, "webp"),
+        }
+    }
+}
+
+/// What images the server accepts and how it stores them, see `config::ImagesSection`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImagePolicy {
+    /// Larger images are downscaled to fit, keeping the aspect ratio
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Larger images are rejected, before decoding
+    pub max_bytes: usize,
+    /// Re-encode even images which need no other change, dropping their metadata
+    pub strip_metadata: bool,
+    pub format: OutputFormat,
+    /// 1 - 100, used for `OutputFormat::Jpeg`
+    pub jpeg_quality: u8,
+}
+
+impl Default for ImagePolicy {
+    fn default() -> Self {
+        ImagePolicy {
+            max_width: 4096,
+            max_height: 4096,
+            max_bytes: 20 * 1024 * 1024,
+            strip_metadata: true,
+            format: OutputFormat::Original,
+            jpeg_quality: 85,
+        }
+    }
+}
+
+impl ImagePolicy {
+    /// Image complying with the policy, the original data if it does already and needs no re-encoding
+    pub fn apply<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, DataProcessingError> {
+        if data.len() > self.max_bytes {
+            return Err(DataProcessingError::ImageTooLarge(
+                data.len(),
+                self.max_bytes,
+            ));
+        }
+        let original = image::guess_format(data)?;
+        if let Some(frames) = decode_animation(data, original)? {
+            return self.apply_animated(data, frames);
+        }
+        let mut img = image::load_from_memory_with_format(data, original)?;
+        let resize = img.width() > self.max_width || img.height() > self.max_height;
+        if resize {
+            img = img.resize(self.max_width, self.max_height, FilterType::Lanczos3);
+        }
+        let format = match self.format {
+            OutputFormat::Original => original,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::WebP => ImageFormat::WebP,
+        };
+        if !resize && !self.strip_metadata && format == original {
+            return Ok(Cow::Borrowed(data));
+        }
+        Ok(Cow::Owned(self.encode(&img, format)?))
+    }
+
+    fn apply_animated<'a>(
+        &self,
+        data: &'a [u8],
+        frames: Vec<Frame>,
+    ) -> Result<Cow<'a, [u8]>, DataProcessingError> {
+        let (width, height) = frames[0].buffer().dimensions();
+        let resize = width > self.max_width || height > self.max_height;
+        if !resize && !self.strip_metadata {
+            return Ok(Cow::Borrowed(data));
+        }
+        let frames = if resize {
+            let ratio = f64::min(
+                self.max_width as f64 / width as f64,
+                self.max_height as f64 / height as f64,
+            );
+            let scale = |size: u32| ((size as f64 * ratio).round() as u32).max(1);
+            frames
+                .into_iter()
+                .map(|frame| {
+                    let (width, height) = frame.buffer().dimensions();
+                    let buffer = image::imageops::resize(
+                        frame.buffer(),
+                        scale(width),
+                        scale(height),
+                        FilterType::Lanczos3,
+                    );
+                    Frame::from_parts(
+                        buffer,
+                        scale(frame.left()),
+                        scale(frame.top()),
+                        frame.delay(),
+                    )
+                })
+                .collect()
+        } else {
+            frames
+        };
+        let mut encoded = Vec::new();
+        {
+            // Finishes the GIF once dropped
+            let mut encoder = GifEncoder::new_with_speed(&mut encoded, 10);
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(frames)?;
+        }
+        Ok(Cow::Owned(encoded))
+    }
+
+    fn encode(&self, img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+        let mut encoded = Vec::new();
+        let mut writer = Cursor::new(&mut encoded);
+        match format {
+            ImageFormat::Png => img.write_with_encoder(PngEncoder::new(writer))?,
+            // Neither supports every color type, 8 bit RGB(A) is what they take
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(writer, self.jpeg_quality))?,
+            ImageFormat::WebP => DynamicImage::ImageRgba8(img.to_rgba8())
+                .write_with_encoder(WebPEncoder::new_lossless(writer))?,
+            format => img.write_to(&mut writer, format)?,
+        }
+        Ok(encoded)
+    }
+}
+
+/// What is known about a stored image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Format of the stored image, e.g. `png` or `jpg` - the one it was sent in, unless the
+    /// `ImagePolicy` of the server converts images
+    pub format: String,
+    /// Number of frames, 1 for still images
+    #[serde(default = "single_frame")]
+    pub frames: u32,
+    /// Length of one loop of an animation in milliseconds, 0 for still images
+    #[serde(default)]
+    pub duration_ms: u64,
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+fn single_frame() -> u32 {
+    1
+}
+
+/// Downscaled PNG copy of an image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// Attachment ID of the thumbnail, see `thumbnail_id`
+    pub id: String,
+    /// One of `THUMBNAIL_SIZES`
+    pub max_size: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Size in bytes
+    pub size: i64,
+}
+
+/// Decoded image with its thumbnails rendered, ready to be stored
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    pub frames: u32,
+    pub duration_ms: u64,
+    /// Thumbnail (with empty `id`) and its PNG content, one for each of `THUMBNAIL_SIZES`
+    pub thumbnails: Vec<(Thumbnail, Vec<u8>)>,
+}
+
+impl ProcessedImage {
+    /// Metadata of the image stored as attachment `attachment_id`
+    pub fn info(&self, attachment_id: &str) -> ImageInfo {
+        ImageInfo {
+            width: self.width,
+            height: self.height,
+            format: self.format.clone(),
+            frames: self.frames,
+            duration_ms: self.duration_ms,
+            thumbnails: self
+                .thumbnails
+                .iter()
+                .map(|(thumbnail, _)| Thumbnail {
+                    id: thumbnail_id(attachment_id, thumbnail.max_size),
+                    ..thumbnail.clone()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Decodes the image, records its metadata and renders the thumbnails
+pub fn process_image(data: &[u8]) -> Result<ProcessedImage, ImageError> {
+    let format = image::guess_format(data)?;
+    let img = image::load_from_memory_with_format(data, format)?;
+    let thumbnails = THUMBNAIL_SIZES
+        .iter()
+        .map(|max_size| render_thumbnail(&img, *max_size))
+        .collect::<Result<_, _>>()?;
+    let (frames, duration_ms) = match decode_animation(data, format)? {
+        Some(frames) => (frames.len() as u32, animation_duration_ms(&frames)),
+        None => (1, 0),
+    };
+    Ok(ProcessedImage {
+        width: img.width(),
+        height: img.height(),
+        format: format_name(format),
+        frames,
+        duration_ms,
+        thumbnails,
+    })
+}
+
+/// All frames of an animated image, `None` for a still image or an animation of a single frame
+pub fn decode_animation(
+    data: &[u8],
+    format: ImageFormat,
+) -> Result<Option<Vec<Frame>>, ImageError> {
+    let frames: Frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))?.into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(data))?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    let frames = frames.collect_frames()?;
+    Ok((frames.len() > 1).then_some(frames))
+}
+
+/// Sum of the frame delays
+pub fn animation_duration_ms(frames: &[Frame]) -> u64 {
+    frames
+        .iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            numer as u64 / denom.max(1) as u64
+        })
+        .sum()
+}
+
+fn render_thumbnail(img: &DynamicImage, max_size: u32) -> Result<(Thumbnail, Vec<u8>), ImageError> {
+    let thumbnail = if img.width() > max_size || img.height() > max_size {
+        img.thumbnail(max_size, max_size)
+    } else {
+        img.clone()
+    };
+    let mut png = Vec::new();
+    thumbnail.write_with_encoder(PngEncoder::new(Cursor::new(&mut png)))?;
+    Ok((
+        Thumbnail {
+            id: String::new(),
+            max_size,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            size: png.len() as i64,
+        },
+        png,
+    ))
+}
+
+/// Lowercase name of the format, its usual extension
+pub fn format_name(format: ImageFormat) -> String {
+    format.extensions_str()[0].to_string()
+}
+
+/// Name of an image stored without one, made up from its detected format
+pub fn image_name(data: &[u8]) -> String {
+    match image::guess_format(data) {
+        Ok(format) => format!("image.{}", format_name(format)),
+        Err(_) => "image".to_string(),
+    }
+}
+
+/// `name` with the extension of the format of `data`, for images converted by the `ImagePolicy`
+pub fn with_format_extension(name: &str, data: &[u8]) -> String {
+    let Ok(format) = image::guess_format(data) else {
+        return name.to_string();
+    };
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if format
+        .extensions_str()
+        .contains(&extension.to_lowercase().as_str())
+    {
+        return name.to_string();
+    }
+    format!("{}.{}", stem, format_name(format))
+}
+
+/// Attachment ID of the thumbnail of image `attachment_id`
+pub fn thumbnail_id(attachment_id: &str, max_size: u32) -> String {
+    format!("{}-{}px", attachment_id, max_size)
+}
+
+/// Image attachment ID and thumbnail size, if `id` is an ID made by `thumbnail_id`
+pub fn parse_thumbnail_id(id: &str) -> Option<(&str, u32)> {
+    let (attachment_id, size) = id.strip_suffix("px")?.rsplit_once('-')?;
+    let max_size = size.parse().ok()?;
+    THUMBNAIL_SIZES
+        .contains(&max_size)
+        .then_some((attachment_id, max_size))
+}
+
+/// File name of a thumbnail, made from the name of the image
+pub fn thumbnail_name(image_name: &str, max_size: u32) -> String {
+    let stem = image_name
+        .rsplit_once('.')
+        .map_or(image_name, |(stem, _)| stem);
+    format!("{}-{}px.png", stem, max_size)
+}
@@ -1,146 +1,224 @@
-//! Module for handling various input types
-//!
-//! # Examples
-//! handle_vec_input(vec![".file".to_string(), "/full/path/to/file.txt".to_string()])
-//! handle_vec_input(vec![".image".to_string(), "/full/path/to/image.png".to_string()])
-//! handle_vec_input(vec![".quit".to_string()])
-//! handle_vec_input(vec![".text".to_string(), "Hello World".to_string()])
-//!
-//!
-//! There are several defined operations which can be used.
-//! For each operation a function is defined.
-//! "Auth" is only used for authentication, and is not intended to be used manually (but right now it could be).
-use std::{error::Error, fs::File, io::Read, path::Path};
-
-use anyhow::Result;
-use crate::MessageType;
-
-#[derive(Debug)]
-pub enum Operation {
-    File,
-    Image,
-    Quit,
-    Text,
-    Auth, // TODO: Add LoadAll - load all missed messages by this client
-}
-impl From<&str> for Operation {
-    fn from(value: &str) -> Self {
-        return match value.to_lowercase().trim() {
-            ".file" => {
-                log::trace!("Operation: File");
-                Operation::File
-            }
-            ".image" => {
-                log::trace!("Operation: Image");
-                Operation::Image
-            }
-            ".quit" => {
-                log::trace!("Operation: Quit");
-                Operation::Quit
-            }
-            ".q" => {
-                log::trace!("Operation: Quit");
-                Operation::Quit
-            }
-            ".auth" => {
-                log::trace!("Operation: Authenticaiton");
-                Operation::Auth
-            }
-            _ => {
-                log::trace!("Operation: Text");
-                Operation::Text
-            }
-        };
-    }
-}
-
-fn read_file_to_bytes(filename: &String) -> Result<Vec<u8>, Box<dyn Error>> {
-    log::info!("File: {}", filename);
-    let mut file = match File::open(filename) {
-        Ok(file) => file,
-        Err(error) => {
-            log::error!("Failed to open file: {}", error);
-            return Err(Box::new(error));
-        }
-    };
-
-    let mut input: Vec<u8> = Vec::new();
-    let _ = match file.read_to_end(&mut input) {
-        Ok(_) => Ok(&input),
-        Err(error) => {
-            log::error!("Failed to read file: {}", error);
-            Err(Box::new(error))
-        }
-    };
-    Ok(input)
-}
-
-fn get_file_as_messagetype(filename: String) -> Result<MessageType, Box<dyn Error>> {
-    let input = read_file_to_bytes(&filename)?;
-    let path = Path::new(filename.as_str());
-
-    Ok(MessageType::File(
-        path.file_name().unwrap().to_str().unwrap().to_string(),
-        input,
-    ))
-}
-
-fn get_image_as_messagetype(filename: String) -> Result<MessageType, Box<dyn Error>> {
-    let input = read_file_to_bytes(&filename)?;
-    Ok(MessageType::Image(input))
-}
-
-fn handle_text(input: &str) -> Result<MessageType, Box<dyn Error>> {
-    Ok(MessageType::Text(input.to_string()))
-}
-
-fn handle_auth(input: &str) -> Result<MessageType, Box<dyn Error>> {
-    Ok(MessageType::Auth(
-        input.split(' ').collect::<Vec<&str>>()[1].to_string(),
-    ))
-}
-
-fn handle_file(input: &str) -> Result<MessageType, Box<dyn Error>> {
-    let (_left, right) = match input.splitn(2, ' ').collect::<Vec<&str>>().as_slice() {
-        [left, right] => (*left, *right),
-        _ => {
-            log::error!("Error: Invalid input");
-            ("", "")
-        }
-    };
-    get_file_as_messagetype(right.to_string())
-}
-
-fn handle_image(input: &str) -> Result<MessageType, Box<dyn Error>> {
-    let (_left, right) = match input.splitn(2, ' ').collect::<Vec<&str>>().as_slice() {
-        [left, right] => (*left, *right),
-        _ => {
-            log::error!("Error: Invalid input");
-            ("", "")
-        }
-    };
-    get_image_as_messagetype(right.to_string())
-}
-
-fn handle_operation(operation: &Operation, input: &str) -> Result<MessageType, Box<dyn Error>> {
-    match operation {
-        Operation::File => handle_file(input),
-        Operation::Image => handle_image(input),
-        Operation::Quit => Err("Exitting...".into()),
-        Operation::Text => handle_text(input),
-        Operation::Auth => handle_auth(input),
-    }
-}
-/// Handles the input from the user
-///
-/// # Arguments
-/// `input` - The input from the user, it will be pre-parsed to two parts - "command" as "left" and the rest as "right" part
-/// # Examples
-/// handle_vec_input(vec![".file".to_string(), "/full/path/to/file.txt".to_string()])
-/// handle_vec_input(vec![".image".to_string(), "/full/path/to/image.png".to_string()])
-/// handle_vec_input(vec![".quit".to_string()])
-/// handle_vec_input(vec![".text".to_string(), "Hello World".to_string()])
-pub fn handle_vec_input(input: Vec<String>) -> Result<MessageType, Box<dyn Error>> {
-    let operation = Operation::from(input[0].as_str());
-    handle_operation(&operation, &input.join(" "))
-}
+//! Module for handling various input types
+//!
+//! # Examples
+//! handle_vec_input(vec![".file".to_string(), "/full/path/to/file.txt".to_string()])
+//! handle_vec_input(vec![".image".to_string(), "/full/path/to/image.png | Optional caption".to_string()])
+//! handle_vec_input(vec![".quit".to_string()])
+//! handle_vec_input(vec![".text".to_string(), "Hello World".to_string()])
+//! handle_vec_input(vec![".attachments".to_string()])
+//! handle_vec_input(vec![".quota".to_string()])
+//! handle_vec_input(vec![".get".to_string(), "<attachment id> [destination]".to_string()])
+//! handle_vec_input(vec![".search".to_string(), "\"hello wor\"* from:<uid> since:7d".to_string()])
+//!
+//!
+//! There are several defined operations which can be used.
+//! For each operation a function is defined.
+//! "Auth" is only used for authentication, and is not intended to be used manually (but right now it could be).
+use std::{error::Error, fs::File, io::Read, path::Path};
+
+use anyhow::Result;
+use crate::search::SearchQuery;
+use crate::{get_timestamp, AttachmentInfo, AttachmentRequest, MessageType};
+
+#[derive(Debug)]
+pub enum Operation {
+    File,
+    Image,
+    Quit,
+    Text,
+    Attachments,
+    Get,
+    Quota,
+    Search,
+    Auth, // TODO: Add LoadAll - load all missed messages by this client
+}
+impl From<&str> for Operation {
+    fn from(value: &str) -> Self {
+        match value.to_lowercase().trim() {
+            ".file" => {
+                log::trace!("Operation: File");
+                Operation::File
+            }
+            ".image" => {
+                log::trace!("Operation: Image");
+                Operation::Image
+            }
+            ".quit" => {
+                log::trace!("Operation: Quit");
+                Operation::Quit
+            }
+            ".q" => {
+                log::trace!("Operation: Quit");
+                Operation::Quit
+            }
+            ".attachments" => {
+                log::trace!("Operation: Attachments");
+                Operation::Attachments
+            }
+            ".get" => {
+                log::trace!("Operation: Get");
+                Operation::Get
+            }
+            ".quota" => {
+                log::trace!("Operation: Quota");
+                Operation::Quota
+            }
+            ".search" => {
+                log::trace!("Operation: Search");
+                Operation::Search
+            }
+            ".auth" => {
+                log::trace!("Operation: Authenticaiton");
+                Operation::Auth
+            }
+            _ => {
+                log::trace!("Operation: Text");
+                Operation::Text
+            }
+        }
+    }
+}
+
+fn read_file_to_bytes(filename: &String) -> Result<Vec<u8>, Box<dyn Error>> {
+    log::info!("File: {}", filename);
+    let mut file = match File::open(filename) {
+        Ok(file) => file,
+        Err(error) => {
+            log::error!("Failed to open file: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+
+    let mut input: Vec<u8> = Vec::new();
+    let _ = match file.read_to_end(&mut input) {
+        Ok(_) => Ok(&input),
+        Err(error) => {
+            log::error!("Failed to read file: {}", error);
+            Err(Box::new(error))
+        }
+    };
+    Ok(input)
+}
+
+/// Reads `<path> [| caption]` into the descriptor of the file and its content, named as the file
+fn read_attachment(filename: String) -> Result<(AttachmentInfo, Vec<u8>), Box<dyn Error>> {
+    let (filename, caption) = match filename.split_once(" | ") {
+        Some((filename, caption)) => (filename.to_string(), Some(caption.trim().to_string())),
+        None => (filename, None),
+    };
+    let input = read_file_to_bytes(&filename)?;
+    let path = Path::new(filename.as_str());
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Invalid file name")?;
+    let caption = caption.filter(|caption| !caption.is_empty());
+    Ok((AttachmentInfo::new(name, &input, caption), input))
+}
+
+fn get_file_as_messagetype(filename: String) -> Result<MessageType, Box<dyn Error>> {
+    let (info, input) = read_attachment(filename)?;
+    Ok(MessageType::File(info, input))
+}
+
+fn get_image_as_messagetype(filename: String) -> Result<MessageType, Box<dyn Error>> {
+    let (info, input) = read_attachment(filename)?;
+    Ok(MessageType::Image(info, input))
+}
+
+fn handle_text(input: &str) -> Result<MessageType, Box<dyn Error>> {
+    Ok(MessageType::Text(input.to_string()))
+}
+
+fn handle_auth(input: &str) -> Result<MessageType, Box<dyn Error>> {
+    Ok(MessageType::Auth(
+        input.split(' ').collect::<Vec<&str>>()[1].to_string(),
+    ))
+}
+
+fn handle_file(input: &str) -> Result<MessageType, Box<dyn Error>> {
+    let (_left, right) = match input.splitn(2, ' ').collect::<Vec<&str>>().as_slice() {
+        [left, right] => (*left, *right),
+        _ => {
+            log::error!("Error: Invalid input");
+            ("", "")
+        }
+    };
+    get_file_as_messagetype(right.to_string())
+}
+
+fn handle_image(input: &str) -> Result<MessageType, Box<dyn Error>> {
+    let (_left, right) = match input.splitn(2, ' ').collect::<Vec<&str>>().as_slice() {
+        [left, right] => (*left, *right),
+        _ => {
+            log::error!("Error: Invalid input");
+            ("", "")
+        }
+    };
+    get_image_as_messagetype(right.to_string())
+}
+
+fn handle_attachments(_input: &str) -> Result<MessageType, Box<dyn Error>> {
+    Ok(MessageType::AttachmentRequest(AttachmentRequest::List))
+}
+
+fn handle_quota(_input: &str) -> Result<MessageType, Box<dyn Error>> {
+    Ok(MessageType::AttachmentRequest(AttachmentRequest::Quota))
+}
+
+/// Searches stored messages, see `search` for the query syntax
+fn handle_search(input: &str) -> Result<MessageType, Box<dyn Error>> {
+    let query = input.trim().split_once(' ').map_or("", |(_, query)| query);
+    let query = SearchQuery::parse(query, get_timestamp().parse()?)?;
+    if query.terms().is_empty() {
+        return Err("Usage: .search <words, \"phrase\" or prefix*> [from:<uid>] [since:<time>] [until:<time>]".into());
+    }
+    Ok(MessageType::SearchRequest(query))
+}
+
+/// Requests the first chunk, destination is handled by the client receiving the chunks
+fn handle_get(input: &str) -> Result<MessageType, Box<dyn Error>> {
+    let (id, _dest) = parse_get(input).ok_or("Usage: .get <attachment id> [destination]")?;
+    Ok(MessageType::AttachmentRequest(
+        AttachmentRequest::Download {
+            id: id.to_string(),
+            offset: 0,
+        },
+    ))
+}
+
+/// Splits ".get <id> [dest]" into attachment ID and optional destination path
+pub fn parse_get(input: &str) -> Option<(&str, Option<&str>)> {
+    let mut parts = input.trim().splitn(3, ' ').skip(1);
+    let id = parts.next().filter(|id| !id.is_empty())?;
+    let dest = parts.next().map(str::trim).filter(|dest| !dest.is_empty());
+    Some((id, dest))
+}
+
+fn handle_operation(operation: &Operation, input: &str) -> Result<MessageType, Box<dyn Error>> {
+    match operation {
+        Operation::File => handle_file(input),
+        Operation::Image => handle_image(input),
+        Operation::Quit => Err("Exitting...".into()),
+        Operation::Text => handle_text(input),
+        Operation::Attachments => handle_attachments(input),
+        Operation::Get => handle_get(input),
+        Operation::Quota => handle_quota(input),
+        Operation::Search => handle_search(input),
+        Operation::Auth => handle_auth(input),
+    }
+}
+/// Handles the input from the user
+///
+/// # Arguments
+/// `input` - The input from the user, it will be pre-parsed to two parts - "command" as "left" and the rest as "right" part
+/// # Examples
+/// handle_vec_input(vec![".file".to_string(), "/full/path/to/file.txt".to_string()])
+/// handle_vec_input(vec![".image".to_string(), "/full/path/to/image.png | Optional caption".to_string()])
+/// handle_vec_input(vec![".quit".to_string()])
+/// handle_vec_input(vec![".text".to_string(), "Hello World".to_string()])
+/// handle_vec_input(vec![".get".to_string(), "<attachment id> /path/to/dest".to_string()])
+pub fn handle_vec_input(input: Vec<String>) -> Result<MessageType, Box<dyn Error>> {
+    let operation = Operation::from(input[0].as_str());
+    handle_operation(&operation, &input.join(" "))
+}
@@ -4,16 +4,15 @@
 //! between the client and the server is implemented here.
 //!
 //!
-use anyhow::Context;
+use bytes::Bytes;
 use std::{
     env,
     fmt::Display,
     io::{self, Cursor},
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
-use uuid::Uuid;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::{
@@ -30,14 +29,45 @@ use image::{codecs::png::PngEncoder, io::Reader as ImageReader, ImageError};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use blob_store::BlobStore;
 use eyre::Result;
+use file_name::{numbered_file_name, sanitize_file_name, FileNameError};
+use images::ImageInfo;
+use search::{SearchHit, SearchQuery};
 
+pub mod address;
+pub mod archive;
+pub mod backup;
+pub mod blob_store;
+pub mod config;
 pub mod db_client;
+pub mod envelope;
+pub mod file_name;
+pub mod images;
 pub mod input_handler;
+mod test_address;
+mod test_archive;
+mod test_backup;
+mod test_blob_store;
+mod test_config;
 mod test_db_client;
+mod test_envelope;
+mod test_file_name;
+mod test_frame;
+mod test_images;
 mod test_input_handler;
+mod test_migrations;
+mod test_mime;
+mod test_retention;
+mod test_search;
+mod test_store;
 
 pub mod metrics;
+pub mod migrations;
+pub mod mime;
+pub mod retention;
+pub mod search;
+pub mod store;
 
 #[derive(Error, Debug)]
 pub enum DataProcessingError {
@@ -51,10 +81,30 @@ pub enum DataProcessingError {
     Serde(#[from] serde_json::Error),
     #[error("Cannot process image - invalid image format")]
     ImageError(#[from] ImageError),
+    #[error("Image of {0} bytes exceeds the limit of {1} bytes")]
+    ImageTooLarge(usize, usize),
+    #[error("Frame of {0} bytes exceeds the limit")]
+    FrameTooLarge(usize),
+    #[error("Rejected file name {0:?}: {1}")]
+    RejectedFileName(String, FileNameError),
+    #[error("Content of {0} does not match its checksum")]
+    ChecksumMismatch(String),
+    #[error("Content of {0} is {2}, not {1} as declared")]
+    MimeMismatch(String, String, String),
+    #[error("Upload of {0} blocked: {1}")]
+    BlockedUpload(String, mime::BlockedType),
+    #[error("Stored message cannot be decoded: {0}")]
+    Decode(#[from] envelope::EnvelopeError),
     #[error("Exitting")]
     Exit,
 }
 
+/// How many ` (n)` suffixes are tried when a received file name is taken already
+const MAX_NAME_COLLISIONS: usize = 1000;
+
+/// Largest frame accepted by `read_from_stream`, see `config::LimitsSection` to change it
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
 #[derive(Error, Debug)]
 pub enum ConnectionError {
     #[error("Server not found: {0}")]
@@ -68,26 +118,303 @@ pub enum ConnectionError {
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MessageType {
     Text(String),
-    Image(Vec<u8>),
-    File(String, Vec<u8>), // Filename and its content as bytes
+    Image(AttachmentInfo, Vec<u8>),
+    File(AttachmentInfo, Vec<u8>), // Descriptor of the file and its content as bytes
     Error(String),
     Auth(String),
+    Shutdown(String), // Server is going down - clients should reconnect later
+    AttachmentRequest(AttachmentRequest), // Client asks for stored files/images
+    AttachmentResponse(AttachmentResponse), // Server's answer to AttachmentRequest, sent to the asking client only
+    SearchRequest(SearchQuery),             // Client searches stored messages
+    SearchResponse(Vec<SearchHit>), // Server's answer to SearchRequest, sent to the asking client only
+}
+
+/// Request of a client about attachments stored on the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AttachmentRequest {
+    /// All attachments, without their content
+    List,
+    /// Content of attachment starting at `offset`, the server answers with a single chunk.
+    /// The client asks for the next chunk once it has the previous one, so large files never
+    /// fill the outbox or exceed the frame size.
+    Download { id: String, offset: u64 },
+    /// Attachment storage used by the asking user and its quota
+    Quota,
+}
+
+/// Answer of the server to `AttachmentRequest`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AttachmentResponse {
+    List(Vec<Attachment>),
+    Chunk(AttachmentChunk),
+    /// No attachment with this ID (or offset beyond its end)
+    NotFound(String),
+    Quota(StorageQuota),
+}
+
+/// Part of the content of an attachment
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttachmentChunk {
+    pub attachment: Attachment,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl AttachmentChunk {
+    /// True if this chunk ends the content
+    pub fn is_last(&self) -> bool {
+        self.offset + self.data.len() as u64 >= self.attachment.size as u64
+    }
+}
+
+/// Largest chunk of attachment content sent in one frame
+pub const ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Descriptor of the content of a `File` or `Image` message
+///
+/// Filled in by the sender, the receiver checks the content against it with `verify` before using it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttachmentInfo {
+    pub name: String,
+    /// Detected from the content, see `mime::sniff`
+    pub mime: String,
+    /// Size of the content in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+}
+
+impl Display for AttachmentInfo {
+    /// Name followed by the caption, if any
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.caption {
+            Some(caption) => write!(f, "{}: {}", self.name, caption),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl AttachmentInfo {
+    /// Describes `data` sent under `name`
+    pub fn new(name: &str, data: &[u8], caption: Option<String>) -> Self {
+        AttachmentInfo {
+            name: name.to_string(),
+            mime: mime::sniff(data).to_string(),
+            size: data.len() as u64,
+            sha256: BlobStore::hash(data),
+            caption,
+        }
+    }
+
+    /// Fails unless `data` is the described content
+    pub fn verify(&self, data: &[u8]) -> Result<(), DataProcessingError> {
+        if self.size != data.len() as u64 || self.sha256 != BlobStore::hash(data) {
+            return Err(DataProcessingError::ChecksumMismatch(self.name.clone()));
+        }
+        let sniffed = mime::sniff(data);
+        if self.mime != sniffed {
+            return Err(DataProcessingError::MimeMismatch(
+                self.name.clone(),
+                self.mime.clone(),
+                sniffed.to_string(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Display for MessageType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             MessageType::Text(t) => write!(f, "{}", t),
-            MessageType::Image(_) => write!(f, "Image"),
-            MessageType::File(_, _) => write!(f, "File"),
+            MessageType::Image(info, _) => write!(f, "Image {}", info),
+            MessageType::File(info, _) => write!(f, "File {}", info),
             MessageType::Error(e) => write!(f, "Error: {}", e),
             MessageType::Auth(a) => write!(f, "Auth: {}", a),
+            MessageType::Shutdown(s) => write!(f, "Server shutting down: {}", s),
+            MessageType::AttachmentRequest(r) => write!(f, "Attachment request: {:?}", r),
+            MessageType::AttachmentResponse(AttachmentResponse::List(list)) => {
+                write!(f, "{} attachments", list.len())
+            }
+            MessageType::AttachmentResponse(AttachmentResponse::Chunk(chunk)) => write!(
+                f,
+                "Attachment {} bytes {}-{} of {}",
+                chunk.attachment.id,
+                chunk.offset,
+                chunk.offset + chunk.data.len() as u64,
+                chunk.attachment.size
+            ),
+            MessageType::AttachmentResponse(AttachmentResponse::NotFound(id)) => {
+                write!(f, "Attachment {} not found", id)
+            }
+            MessageType::AttachmentResponse(AttachmentResponse::Quota(quota)) => {
+                write!(f, "Attachment storage: {}", quota)
+            }
+            MessageType::SearchRequest(query) => write!(f, "Search: {}", query.text),
+            MessageType::SearchResponse(hits) => write!(f, "{} messages found", hits.len()),
         }
     }
 }
+impl MessageType {
+    /// File message with `data` sent as `name`, without caption
+    pub fn file(name: &str, data: Vec<u8>) -> Self {
+        MessageType::File(AttachmentInfo::new(name, &data, None), data)
+    }
+
+    /// Image message with `data` sent as `name`, without caption
+    pub fn image(name: &str, data: Vec<u8>) -> Self {
+        MessageType::Image(AttachmentInfo::new(name, &data, None), data)
+    }
+
+    /// Kind of the message, as stored in the `kind` column of `messages`
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            MessageType::Text(_) => MessageKind::Text,
+            MessageType::Image(..) => MessageKind::Image,
+            MessageType::File(..) => MessageKind::File,
+            MessageType::Error(_) => MessageKind::Error,
+            MessageType::Auth(_) => MessageKind::Auth,
+            MessageType::Shutdown(_) => MessageKind::Shutdown,
+            MessageType::AttachmentRequest(_) => MessageKind::AttachmentRequest,
+            MessageType::AttachmentResponse(_) => MessageKind::AttachmentResponse,
+            MessageType::SearchRequest(_) => MessageKind::SearchRequest,
+            MessageType::SearchResponse(_) => MessageKind::SearchResponse,
+        }
+    }
+}
+
+/// Kind of a MessageType without its content, used to filter messages in DB
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Text,
+    Image,
+    File,
+    Error,
+    Auth,
+    Shutdown,
+    AttachmentRequest,
+    AttachmentResponse,
+    SearchRequest,
+    SearchResponse,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Text => "text",
+            MessageKind::Image => "image",
+            MessageKind::File => "file",
+            MessageKind::Error => "error",
+            MessageKind::Auth => "auth",
+            MessageKind::Shutdown => "shutdown",
+            MessageKind::AttachmentRequest => "attachment_request",
+            MessageKind::AttachmentResponse => "attachment_response",
+            MessageKind::SearchRequest => "search_request",
+            MessageKind::SearchResponse => "search_response",
+        }
+    }
+}
+
+impl Display for MessageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for MessageKind {
+    type Err = DataProcessingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(MessageKind::Text),
+            "image" => Ok(MessageKind::Image),
+            "file" => Ok(MessageKind::File),
+            "error" => Ok(MessageKind::Error),
+            "auth" => Ok(MessageKind::Auth),
+            "shutdown" => Ok(MessageKind::Shutdown),
+            "attachment_request" => Ok(MessageKind::AttachmentRequest),
+            "attachment_response" => Ok(MessageKind::AttachmentResponse),
+            "search_request" => Ok(MessageKind::SearchRequest),
+            "search_response" => Ok(MessageKind::SearchResponse),
+            _ => Err(DataProcessingError::InvalidFormat),
+        }
+    }
+}
+
+/// File or image attached to a stored message, without its content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub mime: String,
+    /// Size in bytes
+    pub size: i64,
+    /// SHA-256 of the content, hex encoded
+    pub hash: String,
+    /// Metadata and thumbnails, for images that could be decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInfo>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct User {
     pub uid: String,
+    /// Bytes of attachments of the messages the user sent
+    #[serde(default)]
+    pub used_bytes: i64,
+    /// Storage quota set by an admin (0 for unlimited), the default of the server applies if `None`
+    #[serde(default)]
+    pub quota_bytes: Option<i64>,
+}
+
+impl User {
+    /// User with no attachments stored and the default quota
+    pub fn new(uid: String) -> Self {
+        User {
+            uid,
+            used_bytes: 0,
+            quota_bytes: None,
+        }
+    }
+}
+
+/// Attachment storage used by a user and its limit
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StorageQuota {
+    pub used_bytes: u64,
+    /// `None` if unlimited
+    pub quota_bytes: Option<u64>,
+}
+
+impl StorageQuota {
+    /// Quota of `user`, `default_bytes` (0 for unlimited) unless an admin set another one
+    pub fn of(user: &User, default_bytes: u64) -> Self {
+        let quota = user
+            .quota_bytes
+            .map_or(default_bytes, |quota| quota.max(0) as u64);
+        StorageQuota {
+            used_bytes: user.used_bytes.max(0) as u64,
+            quota_bytes: (quota > 0).then_some(quota),
+        }
+    }
+
+    /// True if `size` more bytes can be stored without exceeding the quota
+    pub fn allows(&self, size: u64) -> bool {
+        self.quota_bytes
+            .is_none_or(|quota| self.used_bytes + size <= quota)
+    }
+}
+
+impl Display for StorageQuota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.quota_bytes {
+            Some(quota) => write!(f, "{} of {} bytes used", self.used_bytes, quota),
+            None => write!(f, "{} bytes used, no quota", self.used_bytes),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -96,6 +423,9 @@ pub struct Message {
     pub uid: String,
     pub timestamp: String,
     pub message: MessageType,
+    /// ID of the stored content of a file or image, see `AttachmentRequest::Download`
+    #[serde(default)]
+    pub attachment_id: Option<String>,
 }
 
 /// Serialize a MessageType using JSON.
@@ -110,18 +440,18 @@ pub fn deserialize_message(data: &[u8]) -> Result<MessageType, crate::DataProces
     Ok(serde_json::from_slice(data)?)
 }
 
-/// De-Serialize a MessageType using binary.
+/// Serialize a MessageType using binary, in the current version of the storage envelope.
 /// Used for storing data in DB
 pub fn serialize_message_as_bin(
     message: &MessageType,
 ) -> Result<Vec<u8>, crate::DataProcessingError> {
-    Ok(bincode::serialize(message).unwrap())
+    Ok(envelope::encode(message)?)
 }
 
-/// De-Serialize a MessageType using binary.
+/// De-Serialize a MessageType using binary, any version of the storage envelope is accepted.
 /// Used for retrieving data from DB
 pub fn deserialize_message_as_bin(data: &[u8]) -> Result<MessageType, crate::DataProcessingError> {
-    Ok(bincode::deserialize(data).unwrap())
+    Ok(envelope::decode(data)?)
 }
 
 /// Generic user I/O function, waits for user input.
@@ -129,73 +459,30 @@ pub fn await_input() -> Result<String, crate::DataProcessingError> {
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
         Ok(_res) => {
-            if input == ".quit" || input.is_empty() || input == ".q" {
+            // End of input, `.quit` is up to the caller
+            if input.is_empty() {
                 Err(DataProcessingError::Exit)
             } else {
-                return Ok(input.trim().to_string());
+                Ok(input.trim().to_string())
             }
         }
         Err(err) => Err(err.into()),
     }
 }
 
-/// Returns a (SocketAddr, Uuid), using default if cannot parse and/or no args given
-pub fn get_addr(args: Vec<String>) -> Result<(SocketAddrV4, Uuid), crate::DataProcessingError> {
-    // Evaluate args
-    //println!("{:?}", args);
-    // Validate args for hostname and port
-    let hostname: Result<Ipv4Addr, std::net::AddrParseError>;
-    let port: Result<u16, std::num::ParseIntError>;
-    let mut uid: Result<Uuid, uuid::Error> = Ok(Uuid::new_v4());
-    if args.len() < 3 {
-        log::warn!("Usage: server/client <hostname> <port> <uid>; using default values now...");
-        hostname = "127.0.0.1".parse::<Ipv4Addr>();
-        port = "11111".parse::<u16>();
-    } else {
-        hostname = args[1].parse::<Ipv4Addr>();
-        match hostname {
-            Ok(h) => {
-                log::info!("Parsed hostname: {:?}", h);
-            }
-            Err(e) => {
-                log::error!("Error parsing hostname: {:?}", e);
-                panic!()
-            }
-        }
-        port = args[2].parse::<u16>();
-        match port {
-            Ok(p) => {
-                log::info!("Parsed port: {:?}", &p);
-            }
-            Err(e) => {
-                log::error!("Error parsing port: {:?}", e);
-                panic!()
-            }
-        }
-        if args.len() == 4 {
-            uid = args[3].parse::<Uuid>();
-            match uid {
-                Ok(p) => {
-                    log::info!("Parsed uid: {:?}", &p);
-                }
-                Err(e) => {
-                    log::error!("Error parsing uid: {:?}", e);
-                    panic!()
-                }
-            }
-        }
-    }
-
-    Ok((
-        SocketAddrV4::new(hostname.unwrap(), port.to_owned().unwrap()),
-        uid.unwrap(),
-    ))
-}
-
 /// Generic function to read from "ReadHalf" of stream
 /// Uses JSON encoding to deserialize the MessageType read.
 pub async fn read_from_stream(
     stream: &mut OwnedReadHalf,
+) -> Result<MessageType, crate::DataProcessingError> {
+    read_from_stream_limited(stream, DEFAULT_MAX_FRAME_SIZE).await
+}
+
+/// Same as `read_from_stream`, refusing frames larger than `max_frame_size` bytes.
+/// The stream cannot be used anymore after `FrameTooLarge` error.
+pub async fn read_from_stream_limited(
+    stream: &mut OwnedReadHalf,
+    max_frame_size: usize,
 ) -> Result<MessageType, crate::DataProcessingError> {
     // Read first 4 bytes containing length of the rest of the message
     let mut len_bytes = [0u8; 4];
@@ -205,6 +492,10 @@ pub async fn read_from_stream(
     };
 
     let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > max_frame_size {
+        log::error!("Refusing frame of {} bytes", len);
+        return Err(DataProcessingError::FrameTooLarge(len));
+    }
     if len > 0 {
         log::trace!("Receiving data...");
         let mut buffer = vec![0u8; len];
@@ -225,60 +516,73 @@ pub async fn read_from_stream(
     }
 }
 
+/// Encode a MessageType into a complete wire frame - 4-byte big endian length followed by JSON.
+/// The frame is immutable and cheap to clone, so a broadcast message is encoded only once
+/// and the same buffer is shared by all recipients.
+pub fn encode_frame(message: &MessageType) -> Result<Bytes, DataProcessingError> {
+    let mut buffer = vec![0u8; 4];
+    serde_json::to_writer(&mut buffer, message)?;
+    let len = (buffer.len() - 4) as u32;
+    buffer[..4].copy_from_slice(&len.to_be_bytes());
+    Ok(Bytes::from(buffer))
+}
+
+/// Write an already encoded frame (see `encode_frame`) to "WriteHalf" of stream
+pub async fn write_frame_to_stream(
+    stream: &mut OwnedWriteHalf,
+    frame: &Bytes,
+) -> Result<(), DataProcessingError> {
+    stream.write_all(frame).await?;
+    log::info!("Transfer complete!");
+    Ok(())
+}
+
 /// Generic function to write to "WriteHalf" of stream
 /// Uses JSON encoding to serialize the MessageType.
 pub async fn write_to_stream(
     stream: &mut OwnedWriteHalf,
     message: &MessageType,
 ) -> Result<(), DataProcessingError> {
-    let ser_message = serialize_message(message)
-        .map_err(|e| log::error!("Error: {:?}", e))
-        .unwrap();
-    // Send the length of the serialized message (as 4-byte value).
-    let len = ser_message.len() as u32;
-    stream
-        .write_all(&len.to_be_bytes())
-        .await
-        .expect("Failed to write to stream");
-
-    // Send the serialized message.
-    let s = stream.write_all(ser_message.as_bytes()).await;
-    match s {
-        Ok(it) => it,
-        Err(err) => return Err(DataProcessingError::Io(err)),
-    };
-
-    log::info!("Transfer complete!");
-    Ok(())
+    let frame = encode_frame(message).map_err(|e| {
+        log::error!("Error: {:?}", e);
+        e
+    })?;
+    write_frame_to_stream(stream, &frame).await
 }
 
-/// Handler of Messages received
-pub async fn handle_stream_message(message: MessageType) -> MessageType {
+/// Handler of Messages received, files and images are written into `files_dir`
+pub async fn handle_stream_message(message: MessageType, files_dir: &Path) -> MessageType {
     match &message {
         MessageType::Auth(uid) => {
             log::info!("Authenticating user {:?}", &uid);
             // Save UID in DB
             MessageType::Auth(format!("{:?}", uid))
         }
-        MessageType::File(name, file) => {
+        MessageType::File(info, file) => {
             // Write file into files/ dir
-            let result = write_file(&message, file, name);
+            let result = async {
+                info.verify(file)?;
+                write_file(file, &info.name, files_dir).await
+            };
             match result.await {
                 Err(e) => {
                     log::error!("Error: {:?}", e);
-                    MessageType::Text(format!("Error: {:?}", e))
+                    MessageType::Text(format!("Error: {}", e))
                 }
                 Ok(msg) => MessageType::Text(format!("{:?}", msg)),
             }
         }
-        MessageType::Image(file) => {
+        MessageType::Image(info, file) => {
             // Write image into files/ dir
-            let result = write_image(&message, file);
+            let result = async {
+                info.verify(file)?;
+                write_image(file, files_dir).await
+            };
             // If result is error, send message back to client
             match result.await {
                 Err(e) => {
                     log::error!("Error: {:?}", e);
-                    MessageType::Text(format!("Error: {:?}", e))
+                    MessageType::Text(format!("Error: {}", e))
                 }
                 Ok(msg) => MessageType::Text(format!("{:?}", msg)),
             }
@@ -288,6 +592,30 @@ pub async fn handle_stream_message(message: MessageType) -> MessageType {
             message
         }
         MessageType::Error(e) => MessageType::Error(format!("Error: {}", e)),
+        MessageType::Shutdown(reason) => {
+            log::info!("Server is shutting down: {}", reason);
+            message
+        }
+        MessageType::AttachmentResponse(AttachmentResponse::List(attachments)) => {
+            log::info!("{} attachments stored on server:", attachments.len());
+            for a in attachments {
+                log::info!("{}  {} ({}, {} bytes)", a.id, a.name, a.mime, a.size);
+            }
+            message
+        }
+        MessageType::SearchResponse(hits) => {
+            log::info!("{} messages found:", hits.len());
+            for hit in hits {
+                log::info!("{}", hit);
+            }
+            message
+        }
+        MessageType::AttachmentRequest(_)
+        | MessageType::AttachmentResponse(_)
+        | MessageType::SearchRequest(_) => {
+            log::info!("Received: {}", message);
+            message
+        }
     }
 }
 
@@ -300,45 +628,73 @@ pub fn get_timestamp() -> String {
         .to_string()
 }
 
-/// Helper function to build path to files
-async fn prepare_path(
-    message: &MessageType,
+/// Helper function to prepare the storage root, relative `files_dir` is resolved against current dir
+///
+/// The root is canonicalized, so files created in it cannot end up elsewhere through symlinks in its path.
+async fn prepare_path(files_dir: &Path) -> Result<PathBuf, DataProcessingError> {
+    let path = env::current_dir()?.join(files_dir);
+    if let Err(e) = fs::create_dir_all(&path).await {
+        log::error!("Failed to create target path: {}", e);
+        return Err(DataProcessingError::Io(e));
+    }
+    Ok(fs::canonicalize(&path).await?)
+}
+
+/// Creates a new file named `file_name` in `root`, a name already taken gets a ` (n)` suffix
+///
+/// Names are checked by `file_name::sanitize_file_name`, existing files (and symlinks) are never
+/// opened or overwritten.
+async fn create_unique_file(
+    root: &Path,
     file_name: &str,
-    current_timestamp: &str,
-) -> Result<PathBuf, DataProcessingError> {
-    let path = env::current_dir();
-    match path {
-        Ok(mut path) => {
-            path.push("files");
-            match fs::create_dir_all(&path).await {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("Failed to create target path: {}", e);
-                    return Err(DataProcessingError::Io(e));
-                }
-            };
-            if let MessageType::Image(_i) = message {
-                path.push(String::from(current_timestamp) + ".png");
-            } else {
-                path.push(file_name);
-            }
-            Ok(path)
+) -> Result<(File, PathBuf), DataProcessingError> {
+    let file_name = sanitize_file_name(file_name)
+        .map_err(|e| DataProcessingError::RejectedFileName(file_name.to_string(), e))?;
+    for n in 0..MAX_NAME_COLLISIONS {
+        let name = match n {
+            0 => file_name.to_string(),
+            n => numbered_file_name(file_name, n),
+        };
+        let path = root.join(&name);
+        // A sanitized name is a single component, checked anyway as the last line of defence
+        if path.parent() != Some(root) {
+            return Err(DataProcessingError::RejectedFileName(
+                name,
+                FileNameError::PathSeparator,
+            ));
+        }
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(DataProcessingError::Io(e)),
         }
-        Err(e) => Err(DataProcessingError::Io(e)),
     }
+    Err(DataProcessingError::Io(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("too many files named {}", file_name),
+    )))
 }
 
-/// Helper function to write file into files/ dir
+/// Helper function to write file into files dir, under its own name unless taken already
 async fn write_file(
-    message: &MessageType,
     file: &[u8],
     file_name: &str,
+    files_dir: &Path,
 ) -> Result<String, DataProcessingError> {
-    let path = prepare_path(message, file_name, "").await?;
-    let tgt_file = File::create(&path)
-        .await
-        .context(format!("Cannot create file at {:?}", &path.to_str()));
-    match tgt_file.unwrap().write_all(file).await {
+    let root = prepare_path(files_dir).await?;
+    let (mut tgt_file, path) = create_unique_file(&root, file_name).await?;
+    // tokio writes in the background, flush makes sure the content is written when reported
+    let written = async {
+        tgt_file.write_all(file).await?;
+        tgt_file.flush().await
+    }
+    .await;
+    match written {
         Ok(_) => {
             let msg = format!(
                 "Received file {} written to: {:?}",
@@ -349,19 +705,35 @@ async fn write_file(
             Ok(msg)
         }
         Err(e) => {
-            log::error!(
-                "Failed to open target path: {}",
-                path.as_os_str().to_os_string().to_str().unwrap()
-            );
+            log::error!("Failed to write target path: {}", path.display());
             Err(DataProcessingError::Io(e))
         }
     }
 }
-/// Helper function to write image into files/ dir
+/// Creates the file a downloaded attachment is written to
+///
+/// `dest` is used as is when it is a file path, a directory gets a new file named after the attachment
+/// (as does `files_dir` when no `dest` is given).
+pub async fn create_download_file(
+    dest: Option<&Path>,
+    file_name: &str,
+    files_dir: &Path,
+) -> Result<(File, PathBuf), DataProcessingError> {
+    let root = match dest {
+        Some(dest) if !fs::metadata(dest).await.is_ok_and(|m| m.is_dir()) => {
+            return Ok((fs::File::create(dest).await?, dest.to_path_buf()));
+        }
+        Some(dest) => prepare_path(dest).await?,
+        None => prepare_path(files_dir).await?,
+    };
+    create_unique_file(&root, file_name).await
+}
+
+/// Helper function to write image into files dir
 /// Images are encoded as PNG, and renamed to <timestamp>.png
-async fn write_image(message: &MessageType, file: &[u8]) -> Result<String, DataProcessingError> {
+async fn write_image(file: &[u8], files_dir: &Path) -> Result<String, DataProcessingError> {
     let current_timestamp = get_timestamp();
-    let path = prepare_path(message, "", &current_timestamp).await?;
+    let root = prepare_path(files_dir).await?;
 
     let mut bytes: Vec<u8> = Vec::new();
     //let img = BufReader::new(file);
@@ -369,27 +741,47 @@ async fn write_image(message: &MessageType, file: &[u8]) -> Result<String, DataP
     let img = ImageReader::new(data)
         .with_guessed_format()
         .expect("This will never fail using Cursor");
-    let img = img.decode().unwrap();
-    match img.write_with_encoder(PngEncoder::new(&mut bytes)) {
+    let image_format = img.format();
+    let format = image_format.map_or("unknown".to_string(), images::format_name);
+    let img = img.decode()?;
+    // Converting an animation to PNG would keep only its first frame, it is written as received
+    let frames = match image_format {
+        Some(image_format) => images::decode_animation(file, image_format)?.map(|f| f.len()),
+        None => None,
+    };
+    let (extension, encoded) = match frames {
+        Some(_) => {
+            bytes.extend_from_slice(file);
+            (format.as_str(), Ok(()))
+        }
+        None => ("png", img.write_with_encoder(PngEncoder::new(&mut bytes))),
+    };
+    let file_name = format!("{}.{}", current_timestamp, extension);
+    match encoded {
         Ok(_res) => {
-            let tgt_file = File::create(&path)
-                .await
-                .context(format!("Cannot create file at {:?}", &path.to_str()));
-            match tgt_file.unwrap().write_all(&bytes).await {
+            let (mut tgt_file, path) = create_unique_file(&root, &file_name).await?;
+            let written = async {
+                tgt_file.write_all(&bytes).await?;
+                tgt_file.flush().await
+            }
+            .await;
+            match written {
                 Ok(_) => {
                     let msg = format!(
-                        "Received image {} written to: {:?}",
-                        current_timestamp + ".png",
+                        "Received image {} ({}x{} {}{}, {} bytes) written to: {:?}",
+                        file_name,
+                        img.width(),
+                        img.height(),
+                        format,
+                        frames.map_or(String::new(), |n| format!(", {} frames", n)),
+                        file.len(),
                         path
                     );
                     log::info!("{}", msg.as_str());
                     Ok(msg)
                 }
                 Err(e) => {
-                    log::error!(
-                        "Failed to open target path: {}",
-                        path.as_os_str().to_os_string().to_str().unwrap()
-                    );
+                    log::error!("Failed to write target path: {}", path.display());
                     Err(DataProcessingError::Io(e))
                 }
             }
@@ -1,5 +1,8 @@
 use lazy_static::lazy_static;
 use prometheus::{Encoder, Gauge, IntCounter, Opts, TextEncoder};
+use std::sync::Once;
+
+static INIT: Once = Once::new();
 
 lazy_static! {
     static ref METRICS_COUNTER: IntCounter = IntCounter::new(
@@ -14,6 +17,51 @@ lazy_static! {
         "How many clients are currently connected"
     ))
     .expect("metric can be created");
+    static ref OUTBOX_LAG_COUNT: IntCounter = IntCounter::new(
+        "http_outbox_lag_counter",
+        "How many times a message was sent to a client with full outbox"
+    )
+    .unwrap();
+    static ref OUTBOX_DROP_COUNT: IntCounter = IntCounter::new(
+        "http_outbox_dropped_counter",
+        "How many messages were dropped from full client outboxes"
+    )
+    .unwrap();
+    static ref SLOW_CLIENT_DISCONNECT_COUNT: IntCounter = IntCounter::new(
+        "http_slow_client_disconnect_counter",
+        "How many clients were disconnected for not reading their messages"
+    )
+    .unwrap();
+    static ref RATE_LIMITED_COUNT: IntCounter = IntCounter::new(
+        "http_rate_limited_counter",
+        "How many messages were rejected for exceeding the rate limit"
+    )
+    .unwrap();
+    static ref BLOCKED_UPLOAD_COUNT: IntCounter = IntCounter::new(
+        "http_blocked_upload_counter",
+        "How many files and images were refused for their content type"
+    )
+    .unwrap();
+    static ref CONFIG_RELOAD_COUNT: IntCounter = IntCounter::new(
+        "http_config_reload_counter",
+        "How many times the server config was reloaded"
+    )
+    .unwrap();
+    static ref PURGED_MESSAGE_COUNT: IntCounter = IntCounter::new(
+        "http_retention_purged_messages_counter",
+        "How many messages were deleted by the retention policy"
+    )
+    .unwrap();
+    static ref PURGED_ATTACHMENT_COUNT: IntCounter = IntCounter::new(
+        "http_retention_purged_attachments_counter",
+        "How many files and images were deleted by the retention policy"
+    )
+    .unwrap();
+    static ref PURGED_BYTES_COUNT: IntCounter = IntCounter::new(
+        "http_retention_purged_bytes_counter",
+        "How many bytes of attachments were deleted by the retention policy"
+    )
+    .unwrap();
 }
 
 pub async fn get_metrics() -> Result<String, prometheus::Error> {
@@ -39,14 +87,75 @@ pub fn dec_client_count() {
     CLIENT_COUNT.dec();
 }
 
+pub fn inc_outbox_lag_count() {
+    OUTBOX_LAG_COUNT.inc();
+}
+
+pub fn inc_outbox_drop_count() {
+    OUTBOX_DROP_COUNT.inc();
+}
+
+pub fn inc_slow_client_disconnect_count() {
+    SLOW_CLIENT_DISCONNECT_COUNT.inc();
+}
+
+pub fn inc_rate_limited_count() {
+    RATE_LIMITED_COUNT.inc();
+}
+
+pub fn inc_blocked_upload_count() {
+    BLOCKED_UPLOAD_COUNT.inc();
+}
+
+pub fn inc_config_reload_count() {
+    CONFIG_RELOAD_COUNT.inc();
+}
+
+/// Counts what was deleted by enforcing the retention policy
+pub fn add_purged(messages: u64, attachments: u64, bytes: u64) {
+    PURGED_MESSAGE_COUNT.inc_by(messages);
+    PURGED_ATTACHMENT_COUNT.inc_by(attachments);
+    PURGED_BYTES_COUNT.inc_by(bytes);
+}
+
+/// Registers all counters in the default registry, calling it more than once is harmless
 pub fn init_counters() {
-    prometheus::default_registry()
-        .register(Box::new(METRICS_COUNTER.clone()))
-        .expect("Failed to register metrics counter");
-    prometheus::default_registry()
-        .register(Box::new(MSG_COUNT.clone()))
-        .expect("Failed to register message counter");
-    prometheus::default_registry()
-        .register(Box::new(CLIENT_COUNT.clone()))
-        .expect("Failed to register client counter");
+    INIT.call_once(|| {
+        prometheus::default_registry()
+            .register(Box::new(METRICS_COUNTER.clone()))
+            .expect("Failed to register metrics counter");
+        prometheus::default_registry()
+            .register(Box::new(MSG_COUNT.clone()))
+            .expect("Failed to register message counter");
+        prometheus::default_registry()
+            .register(Box::new(CLIENT_COUNT.clone()))
+            .expect("Failed to register client counter");
+        prometheus::default_registry()
+            .register(Box::new(OUTBOX_LAG_COUNT.clone()))
+            .expect("Failed to register outbox lag counter");
+        prometheus::default_registry()
+            .register(Box::new(OUTBOX_DROP_COUNT.clone()))
+            .expect("Failed to register outbox drop counter");
+        prometheus::default_registry()
+            .register(Box::new(SLOW_CLIENT_DISCONNECT_COUNT.clone()))
+            .expect("Failed to register slow client disconnect counter");
+        prometheus::default_registry()
+            .register(Box::new(RATE_LIMITED_COUNT.clone()))
+            .expect("Failed to register rate limited counter");
+        prometheus::default_registry()
+            .register(Box::new(BLOCKED_UPLOAD_COUNT.clone()))
+            .expect("Failed to register blocked upload counter");
+        prometheus::default_registry()
+            .register(Box::new(CONFIG_RELOAD_COUNT.clone()))
+            .expect("Failed to register config reload counter");
+        prometheus::default_registry()
+            .register(Box::new(PURGED_MESSAGE_COUNT.clone()))
+            .expect("Failed to register purged message counter");
+        prometheus::default_registry()
+            .register(Box::new(PURGED_ATTACHMENT_COUNT.clone()))
+            .expect("Failed to register purged attachment counter");
+        prometheus::default_registry()
+            .register(Box::new(PURGED_BYTES_COUNT.clone()))
+            .expect("Failed to register purged bytes counter");
+    });
 }
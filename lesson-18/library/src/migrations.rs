@@ -0,0 +1,381 @@
+//! Versioned database schema
+//!
+//! Migrations live in `library/migrations` as `<version>_<name>.up.sql` / `.down.sql` pairs and are
+//! embedded into the binary. Applied versions are recorded in the `_sqlx_migrations` table.
+//!
+//! Pending migrations are applied at startup (see `db_client::setup_database_pool_with`). A database
+//! migrated by a newer binary is refused, as this binary does not know its schema.
+//! The server's `migrate` subcommand shows status and moves the schema up or down explicitly.
+//!
+//! Data that SQL alone cannot convert (like bincode encoded messages) is converted in Rust around the
+//! scripts, see `convert_legacy_messages`.
+use image::ImageFormat;
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::{Pool, Sqlite};
+use thiserror::Error;
+
+use crate::blob_store::BlobStore;
+use crate::{db_client, envelope, images};
+
+/// All migrations known to this binary
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Version storing messages in columns instead of bincode blobs
+pub const NORMALIZED_MESSAGES_VERSION: i64 = 2;
+/// Version storing attachment contents in the blob store instead of the database
+pub const BLOB_STORE_VERSION: i64 = 3;
+/// Version recording metadata and thumbnails of images
+pub const IMAGE_METADATA_VERSION: i64 = 4;
+/// Version recording frame count and duration of animated images
+pub const IMAGE_ANIMATION_VERSION: i64 = 5;
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Migration failed: {0}")]
+    Migrate(#[from] MigrateError),
+    #[error("Database schema version {database} is newer than this binary supports ({binary}), refusing to use it")]
+    DatabaseNewer { database: i64, binary: i64 },
+    #[error("Unknown schema version {0}")]
+    UnknownVersion(i64),
+}
+
+/// State of a single migration
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    /// When it was applied, `None` if pending
+    pub installed_on: Option<String>,
+}
+
+/// Latest schema version known to this binary
+pub fn latest_version() -> i64 {
+    MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0)
+}
+
+/// Schema version of the database, 0 for a database without any migrations applied
+pub async fn schema_version(pool: &Pool<Sqlite>) -> Result<i64, MigrationError> {
+    if !migrations_table_exists(pool).await? {
+        return Ok(0);
+    }
+    let version: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success = TRUE")
+            .fetch_one(pool)
+            .await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Fails if the database was migrated by a newer binary
+pub async fn check_version(pool: &Pool<Sqlite>) -> Result<i64, MigrationError> {
+    let database = schema_version(pool).await?;
+    let binary = latest_version();
+    if database > binary {
+        return Err(MigrationError::DatabaseNewer { database, binary });
+    }
+    Ok(database)
+}
+
+/// Applies all pending migrations, returns the new schema version
+///
+/// Attachment contents are moved into `blobs`, thumbnails of images stored before are added there.
+/// Images recorded before `IMAGE_ANIMATION_VERSION` get their frames counted.
+pub async fn migrate_up(pool: &Pool<Sqlite>, blobs: &BlobStore) -> Result<i64, MigrationError> {
+    let before = check_version(pool).await?;
+    MIGRATOR.run(pool).await?;
+    let after = schema_version(pool).await?;
+    if after >= NORMALIZED_MESSAGES_VERSION {
+        convert_legacy_messages(pool, blobs).await?;
+    }
+    if after >= BLOB_STORE_VERSION {
+        move_attachments_to_blobs(pool, blobs).await?;
+    }
+    // Only once, images that cannot be decoded would be tried again on every start
+    if before < IMAGE_METADATA_VERSION && after >= IMAGE_METADATA_VERSION {
+        add_image_metadata(pool, blobs).await?;
+    }
+    // Images added just above are recorded with their frames already
+    if (IMAGE_METADATA_VERSION..IMAGE_ANIMATION_VERSION).contains(&before)
+        && after >= IMAGE_ANIMATION_VERSION
+    {
+        add_animation_metadata(pool, blobs).await?;
+    }
+    if after != before {
+        log::info!(
+            "Database schema migrated from version {} to {}",
+            before,
+            after
+        );
+    }
+    Ok(after)
+}
+
+/// Reverts migrations down to `target` version (0 reverts everything), returns the new schema version
+///
+/// Attachment contents are read back from `blobs` when going below `BLOB_STORE_VERSION`.
+pub async fn migrate_down(
+    pool: &Pool<Sqlite>,
+    target: i64,
+    blobs: &BlobStore,
+) -> Result<i64, MigrationError> {
+    if target != 0 && !MIGRATOR.version_exists(target) {
+        return Err(MigrationError::UnknownVersion(target));
+    }
+    let before = check_version(pool).await?;
+    if before >= BLOB_STORE_VERSION && target < BLOB_STORE_VERSION {
+        restore_inline_attachments(pool, blobs).await?;
+    }
+    if before >= NORMALIZED_MESSAGES_VERSION && target < NORMALIZED_MESSAGES_VERSION {
+        restore_legacy_messages(pool).await?;
+    }
+    MIGRATOR.undo(pool, target).await?;
+    let after = schema_version(pool).await?;
+    log::info!(
+        "Database schema reverted from version {} to {}",
+        before,
+        after
+    );
+    Ok(after)
+}
+
+/// Converts messages stored as bincode blobs into `kind`/`body`/`attachments`, returns number of converted rows
+///
+/// Rows that cannot be decoded are left as they are (and logged), they are still readable by older binaries.
+pub async fn convert_legacy_messages(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<usize, MigrationError> {
+    let legacy: Vec<(String, Vec<u8>)> = sqlx::query_as(
+        "SELECT id, message FROM messages WHERE kind IS NULL AND message IS NOT NULL",
+    )
+    .fetch_all(pool)
+    .await?;
+    if legacy.is_empty() {
+        return Ok(0);
+    }
+
+    let mut converted = 0;
+    let mut tx = pool.begin().await?;
+    for (id, blob) in legacy {
+        match envelope::decode(&blob) {
+            Ok(message) => {
+                db_client::insert_message_columns(&mut tx, blobs, &id, &message).await?;
+                converted += 1;
+            }
+            Err(e) => log::warn!("Message {} left in legacy format: {}", id, e),
+        }
+    }
+    tx.commit().await?;
+    log::info!("Converted {} messages to the normalized format", converted);
+    Ok(converted)
+}
+
+/// id, kind, body, attachment name and attachment data of a message
+type NormalizedRow = (
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<Vec<u8>>,
+);
+
+/// Encodes normalized messages back into bincode blobs, so that the columns can be dropped
+async fn restore_legacy_messages(pool: &Pool<Sqlite>) -> Result<(), MigrationError> {
+    let rows: Vec<NormalizedRow> = sqlx::query_as(
+        "SELECT m.id, m.kind, m.body, a.name, a.data
+             FROM messages m LEFT JOIN attachments a ON a.id = m.attachment_id
+             WHERE m.kind IS NOT NULL",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    for (id, kind, body, name, data) in rows {
+        // Older binaries read only the format from before the envelope
+        let blob = envelope::message_from_parts(&kind, body, name, data)
+            .and_then(|message| envelope::encode_v0(&message));
+        match blob {
+            Ok(blob) => {
+                sqlx::query("UPDATE messages SET message = ? WHERE id = ?")
+                    .bind(blob)
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            Err(e) => log::warn!("Message {} cannot be restored to legacy format: {}", id, e),
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Writes attachment contents still stored in the database into the blob store, returns their number
+pub async fn move_attachments_to_blobs(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<usize, MigrationError> {
+    let inline: Vec<(String, Vec<u8>)> =
+        sqlx::query_as("SELECT id, data FROM attachments WHERE data IS NOT NULL")
+            .fetch_all(pool)
+            .await?;
+    if inline.is_empty() {
+        return Ok(0);
+    }
+
+    let mut tx = pool.begin().await?;
+    for (id, data) in &inline {
+        // Reference of the hash is counted by the migration script already
+        blobs.put(data).await.map_err(sqlx::Error::Io)?;
+        sqlx::query("UPDATE attachments SET data = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    log::info!(
+        "Moved {} attachments to blob store {:?}",
+        inline.len(),
+        blobs.root()
+    );
+    Ok(inline.len())
+}
+
+/// Records metadata and thumbnails of image attachments stored without them, returns their number
+pub async fn add_image_metadata(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<usize, MigrationError> {
+    let images: Vec<(String, String)> = sqlx::query_as(
+        "SELECT a.id, a.hash FROM attachments a
+             JOIN messages m ON m.attachment_id = a.id
+             WHERE m.kind = 'image' AND a.id NOT IN (SELECT attachment_id FROM images)",
+    )
+    .fetch_all(pool)
+    .await?;
+    if images.is_empty() {
+        return Ok(0);
+    }
+
+    let mut tx = pool.begin().await?;
+    for (id, hash) in &images {
+        let data = blobs.get(hash).await.map_err(sqlx::Error::Io)?;
+        db_client::insert_image(&mut tx, blobs, id, &data).await?;
+    }
+    tx.commit().await?;
+    log::info!("Added metadata of {} images", images.len());
+    Ok(images.len())
+}
+
+/// Counts frames of recorded images in animated formats, returns the number of animations found
+pub async fn add_animation_metadata(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<usize, MigrationError> {
+    let candidates: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT i.attachment_id, i.format, a.hash FROM images i
+             JOIN attachments a ON a.id = i.attachment_id
+             WHERE i.format IN ('gif', 'png', 'webp')",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    let mut animations = 0;
+    for (id, format, hash) in &candidates {
+        let data = blobs.get(hash).await.map_err(sqlx::Error::Io)?;
+        let frames = ImageFormat::from_extension(format)
+            .map_or(Ok(None), |format| images::decode_animation(&data, format));
+        let frames = match frames {
+            Ok(Some(frames)) => frames,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("Cannot count frames of image {}: {}", id, e);
+                continue;
+            }
+        };
+        sqlx::query("UPDATE images SET frames = ?, duration_ms = ? WHERE attachment_id = ?")
+            .bind(frames.len() as u32)
+            .bind(images::animation_duration_ms(&frames) as i64)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        animations += 1;
+    }
+    tx.commit().await?;
+    if animations > 0 {
+        log::info!("Added frames of {} animated images", animations);
+    }
+    Ok(animations)
+}
+
+/// Reads attachment contents back from the blob store into the database
+async fn restore_inline_attachments(
+    pool: &Pool<Sqlite>,
+    blobs: &BlobStore,
+) -> Result<(), MigrationError> {
+    let stored: Vec<(String, String)> =
+        sqlx::query_as("SELECT id, hash FROM attachments WHERE data IS NULL")
+            .fetch_all(pool)
+            .await?;
+
+    let mut tx = pool.begin().await?;
+    for (id, hash) in stored {
+        let data = blobs.get(&hash).await.map_err(sqlx::Error::Io)?;
+        sqlx::query("UPDATE attachments SET data = ? WHERE id = ?")
+            .bind(data)
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Version one step below the current one, 0 if there is none
+pub async fn previous_version(pool: &Pool<Sqlite>) -> Result<i64, MigrationError> {
+    let current = check_version(pool).await?;
+    Ok(MIGRATOR
+        .iter()
+        .map(|m| m.version)
+        .filter(|version| *version < current)
+        .max()
+        .unwrap_or(0))
+}
+
+/// Status of all migrations known to this binary, in order
+pub async fn status(pool: &Pool<Sqlite>) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let applied: Vec<(i64, String)> = match migrations_table_exists(pool).await? {
+        true => {
+            sqlx::query_as(
+                "SELECT version, installed_on FROM _sqlx_migrations WHERE success = TRUE",
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        false => Vec::new(),
+    };
+    let mut status: Vec<MigrationStatus> = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            installed_on: applied
+                .iter()
+                .find(|(version, _)| *version == m.version)
+                .map(|(_, installed_on)| installed_on.clone()),
+        })
+        .collect();
+    status.sort_by_key(|m| m.version);
+    Ok(status)
+}
+
+async fn migrations_table_exists(pool: &Pool<Sqlite>) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(count > 0)
+}
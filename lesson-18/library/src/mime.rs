@@ -0,0 +1,136 @@
+//! Content type of attachments, detected from the content itself
+//!
+//! Names and declared types come from the sender, so whatever decides how content is treated
+//! looks at the sniffed type instead. Types are recognized by the magic bytes they start with
+//! (images by the `image` crate), content which is valid UTF-8 without control characters is
+//! `text/plain`.
+//!
+//! The server refuses uploads of types its `UploadPolicy` blocks, see `config::UploadsSection`.
+use thiserror::Error;
+
+/// Type of content that is not recognized
+pub const OCTET_STREAM: &str = "application/octet-stream";
+/// Type of content that looks like text
+pub const TEXT_PLAIN: &str = "text/plain";
+
+/// Magic bytes at an offset and the type they identify, checked in order
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"PK\x05\x06", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"MZ", "application/vnd.microsoft.portable-executable"),
+    (0, b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+    (0, b"\xce\xfa\xed\xfe", "application/x-mach-binary"),
+    (0, b"\xfe\xed\xfa\xcf", "application/x-mach-binary"),
+    (0, b"\xfe\xed\xfa\xce", "application/x-mach-binary"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"#!", "text/x-shellscript"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (8, b"WAVE", "audio/wav"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+/// Types blocked unless configured otherwise - programs that could be run by the receiver
+pub const EXECUTABLE_TYPES: [&str; 4] = [
+    "application/x-executable",
+    "application/vnd.microsoft.portable-executable",
+    "application/x-mach-binary",
+    "text/x-shellscript",
+];
+
+/// MIME type of `data`, `OCTET_STREAM` if it is not recognized
+pub fn sniff(data: &[u8]) -> &'static str {
+    if let Ok(format) = image::guess_format(data) {
+        return format.to_mime_type();
+    }
+    let signature = SIGNATURES.iter().find(|(offset, magic, _)| {
+        data.get(*offset..)
+            .is_some_and(|data| data.starts_with(magic))
+    });
+    if let Some((_, _, mime)) = signature {
+        return mime;
+    }
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => TEXT_PLAIN,
+        _ => OCTET_STREAM,
+    }
+}
+
+/// Why an upload of a type was refused
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum BlockedType {
+    #[error("type {0} is denied (matches {1})")]
+    Denied(String, String),
+    #[error("type {0} is not among the allowed types")]
+    NotAllowed(String),
+}
+
+/// Which content types the server accepts
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadPolicy {
+    /// Only these are accepted, any type if empty
+    pub allowed_types: Vec<String>,
+    /// Never accepted, even if allowed
+    pub denied_types: Vec<String>,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        UploadPolicy {
+            allowed_types: Vec::new(),
+            denied_types: EXECUTABLE_TYPES.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+impl UploadPolicy {
+    /// Fails if content of type `mime` is not accepted
+    pub fn check(&self, mime: &str) -> Result<(), BlockedType> {
+        if let Some(pattern) = self.denied_types.iter().find(|p| matches_type(p, mime)) {
+            return Err(BlockedType::Denied(mime.to_string(), pattern.clone()));
+        }
+        if !self.allowed_types.is_empty()
+            && !self.allowed_types.iter().any(|p| matches_type(p, mime))
+        {
+            return Err(BlockedType::NotAllowed(mime.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// True if `mime` matches `pattern` - a type like `image/png`, a group like `image/*`, or `*/*`
+pub fn matches_type(pattern: &str, mime: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(group) => mime
+            .split_once('/')
+            .is_some_and(|(mime_group, _)| mime_group == group),
+        None => pattern == mime,
+    }
+}
+
+/// Fails unless `pattern` can be used in an `UploadPolicy`
+pub fn validate_type_pattern(pattern: &str) -> Result<(), String> {
+    match pattern.trim().split_once('/') {
+        Some((group, subtype))
+            if !group.is_empty() && !subtype.is_empty() && !subtype.contains('/') =>
+        {
+            Ok(())
+        }
+        _ => Err(format!(
+            "invalid type {:?}, expected e.g. image/png or image/*",
+            pattern
+        )),
+    }
+}
@@ -0,0 +1,112 @@
+//! How long stored messages are kept
+//!
+//! Nothing expires by default. The server deletes messages its `RetentionPolicy` no longer keeps
+//! in a background task (see `config::RetentionSection`), together with their attachments, search
+//! index entries and views. Files and images can be kept shorter than text, an expired attachment
+//! takes its message with it.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::Message;
+
+/// Which messages are kept, a message is deleted once any limit is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RetentionRule {
+    /// Older messages are deleted
+    pub max_age: Option<Duration>,
+    /// Only this many newest messages of each user are kept
+    pub max_per_user: Option<u64>,
+}
+
+impl RetentionRule {
+    /// True unless the rule keeps everything
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_per_user.is_some()
+    }
+
+    /// Messages sent before this time (in milliseconds since UNIX epoch) are expired
+    pub fn cutoff_ms(&self, now_ms: u64) -> Option<u64> {
+        self.max_age
+            .map(|max_age| now_ms.saturating_sub(max_age.as_millis() as u64))
+    }
+}
+
+/// Retention of all messages and, on top of it, of messages with a file or image
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RetentionPolicy {
+    pub messages: RetentionRule,
+    pub attachments: RetentionRule,
+}
+
+impl RetentionPolicy {
+    /// True unless everything is kept forever
+    pub fn is_enabled(&self) -> bool {
+        self.messages.is_enabled() || self.attachments.is_enabled()
+    }
+
+    /// Rules with whether they apply to messages with attachments only
+    pub fn rules(&self) -> [(&RetentionRule, bool); 2] {
+        [(&self.messages, false), (&self.attachments, true)]
+    }
+}
+
+/// What was deleted by enforcing the retention policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PurgeReport {
+    pub messages: u64,
+    pub attachments: u64,
+    /// Size of the deleted attachments
+    pub bytes: u64,
+    /// Blobs removed as no attachment refers to them anymore
+    pub blobs: u64,
+}
+
+impl Display for PurgeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} messages, {} attachments ({} bytes), {} blobs",
+            self.messages, self.attachments, self.bytes, self.blobs
+        )
+    }
+}
+
+/// IDs of `messages` the policy does not keep at `now_ms`, used by stores without SQL
+pub fn expired_messages(
+    policy: &RetentionPolicy,
+    messages: &[Message],
+    now_ms: u64,
+) -> HashSet<String> {
+    let mut expired = HashSet::new();
+    for (rule, attachments_only) in policy.rules() {
+        let mut applies: Vec<&Message> = messages
+            .iter()
+            .filter(|msg| !attachments_only || msg.attachment_id.is_some())
+            .collect();
+        if let Some(cutoff) = rule.cutoff_ms(now_ms) {
+            expired.extend(
+                applies
+                    .iter()
+                    .filter(|msg| msg.timestamp.parse::<u64>().unwrap_or_default() < cutoff)
+                    .map(|msg| msg.id.clone()),
+            );
+        }
+        if let Some(max_per_user) = rule.max_per_user {
+            // Newest first, messages sent in the same millisecond in the order they were stored
+            applies.reverse();
+            applies.sort_by_key(|msg| {
+                std::cmp::Reverse(msg.timestamp.parse::<u64>().unwrap_or_default())
+            });
+            let mut kept: HashMap<&str, u64> = HashMap::new();
+            for msg in applies {
+                let count = kept.entry(&msg.uid).or_default();
+                *count += 1;
+                if *count > max_per_user {
+                    expired.insert(msg.id.clone());
+                }
+            }
+        }
+    }
+    expired
+}
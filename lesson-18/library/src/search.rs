@@ -0,0 +1,308 @@
+//! Full-text search of stored messages
+//!
+//! Text messages and names and captions of files and images are indexed in the `message_search`
+//! FTS5 table (see migration 7), kept up to date by `db_client::save_message` and
+//! `db_client::delete_message`. `MemoryStore` matches the same terms against its messages.
+//!
+//! A query is a list of terms, a message matches if all of them match:
+//! - `word` - the word in any case
+//! - `wor*` - any word starting with `wor`
+//! - `"two words"` - the words next to each other, in `"two wor"*` the last one is a prefix
+//!
+//! and filters `from:<uid>`, `since:<time>` and `until:<time>`, where time is milliseconds since
+//! UNIX epoch (as in `Message::timestamp`) or an age like `30m`, `12h` or `7d`.
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Most hits returned unless the query asks for fewer
+pub const DEFAULT_SEARCH_LIMIT: u32 = 50;
+
+/// Marks start and end of matching words in snippets produced by SQLite
+pub(crate) const HIGHLIGHT_START: char = '\u{1}';
+pub(crate) const HIGHLIGHT_END: char = '\u{2}';
+
+/// Messages to look for, see the module docs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchQuery {
+    pub text: String,
+    /// Only messages of this user
+    #[serde(default)]
+    pub uid: Option<String>,
+    /// Only messages sent at or after, milliseconds since UNIX epoch
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Only messages sent at or before, milliseconds since UNIX epoch
+    #[serde(default)]
+    pub until: Option<u64>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    DEFAULT_SEARCH_LIMIT
+}
+
+impl SearchQuery {
+    /// Query for `text` without filters
+    pub fn new(text: &str) -> Self {
+        SearchQuery {
+            text: text.to_string(),
+            uid: None,
+            since: None,
+            until: None,
+            limit: DEFAULT_SEARCH_LIMIT,
+        }
+    }
+
+    /// Parses terms and filters as typed after `.search`, ages are relative to `now_ms`
+    pub fn parse(input: &str, now_ms: u64) -> Result<Self, String> {
+        let mut query = SearchQuery::new("");
+        let mut text = Vec::new();
+        for chunk in split_chunks(input) {
+            match chunk.split_once(':') {
+                Some(("from", uid)) if !uid.is_empty() => query.uid = Some(uid.to_string()),
+                Some(("since", time)) => query.since = Some(parse_time(time, now_ms)?),
+                Some(("until", time)) => query.until = Some(parse_time(time, now_ms)?),
+                _ => text.push(chunk),
+            }
+        }
+        query.text = text.join(" ");
+        Ok(query)
+    }
+
+    /// Terms of the text, empty if there is nothing to search for
+    pub fn terms(&self) -> Vec<Term> {
+        split_chunks(&self.text)
+            .into_iter()
+            .filter_map(|chunk| {
+                let prefix = chunk.ends_with('*');
+                let words = words(chunk.trim_end_matches('*').trim_matches('"'));
+                (!words.is_empty()).then_some(Term { words, prefix })
+            })
+            .collect()
+    }
+
+    /// True if a message of `uid` sent at `timestamp` passes the filters
+    pub fn accepts(&self, uid: &str, timestamp: &str) -> bool {
+        let time = timestamp.parse::<u64>().unwrap_or_default();
+        self.uid.as_ref().is_none_or(|filter| filter == uid)
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+    }
+}
+
+/// Words which have to be found next to each other, the last one as prefix if `prefix` is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    /// Lowercase, alphanumeric only
+    pub words: Vec<String>,
+    pub prefix: bool,
+}
+
+impl Term {
+    /// Term in FTS5 query syntax, as a quoted phrase
+    pub fn to_fts(&self) -> String {
+        let star = if self.prefix { "*" } else { "" };
+        format!("\"{}\"{}", self.words.join(" "), star)
+    }
+
+    /// Word ranges of `tokens` where the term starts at one of them
+    fn find(&self, tokens: &[String]) -> Vec<std::ops::Range<usize>> {
+        (0..tokens.len())
+            .filter(|start| {
+                self.words.iter().enumerate().all(|(i, word)| {
+                    tokens.get(start + i).is_some_and(|token| {
+                        if self.prefix && i + 1 == self.words.len() {
+                            token.starts_with(word.as_str())
+                        } else {
+                            token == word
+                        }
+                    })
+                })
+            })
+            .map(|start| start..start + self.words.len())
+            .collect()
+    }
+}
+
+/// All terms in FTS5 query syntax, every one of them has to match
+pub fn fts_query(terms: &[Term]) -> String {
+    terms.iter().map(Term::to_fts).collect::<Vec<_>>().join(" ")
+}
+
+/// Byte ranges in `text` of words matching `terms`, `None` unless all of them match
+pub fn match_text(terms: &[Term], text: &str) -> Option<Vec<(usize, usize)>> {
+    let ranges = word_ranges(text);
+    let tokens: Vec<String> = ranges
+        .iter()
+        .map(|&(start, end)| text[start..end].to_lowercase())
+        .collect();
+    let mut highlights = Vec::new();
+    for term in terms {
+        let found = term.find(&tokens);
+        if found.is_empty() {
+            return None;
+        }
+        highlights.extend(found.into_iter().flatten().map(|i| ranges[i]));
+    }
+    highlights.sort_unstable();
+    highlights.dedup();
+    Some(highlights)
+}
+
+/// Message matching a search
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub message_id: String,
+    pub uid: String,
+    pub timestamp: String,
+    #[serde(default)]
+    pub attachment_id: Option<String>,
+    /// Part of the matching text, name or caption
+    pub snippet: String,
+    /// Byte ranges of `snippet` matching the terms
+    pub highlights: Vec<(usize, usize)>,
+}
+
+impl SearchHit {
+    /// Snippet split into parts, with true for those matching the terms
+    pub fn segments(&self) -> Vec<(&str, bool)> {
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        for &(start, end) in &self.highlights {
+            // Ranges come from the other side, a broken one is not highlighted
+            let (Some(before), Some(matched)) =
+                (self.snippet.get(pos..start), self.snippet.get(start..end))
+            else {
+                continue;
+            };
+            segments.push((before, false));
+            segments.push((matched, true));
+            pos = end;
+        }
+        segments.push((&self.snippet[pos..], false));
+        segments.retain(|(text, _)| !text.is_empty());
+        segments
+    }
+
+    /// Snippet with the matching parts between `open` and `close`
+    pub fn highlighted(&self, open: &str, close: &str) -> String {
+        self.segments()
+            .into_iter()
+            .map(|(text, matched)| match matched {
+                true => format!("{}{}{}", open, text, close),
+                false => text.to_string(),
+            })
+            .collect()
+    }
+
+    /// Removes `HIGHLIGHT_START` and `HIGHLIGHT_END` from `marked`, returns the ranges between them
+    pub(crate) fn unmark(marked: &str) -> (String, Vec<(usize, usize)>) {
+        let mut snippet = String::with_capacity(marked.len());
+        let mut highlights = Vec::new();
+        let mut start = None;
+        for c in marked.chars() {
+            match c {
+                HIGHLIGHT_START => start = Some(snippet.len()),
+                HIGHLIGHT_END => {
+                    if let Some(start) = start.take() {
+                        highlights.push((start, snippet.len()));
+                    }
+                }
+                c => snippet.push(c),
+            }
+        }
+        (snippet, highlights)
+    }
+}
+
+impl Display for SearchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.message_id,
+            self.timestamp,
+            self.uid,
+            self.highlighted("**", "**")
+        )
+    }
+}
+
+/// Milliseconds since UNIX epoch, or `now_ms` minus an age like `30s`, `30m`, `12h` or `7d`
+pub fn parse_time(value: &str, now_ms: u64) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid time {:?}, expected milliseconds since UNIX epoch or an age like 12h",
+            value
+        )
+    };
+    if let Ok(ms) = value.parse::<u64>() {
+        return Ok(ms);
+    }
+    let unit_ms = match value.chars().last() {
+        Some('s') => 1000,
+        Some('m') => 60 * 1000,
+        Some('h') => 60 * 60 * 1000,
+        Some('d') => 24 * 60 * 60 * 1000,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+    Ok(now_ms.saturating_sub(count.saturating_mul(unit_ms)))
+}
+
+/// Splits on whitespace outside of double quotes, quotes are kept
+fn split_chunks(input: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                start.get_or_insert(i);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    chunks.push(&input[start..i]);
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if let Some(start) = start {
+        chunks.push(&input[start..]);
+    }
+    chunks
+}
+
+/// Byte ranges of words (runs of alphanumeric characters) in `text`
+fn word_ranges(text: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(word_start)) => {
+                ranges.push((word_start, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = start {
+        ranges.push((start, text.len()));
+    }
+    ranges
+}
+
+/// Lowercase words of `text`
+fn words(text: &str) -> Vec<String> {
+    word_ranges(text)
+        .into_iter()
+        .map(|(start, end)| text[start..end].to_lowercase())
+        .collect()
+}
@@ -0,0 +1,168 @@
+//! Storage of users and messages
+//!
+//! Server and webapp work with `Store` trait objects, so the backend can be chosen by config:
+//! - `SqliteStore` - the SQLite database (see `db_client`)
+//! - `MemoryStore` - everything kept in memory and lost on exit, for tests and ephemeral demos
+//!
+//! # Example
+//! ```no_run
+//! # async fn run() -> Result<(), library::store::StoreError> {
+//! use library::store::{MemoryStore, Store};
+//!
+//! let store: std::sync::Arc<dyn Store> = std::sync::Arc::new(MemoryStore::default());
+//! let uid = uuid::Uuid::new_v4();
+//! store.auth_user(uid).await?;
+//! store.save_message(&uid.to_string(), &library::MessageType::Text("Hi".to_string())).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::backup::{BackupError, BackupReport};
+use crate::db_client::DatabaseConfig;
+use crate::migrations::MigrationError;
+use crate::retention::{PurgeReport, RetentionPolicy};
+use crate::search::{SearchHit, SearchQuery};
+use crate::{Attachment, AttachmentChunk, Message, MessageType, StorageQuota, User};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("{0}")]
+    Migration(#[from] MigrationError),
+    #[error("Unknown user {0}")]
+    UnknownUser(String),
+    #[error("Upload of {0} bytes exceeds the storage quota, {1}")]
+    QuotaExceeded(u64, StorageQuota),
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Users of the chat
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Registers user on authentication, known users are left as they are
+    async fn auth_user(&self, uid: Uuid) -> StoreResult<()>;
+    /// All known users
+    async fn users(&self) -> StoreResult<Vec<User>>;
+    /// Single user, `None` if unknown
+    async fn user(&self, uid: &str) -> StoreResult<Option<User>>;
+    /// Overrides storage quota of a user (0 for unlimited), `None` restores the default of the server
+    async fn set_quota(&self, uid: &str, quota_bytes: Option<i64>) -> StoreResult<()>;
+    /// Deletes user together with all its messages
+    async fn delete_user(&self, uid: &str) -> StoreResult<()>;
+}
+
+/// Messages sent by users
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /// Saves message sent by a known user, returns ID of the stored message
+    async fn save_message(&self, uid: &str, message: &MessageType) -> StoreResult<String>;
+    /// Saves message like `save_message`, unless its attachment would exceed the storage quota of
+    /// the user - `default_quota_bytes` (0 for unlimited) unless an admin set another one.
+    /// Checked while storing the message, so concurrent uploads cannot exceed the quota together.
+    async fn save_message_within_quota(
+        &self,
+        uid: &str,
+        message: &MessageType,
+        default_quota_bytes: u64,
+    ) -> StoreResult<String>;
+    /// Saves message of a known user keeping its ID and timestamp, as exported by `archive`.
+    /// Returns false, saving nothing, if a message with this ID is stored already.
+    async fn import_message(&self, message: &Message) -> StoreResult<bool>;
+    /// All messages
+    async fn messages(&self) -> StoreResult<Vec<Message>>;
+    /// Messages of a single user
+    async fn messages_of_user(&self, uid: &str) -> StoreResult<Vec<Message>>;
+    /// Deletes a single message (and its attachment)
+    async fn delete_message(&self, id: &str) -> StoreResult<()>;
+    /// Files and images of all messages, without their content
+    async fn attachments(&self) -> StoreResult<Vec<Attachment>>;
+    /// Up to `len` bytes of attachment content starting at `offset`, `None` for unknown attachment
+    async fn read_attachment(
+        &self,
+        id: &str,
+        offset: u64,
+        len: usize,
+    ) -> StoreResult<Option<AttachmentChunk>>;
+    /// Text messages and attachments matching the query, see `search`
+    async fn search(&self, query: &SearchQuery) -> StoreResult<Vec<SearchHit>>;
+    /// Deletes messages the policy no longer keeps at `now_ms`, with their attachments
+    async fn purge_expired(
+        &self,
+        policy: &RetentionPolicy,
+        now_ms: u64,
+    ) -> StoreResult<PurgeReport>;
+}
+
+/// Complete storage backend used by server and webapp
+#[async_trait]
+pub trait Store: UserStore + MessageStore {
+    /// Releases resources (like database connections), the store must not be used afterwards
+    async fn close(&self) {}
+    /// Consistent copy of everything stored into a new directory, while the store stays in use
+    async fn backup(&self, dir: &Path) -> Result<BackupReport, BackupError>;
+    /// Removes content no message refers to anymore, e.g. left behind by failed saves, returns
+    /// how many blobs were removed. Must not run while messages are saved, the server owning the
+    /// store calls it at startup.
+    async fn collect_garbage(&self) -> StoreResult<usize> {
+        Ok(0)
+    }
+}
+
+/// Which `Store` implementation to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreBackend {
+    /// SQLite database at `DatabaseConfig::url`
+    #[default]
+    Sqlite,
+    /// In memory, nothing is persisted
+    Memory,
+}
+
+impl FromStr for StoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "sqlite" => Ok(StoreBackend::Sqlite),
+            "memory" => Ok(StoreBackend::Memory),
+            other => Err(format!(
+                "unknown store backend {}, expected sqlite or memory",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for StoreBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreBackend::Sqlite => write!(f, "sqlite"),
+            StoreBackend::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// Opens store of the configured backend
+pub async fn open_store(config: &DatabaseConfig) -> StoreResult<Arc<dyn Store>> {
+    log::info!("Opening {} store", config.backend);
+    Ok(match config.backend {
+        StoreBackend::Sqlite => Arc::new(SqliteStore::connect(config).await?),
+        StoreBackend::Memory => Arc::new(MemoryStore::default()),
+    })
+}
@@ -0,0 +1,381 @@
+//! `Store` keeping everything in memory
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::{MessageStore, Store, StoreBackend, StoreError, StoreResult, UserStore};
+use crate::backup::{BackupError, BackupReport};
+use crate::images::{self, ImageInfo};
+use crate::retention::{self, PurgeReport, RetentionPolicy};
+use crate::search::{self, SearchHit, SearchQuery};
+use crate::{get_timestamp, Attachment, AttachmentChunk, Message, MessageType, StorageQuota, User};
+
+/// Image metadata and the PNG content of each of its thumbnails
+type StoredImage = (ImageInfo, Vec<Vec<u8>>);
+
+/// In-memory store, for tests and demos - nothing survives a restart
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    users: Mutex<Vec<User>>,
+    messages: Mutex<Vec<Message>>,
+    /// Metadata and thumbnail contents of images, by attachment ID
+    images: Mutex<HashMap<String, StoredImage>>,
+}
+
+impl MemoryStore {
+    /// Drops images whose messages are gone
+    fn forget_deleted_images(&self) {
+        let messages = self.messages.lock().unwrap();
+        self.images.lock().unwrap().retain(|id, _| {
+            messages
+                .iter()
+                .any(|msg| msg.attachment_id.as_deref() == Some(id))
+        });
+    }
+
+    /// Stores message of a known user, its attachment (if any) gets the ID of the message
+    ///
+    /// With `default_quota_bytes` an attachment exceeding the quota of the user is refused.
+    fn insert_message(
+        &self,
+        mut message: Message,
+        default_quota_bytes: Option<u64>,
+    ) -> StoreResult<()> {
+        let Some(user) = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|user| user.uid == message.uid)
+            .cloned()
+        else {
+            return Err(StoreError::UnknownUser(message.uid));
+        };
+        let id = message.id.clone();
+        message.attachment_id = match &message.message {
+            MessageType::Image(..) | MessageType::File(..) => Some(id.clone()),
+            _ => None,
+        };
+        if let MessageType::Image(_, data) = &message.message {
+            match images::process_image(data) {
+                Ok(image) => {
+                    let pngs = image
+                        .thumbnails
+                        .iter()
+                        .map(|(_, png)| png.clone())
+                        .collect();
+                    self.images
+                        .lock()
+                        .unwrap()
+                        .insert(id.clone(), (image.info(&id), pngs));
+                }
+                Err(e) => log::warn!("Image {} has no thumbnails: {}", id, e),
+            }
+        }
+        // Checked and stored under one lock, so concurrent uploads are counted
+        let mut messages = self.messages.lock().unwrap();
+        if let (Some(default_bytes), Some((attachment, _))) =
+            (default_quota_bytes, attachment_of(&message))
+        {
+            let used_bytes = messages
+                .iter()
+                .filter(|msg| msg.uid == user.uid)
+                .filter_map(attachment_of)
+                .map(|(attachment, _)| attachment.size)
+                .sum();
+            let quota = StorageQuota::of(&User { used_bytes, ..user }, default_bytes);
+            let size = attachment.size as u64;
+            if !quota.allows(size) {
+                drop(messages);
+                self.images.lock().unwrap().remove(&id);
+                return Err(StoreError::QuotaExceeded(size, quota));
+            }
+        }
+        messages.push(message);
+        Ok(())
+    }
+
+    /// Stores message just sent, returns its new ID
+    fn save_new_message(
+        &self,
+        uid: &str,
+        message: &MessageType,
+        default_quota_bytes: Option<u64>,
+    ) -> StoreResult<String> {
+        let id = Uuid::new_v4().to_string();
+        let message = Message {
+            id: id.clone(),
+            uid: uid.to_string(),
+            timestamp: get_timestamp(),
+            message: message.clone(),
+            attachment_id: None,
+        };
+        self.insert_message(message, default_quota_bytes)?;
+        Ok(id)
+    }
+
+    /// User with the storage used by attachments of its messages
+    fn with_usage(&self, mut user: User) -> User {
+        user.used_bytes = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg.uid == user.uid)
+            .filter_map(attachment_of)
+            .map(|(attachment, _)| attachment.size)
+            .sum();
+        user
+    }
+
+    /// Thumbnail as an attachment, with its content
+    fn thumbnail(&self, id: &str) -> Option<(Attachment, Vec<u8>)> {
+        let (attachment_id, max_size) = images::parse_thumbnail_id(id)?;
+        let images = self.images.lock().unwrap();
+        let (info, pngs) = images.get(attachment_id)?;
+        let index = info
+            .thumbnails
+            .iter()
+            .position(|t| t.max_size == max_size)?;
+        let png = pngs[index].clone();
+        let attachment = Attachment {
+            id: id.to_string(),
+            name: images::thumbnail_name("image", max_size),
+            mime: "image/png".to_string(),
+            size: png.len() as i64,
+            hash: format!("{:x}", Sha256::digest(&png)),
+            image: None,
+        };
+        Some((attachment, png))
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn auth_user(&self, uid: Uuid) -> StoreResult<()> {
+        let uid = uid.to_string();
+        let mut users = self.users.lock().unwrap();
+        if !users.iter().any(|user| user.uid == uid) {
+            users.push(User::new(uid));
+        }
+        Ok(())
+    }
+
+    async fn users(&self) -> StoreResult<Vec<User>> {
+        let users = self.users.lock().unwrap().clone();
+        Ok(users
+            .into_iter()
+            .map(|user| self.with_usage(user))
+            .collect())
+    }
+
+    async fn user(&self, uid: &str) -> StoreResult<Option<User>> {
+        let user = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|user| user.uid == uid)
+            .cloned();
+        Ok(user.map(|user| self.with_usage(user)))
+    }
+
+    async fn set_quota(&self, uid: &str, quota_bytes: Option<i64>) -> StoreResult<()> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|user| user.uid == uid) {
+            Some(user) => {
+                user.quota_bytes = quota_bytes;
+                Ok(())
+            }
+            None => Err(StoreError::UnknownUser(uid.to_string())),
+        }
+    }
+
+    async fn delete_user(&self, uid: &str) -> StoreResult<()> {
+        self.messages.lock().unwrap().retain(|msg| msg.uid != uid);
+        self.forget_deleted_images();
+        self.users.lock().unwrap().retain(|user| user.uid != uid);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn save_message(&self, uid: &str, message: &MessageType) -> StoreResult<String> {
+        self.save_new_message(uid, message, None)
+    }
+
+    async fn save_message_within_quota(
+        &self,
+        uid: &str,
+        message: &MessageType,
+        default_quota_bytes: u64,
+    ) -> StoreResult<String> {
+        self.save_new_message(uid, message, Some(default_quota_bytes))
+    }
+
+    async fn import_message(&self, message: &Message) -> StoreResult<bool> {
+        if self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|msg| msg.id == message.id)
+        {
+            return Ok(false);
+        }
+        self.insert_message(message.clone(), None)?;
+        Ok(true)
+    }
+
+    async fn messages(&self) -> StoreResult<Vec<Message>> {
+        Ok(self.messages.lock().unwrap().clone())
+    }
+
+    async fn messages_of_user(&self, uid: &str) -> StoreResult<Vec<Message>> {
+        Ok(self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg.uid == uid)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_message(&self, id: &str) -> StoreResult<()> {
+        self.messages.lock().unwrap().retain(|msg| msg.id != id);
+        self.forget_deleted_images();
+        Ok(())
+    }
+
+    /// Attachments are not stored separately, each gets the ID of its message
+    async fn attachments(&self) -> StoreResult<Vec<Attachment>> {
+        let messages = self.messages.lock().unwrap();
+        let images = self.images.lock().unwrap();
+        Ok(messages
+            .iter()
+            .filter_map(|msg| attachment_of(msg).map(|(attachment, _)| attachment))
+            .map(|attachment| Attachment {
+                image: images.get(&attachment.id).map(|(info, _)| info.clone()),
+                ..attachment
+            })
+            .collect())
+    }
+
+    async fn read_attachment(
+        &self,
+        id: &str,
+        offset: u64,
+        len: usize,
+    ) -> StoreResult<Option<AttachmentChunk>> {
+        let found = {
+            let messages = self.messages.lock().unwrap();
+            messages
+                .iter()
+                .filter(|msg| msg.attachment_id.as_deref() == Some(id))
+                .find_map(attachment_of)
+                .map(|(attachment, data)| (attachment, data.to_vec()))
+        };
+        Ok(found
+            .or_else(|| self.thumbnail(id))
+            .map(|(attachment, data)| {
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(len).min(data.len());
+                AttachmentChunk {
+                    attachment,
+                    offset,
+                    data: data[start..end].to_vec(),
+                }
+            }))
+    }
+
+    /// Newest messages first, the whole text (or name and caption) is the snippet
+    async fn search(&self, query: &SearchQuery) -> StoreResult<Vec<SearchHit>> {
+        let terms = query.terms();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .iter()
+            .rev()
+            .filter(|msg| query.accepts(&msg.uid, &msg.timestamp))
+            .filter_map(|msg| {
+                let text = searchable_text(&msg.message)?;
+                let highlights = search::match_text(&terms, &text)?;
+                Some(SearchHit {
+                    message_id: msg.id.clone(),
+                    uid: msg.uid.clone(),
+                    timestamp: msg.timestamp.clone(),
+                    attachment_id: msg.attachment_id.clone(),
+                    snippet: text,
+                    highlights,
+                })
+            })
+            .take(query.limit as usize)
+            .collect())
+    }
+
+    /// There is no blob store, content goes away with its message
+    async fn purge_expired(
+        &self,
+        policy: &RetentionPolicy,
+        now_ms: u64,
+    ) -> StoreResult<PurgeReport> {
+        let mut report = PurgeReport::default();
+        {
+            let mut messages = self.messages.lock().unwrap();
+            let expired = retention::expired_messages(policy, &messages, now_ms);
+            for msg in messages.iter().filter(|msg| expired.contains(&msg.id)) {
+                report.messages += 1;
+                if let Some((attachment, _)) = attachment_of(msg) {
+                    report.attachments += 1;
+                    report.bytes += attachment.size as u64;
+                }
+            }
+            messages.retain(|msg| !expired.contains(&msg.id));
+        }
+        self.forget_deleted_images();
+        Ok(report)
+    }
+}
+
+#[async_trait]
+#[async_trait]
+impl Store for MemoryStore {
+    /// Nothing to copy consistently, see `archive` to save the content instead
+    async fn backup(&self, _dir: &Path) -> Result<BackupReport, BackupError> {
+        Err(BackupError::Unsupported(StoreBackend::Memory))
+    }
+}
+
+/// Text of a message as indexed for search, name and caption of files and images
+fn searchable_text(message: &MessageType) -> Option<String> {
+    match message {
+        MessageType::Text(text) => Some(text.clone()),
+        MessageType::Image(info, _) | MessageType::File(info, _) => Some(info.to_string()),
+        _ => None,
+    }
+}
+
+/// Attachment of a file or image message, with its content
+fn attachment_of(msg: &Message) -> Option<(Attachment, &[u8])> {
+    let (info, data) = match &msg.message {
+        MessageType::Image(info, data) | MessageType::File(info, data) => (info, data),
+        _ => return None,
+    };
+    let attachment = Attachment {
+        id: msg.attachment_id.clone()?,
+        name: info.name.clone(),
+        mime: info.mime.clone(),
+        size: data.len() as i64,
+        hash: info.sha256.clone(),
+        image: None,
+    };
+    Some((attachment, data))
+}
@@ -0,0 +1,180 @@
+//! `Store` backed by the SQLite database, using the queries from `db_client`, and the blob store
+use std::path::Path;
+
+use async_trait::async_trait;
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+
+use super::{MessageStore, Store, StoreError, StoreResult, UserStore};
+use crate::backup::{self, BackupError, BackupReport};
+use crate::blob_store::BlobStore;
+use crate::db_client::{self, DatabaseConfig};
+use crate::retention::{PurgeReport, RetentionPolicy};
+use crate::search::{SearchHit, SearchQuery};
+use crate::{Attachment, AttachmentChunk, Message, MessageType, User};
+
+/// SQLite store, cheap to clone (shares the connection pool)
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+    blobs: BlobStore,
+}
+
+impl SqliteStore {
+    /// Store using an existing pool, attachment contents are kept in `blobs`
+    pub fn new(pool: Pool<Sqlite>, blobs: BlobStore) -> Self {
+        SqliteStore { pool, blobs }
+    }
+
+    /// Opens the pool (creating tables if needed)
+    pub async fn connect(config: &DatabaseConfig) -> StoreResult<Self> {
+        Ok(SqliteStore::new(
+            db_client::setup_database_pool_with(config).await?,
+            BlobStore::new(&config.blobs_dir),
+        ))
+    }
+
+    /// Underlying connection pool
+    pub fn pool(&self) -> &Pool<Sqlite> {
+        &self.pool
+    }
+
+    /// Store of attachment contents
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    /// Stores message just sent, returns its new ID
+    async fn save_new_message(
+        &self,
+        uid: &str,
+        message: &MessageType,
+        default_quota_bytes: Option<u64>,
+    ) -> StoreResult<String> {
+        db_client::save_message(
+            &self.pool,
+            &self.blobs,
+            uid.to_string(),
+            message,
+            default_quota_bytes,
+        )
+        .await
+        .map_err(|e| match e {
+            // Foreign key to users
+            StoreError::Database(sqlx::Error::Database(db)) if db.is_foreign_key_violation() => {
+                StoreError::UnknownUser(uid.to_string())
+            }
+            e => e,
+        })
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn auth_user(&self, uid: Uuid) -> StoreResult<()> {
+        db_client::auth_client(&self.pool, uid).await?;
+        Ok(())
+    }
+
+    async fn users(&self) -> StoreResult<Vec<User>> {
+        Ok(db_client::get_users(&self.pool).await?)
+    }
+
+    async fn user(&self, uid: &str) -> StoreResult<Option<User>> {
+        Ok(db_client::get_user(&self.pool, uid).await?)
+    }
+
+    async fn set_quota(&self, uid: &str, quota_bytes: Option<i64>) -> StoreResult<()> {
+        match db_client::set_user_quota(&self.pool, uid, quota_bytes).await? {
+            true => Ok(()),
+            false => Err(StoreError::UnknownUser(uid.to_string())),
+        }
+    }
+
+    async fn delete_user(&self, uid: &str) -> StoreResult<()> {
+        Ok(db_client::delete_user(uid.to_string(), &self.pool, &self.blobs).await?)
+    }
+}
+
+#[async_trait]
+impl MessageStore for SqliteStore {
+    async fn save_message(&self, uid: &str, message: &MessageType) -> StoreResult<String> {
+        self.save_new_message(uid, message, None).await
+    }
+
+    async fn save_message_within_quota(
+        &self,
+        uid: &str,
+        message: &MessageType,
+        default_quota_bytes: u64,
+    ) -> StoreResult<String> {
+        self.save_new_message(uid, message, Some(default_quota_bytes))
+            .await
+    }
+
+    async fn import_message(&self, message: &Message) -> StoreResult<bool> {
+        db_client::import_message(&self.pool, &self.blobs, message)
+            .await
+            .map_err(|e| match e {
+                StoreError::Database(sqlx::Error::Database(db))
+                    if db.is_foreign_key_violation() =>
+                {
+                    StoreError::UnknownUser(message.uid.clone())
+                }
+                e => e,
+            })
+    }
+
+    async fn messages(&self) -> StoreResult<Vec<Message>> {
+        Ok(db_client::get_messages_all(&self.pool, &self.blobs).await?)
+    }
+
+    async fn messages_of_user(&self, uid: &str) -> StoreResult<Vec<Message>> {
+        Ok(db_client::get_messages_user(&self.pool, &self.blobs, uid.to_string()).await?)
+    }
+
+    async fn delete_message(&self, id: &str) -> StoreResult<()> {
+        Ok(db_client::delete_message(id.to_string(), &self.pool, &self.blobs).await?)
+    }
+
+    async fn attachments(&self) -> StoreResult<Vec<Attachment>> {
+        Ok(db_client::get_attachments(&self.pool).await?)
+    }
+
+    async fn read_attachment(
+        &self,
+        id: &str,
+        offset: u64,
+        len: usize,
+    ) -> StoreResult<Option<AttachmentChunk>> {
+        Ok(db_client::read_attachment(&self.pool, &self.blobs, id, offset, len).await?)
+    }
+
+    async fn search(&self, query: &SearchQuery) -> StoreResult<Vec<SearchHit>> {
+        Ok(db_client::search_messages(&self.pool, query).await?)
+    }
+
+    async fn purge_expired(
+        &self,
+        policy: &RetentionPolicy,
+        now_ms: u64,
+    ) -> StoreResult<PurgeReport> {
+        let expired = db_client::expired_messages(&self.pool, policy, now_ms).await?;
+        Ok(db_client::delete_messages(&expired, &self.pool, &self.blobs).await?)
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn backup(&self, dir: &Path) -> Result<BackupReport, BackupError> {
+        backup::backup(&self.pool, &self.blobs, dir).await
+    }
+
+    async fn collect_garbage(&self) -> StoreResult<usize> {
+        Ok(db_client::collect_garbage(&self.pool, &self.blobs).await?)
+    }
+}
@@ -0,0 +1,52 @@
+#[cfg(test)]
+#[tokio::test]
+async fn test_address() {
+    use crate::address::{join_host_port, resolve, split_host_port, with_host_port};
+
+    assert_eq!(join_host_port("localhost", 80), "localhost:80");
+    assert_eq!(join_host_port("::1", 80), "[::1]:80");
+    assert_eq!(join_host_port("[::1]", 80), "[::1]:80");
+
+    assert_eq!(
+        split_host_port("chat.example.com:11111").unwrap(),
+        ("chat.example.com", 11111)
+    );
+    assert_eq!(
+        split_host_port("[fe80::1]:8000").unwrap(),
+        ("fe80::1", 8000)
+    );
+    for invalid in [
+        "localhost",
+        "::1:80",
+        ":80",
+        "host:99999",
+        "host:port",
+        "[::1:80",
+    ] {
+        assert!(
+            split_host_port(invalid).is_err(),
+            "{} should be invalid",
+            invalid
+        );
+    }
+
+    assert_eq!(
+        with_host_port("127.0.0.1:11111", Some("::1"), None).unwrap(),
+        "[::1]:11111"
+    );
+    assert_eq!(
+        with_host_port("[::1]:11111", None, Some(80)).unwrap(),
+        "[::1]:80"
+    );
+    assert!(with_host_port("127.0.0.1:11111", Some("bad host"), None).is_err());
+
+    assert_eq!(
+        resolve("127.0.0.1:11111").await.unwrap(),
+        "127.0.0.1:11111".parse().unwrap()
+    );
+    assert_eq!(
+        resolve("[::1]:11111").await.unwrap(),
+        "[::1]:11111".parse().unwrap()
+    );
+    assert!(resolve("localhost:11111").await.unwrap().ip().is_loopback());
+}
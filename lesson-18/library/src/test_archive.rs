@@ -0,0 +1,138 @@
+#[cfg(test)]
+#[tokio::test]
+async fn test_export_import() {
+    use crate::archive::{self, ArchiveError, ExportFilter};
+    use crate::blob_store::BlobStore;
+    use crate::db_client::DatabaseConfig;
+    use crate::store::{open_store, StoreBackend};
+    use crate::{AttachmentInfo, MessageType};
+    use uuid::Uuid;
+
+    let tmp = std::env::temp_dir().join(format!("chat-archive-{}", Uuid::new_v4()));
+    let open = |backend: StoreBackend, name: &str| {
+        let config = DatabaseConfig {
+            backend,
+            url: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            blobs_dir: tmp.join(name),
+            ..Default::default()
+        };
+        async move { open_store(&config).await.unwrap() }
+    };
+    // Exported from one backend, imported into the other
+    for (from, to) in [
+        (StoreBackend::Sqlite, StoreBackend::Memory),
+        (StoreBackend::Memory, StoreBackend::Sqlite),
+    ] {
+        let source = open(from, "source-blobs").await;
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        source.auth_user(alice).await.unwrap();
+        source.auth_user(bob).await.unwrap();
+        source
+            .set_quota(&alice.to_string(), Some(1000))
+            .await
+            .unwrap();
+        let text = MessageType::Text("Hello".to_string());
+        source
+            .save_message(&alice.to_string(), &text)
+            .await
+            .unwrap();
+        let data = b"quarterly numbers".to_vec();
+        let info = AttachmentInfo::new("report.txt", &data, Some("Budget".to_string()));
+        let file = MessageType::File(info, data);
+        source.save_message(&bob.to_string(), &file).await.unwrap();
+        source.save_message(&bob.to_string(), &file).await.unwrap();
+
+        let dir = tmp.join(format!("{}-export", from));
+        let report = archive::export(source.as_ref(), &dir, &ExportFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            (
+                report.users,
+                report.messages,
+                report.attachments,
+                report.bytes
+            ),
+            (2, 3, 2, 34),
+            "{}",
+            from
+        );
+        // Identical content is stored once
+        let attachments = BlobStore::new(dir.join(archive::ATTACHMENTS_DIR));
+        assert_eq!(attachments.hashes().await.unwrap().len(), 1);
+        assert!(matches!(
+            archive::export(source.as_ref(), &dir, &ExportFilter::default()).await,
+            Err(ArchiveError::Exists(_))
+        ));
+
+        let target = open(to, "target-blobs").await;
+        let report = archive::import(target.as_ref(), &dir).await.unwrap();
+        assert_eq!((report.messages, report.skipped), (3, 0), "{}", to);
+        let mut expected = source.messages().await.unwrap();
+        let mut imported = target.messages().await.unwrap();
+        for messages in [&mut expected, &mut imported] {
+            messages.sort_by(|a, b| a.id.cmp(&b.id));
+        }
+        assert_eq!(expected.len(), imported.len());
+        for (expected, imported) in expected.iter().zip(&imported) {
+            assert_eq!(
+                (&expected.id, &expected.uid, &expected.timestamp),
+                (&imported.id, &imported.uid, &imported.timestamp)
+            );
+            assert_eq!(expected.message, imported.message, "{}", to);
+        }
+        let user = target.user(&alice.to_string()).await.unwrap().unwrap();
+        assert_eq!(user.quota_bytes, Some(1000));
+        let user = target.user(&bob.to_string()).await.unwrap().unwrap();
+        assert_eq!(user.used_bytes, 34, "{}", to);
+
+        // Importing again changes nothing
+        let report = archive::import(target.as_ref(), &dir).await.unwrap();
+        assert_eq!((report.messages, report.skipped), (0, 3), "{}", to);
+        assert_eq!(target.messages().await.unwrap().len(), 3);
+
+        // Filtered export packed into a tar file
+        let tar = tmp.join(format!("{}-bob.tar", from));
+        let filter = ExportFilter {
+            uids: vec![bob.to_string()],
+            since: Some(1),
+            until: None,
+        };
+        let report = archive::export(source.as_ref(), &tar, &filter)
+            .await
+            .unwrap();
+        assert_eq!((report.users, report.messages), (1, 2), "{}", from);
+        let filter = ExportFilter {
+            until: Some(1),
+            ..Default::default()
+        };
+        let empty = tmp.join(format!("{}-empty", from));
+        let report = archive::export(source.as_ref(), &empty, &filter)
+            .await
+            .unwrap();
+        assert_eq!((report.users, report.messages), (2, 0));
+
+        let other = open(to, "other-blobs").await;
+        let report = archive::import(other.as_ref(), &tar).await.unwrap();
+        assert_eq!((report.users, report.messages), (1, 2), "{}", to);
+        assert_eq!(other.users().await.unwrap().len(), 1);
+        assert_eq!(other.attachments().await.unwrap().len(), 2);
+
+        // Content not matching its descriptor is refused
+        let blob = attachments
+            .path(&BlobStore::hash(b"quarterly numbers"))
+            .unwrap();
+        std::fs::write(blob, b"tampered").unwrap();
+        assert!(matches!(
+            archive::import(open(to, "tampered-blobs").await.as_ref(), &dir).await,
+            Err(ArchiveError::Invalid { line: 2, .. })
+        ));
+
+        for store in [source, target, other] {
+            store.close().await;
+        }
+    }
+    let _ = std::fs::remove_dir_all(tmp);
+}
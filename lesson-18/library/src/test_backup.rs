@@ -0,0 +1,85 @@
+#[cfg(test)]
+#[tokio::test]
+async fn test_backup_restore() {
+    use crate::backup::{self, BackupError};
+    use crate::blob_store::BlobStore;
+    use crate::db_client::DatabaseConfig;
+    use crate::store::{open_store, MemoryStore, Store};
+    use crate::{AttachmentInfo, MessageType};
+    use uuid::Uuid;
+
+    let tmp = std::env::temp_dir().join(format!("chat-backup-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    let config = DatabaseConfig {
+        url: format!("sqlite:{}", tmp.join("live.db").display()),
+        max_connections: 1,
+        blobs_dir: tmp.join("blobs"),
+        ..Default::default()
+    };
+    let store = open_store(&config).await.unwrap();
+    let uid = Uuid::new_v4();
+    store.auth_user(uid).await.unwrap();
+    let uid = uid.to_string();
+    let text = MessageType::Text("Hello".to_string());
+    store.save_message(&uid, &text).await.unwrap();
+    let data = b"quarterly numbers".to_vec();
+    let info = AttachmentInfo::new("report.txt", &data, None);
+    let file_id = store
+        .save_message(&uid, &MessageType::File(info, data))
+        .await
+        .unwrap();
+    let backed_up = store.messages().await.unwrap();
+
+    let dir = tmp.join("backup");
+    let report = store.backup(&dir).await.unwrap();
+    assert_eq!((report.blobs, report.blob_bytes), (1, 17));
+    assert_eq!(report.missing_blobs, 0);
+    assert!(matches!(
+        store.backup(&dir).await,
+        Err(BackupError::Exists(_))
+    ));
+    assert!(matches!(
+        MemoryStore::default().backup(&tmp.join("memory")).await,
+        Err(BackupError::Unsupported(_))
+    ));
+    assert_eq!(backup::verify(&dir).await.unwrap().blobs, 1);
+
+    // Changes after the backup are undone by restoring it
+    store.delete_message(&file_id).await.unwrap();
+    let other = MessageType::Text("After backup".to_string());
+    store.save_message(&uid, &other).await.unwrap();
+    store.close().await;
+    backup::restore(&dir, &config).await.unwrap();
+    let store = open_store(&config).await.unwrap();
+    let mut messages = store.messages().await.unwrap();
+    messages.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut expected = backed_up.clone();
+    expected.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(messages, expected);
+    store.close().await;
+    // Replaced database and blob store are kept
+    let kept: Vec<String> = std::fs::read_dir(&tmp)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.contains(".before-restore-"))
+        .collect();
+    assert!(kept.iter().any(|name| name.starts_with("live.db.")));
+    assert!(kept.iter().any(|name| name.starts_with("blobs.")));
+
+    // Damaged backup is refused, nothing is swapped
+    let blob = BlobStore::new(dir.join(backup::BLOBS_DIR))
+        .path(&BlobStore::hash(b"quarterly numbers"))
+        .unwrap();
+    std::fs::write(blob, b"tampered").unwrap();
+    assert!(matches!(
+        backup::verify(&dir).await,
+        Err(BackupError::Damaged(_))
+    ));
+    assert!(backup::restore(&dir, &config).await.is_err());
+    std::fs::write(dir.join(backup::DATABASE_FILE), b"not a database").unwrap();
+    assert!(backup::verify(&dir).await.is_err());
+    let store = open_store(&config).await.unwrap();
+    assert_eq!(store.messages().await.unwrap().len(), 2);
+    store.close().await;
+    let _ = std::fs::remove_dir_all(tmp);
+}
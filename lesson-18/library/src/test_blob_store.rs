@@ -0,0 +1,33 @@
+#[cfg(test)]
+#[tokio::test]
+async fn test_blob_store() {
+    use crate::blob_store::BlobStore;
+
+    let root = std::env::temp_dir().join(format!("chat-blobs-{}", uuid::Uuid::new_v4()));
+    let blobs = BlobStore::new(&root);
+    assert!(blobs.hashes().await.unwrap().is_empty());
+
+    let hash = blobs.put(b"hello").await.unwrap();
+    assert_eq!(hash, BlobStore::hash(b"hello"));
+    assert!(blobs
+        .path(&hash)
+        .unwrap()
+        .starts_with(root.join(&hash[..2])));
+    // Same content is stored once
+    assert_eq!(blobs.put(b"hello").await.unwrap(), hash);
+    blobs.put(b"world").await.unwrap();
+    assert_eq!(blobs.hashes().await.unwrap().len(), 2);
+    assert_eq!(blobs.get(&hash).await.unwrap(), b"hello");
+
+    // Only hashes are valid keys
+    for key in ["../../etc/passwd", "", "ab", &"g".repeat(64)] {
+        assert!(blobs.path(key).is_err(), "{}", key);
+        assert!(blobs.get(key).await.is_err(), "{}", key);
+    }
+
+    blobs.remove(&hash).await.unwrap();
+    blobs.remove(&hash).await.unwrap();
+    assert!(blobs.get(&hash).await.is_err());
+    assert_eq!(blobs.hashes().await.unwrap().len(), 1);
+    std::fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,58 @@
+#[cfg(test)]
+#[test]
+fn test_config_layers() {
+    use crate::config::{parse_override, AppConfig, ConfigError};
+    use crate::images::OutputFormat;
+    use std::io::Write;
+
+    // Defaults are valid on their own
+    let config = AppConfig::load_layers(None, &[], &[]).unwrap();
+    assert_eq!(config, AppConfig::default());
+
+    let path = std::env::temp_dir().join(format!("chat-{}.toml", uuid::Uuid::new_v4()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    writeln!(
+        file,
+        "[server]\naddress = \"0.0.0.0:2000\"\noutbox_capacity = 8\n[logging]\nlevel = \"debug\""
+    )
+    .unwrap();
+
+    // File overrides defaults, env overrides file, command line overrides env
+    let env = vec![("server.address".to_string(), "0.0.0.0:3000".to_string())];
+    let cli = vec![
+        parse_override("server.address=0.0.0.0:4000").unwrap(),
+        parse_override("limits.max_frame_size=1024").unwrap(),
+        parse_override("images.format=JPEG").unwrap(),
+        parse_override("retention.max_attachments_per_user=10").unwrap(),
+    ];
+    let config = AppConfig::load_layers(Some(&path), &env, &cli).unwrap();
+    assert_eq!(config.server.address, "0.0.0.0:4000");
+    assert_eq!(config.server.outbox_capacity, 8);
+    assert_eq!(config.limits.max_frame_size, 1024);
+    assert_eq!(config.log_level(), log::LevelFilter::Debug);
+    assert_eq!(config.image_policy().format, OutputFormat::Jpeg);
+    let retention = config.retention_policy();
+    assert_eq!(retention.attachments.max_per_user, Some(10));
+    assert!(!retention.messages.is_enabled());
+    std::fs::remove_file(&path).unwrap();
+
+    // All validation errors are reported together
+    let invalid = vec![
+        ("server.address".to_string(), "nowhere".to_string()),
+        ("logging.level".to_string(), "loud".to_string()),
+        ("images.format".to_string(), "bmp".to_string()),
+        (
+            "uploads.allowed_types".to_string(),
+            r#"["pdf"]"#.to_string(),
+        ),
+        ("retention.interval_secs".to_string(), "0".to_string()),
+    ];
+    match AppConfig::load_layers(None, &[], &invalid) {
+        Err(ConfigError::Validation(errors)) => assert_eq!(errors.len(), 5),
+        other => panic!("Expected validation error, got {:?}", other),
+    }
+
+    // Unknown keys are rejected
+    let unknown = vec![("server.adress".to_string(), "0.0.0.0:1".to_string())];
+    assert!(AppConfig::load_layers(None, &[], &unknown).is_err());
+}
@@ -13,12 +13,19 @@ async fn test_db_client() {
     assert!(db_pool.is_ok());
 
     let db_pool = db_pool.unwrap();
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(journal_mode, "wal");
+
     let uid = Uuid::new_v4();
     let msg = crate::MessageType::Text("Hello world".to_string());
 
     let result_auth = auth_client(&db_pool, uid).await;
     assert!(result_auth.is_ok());
 
-    let result_savemsg = save_message(&db_pool, uid.to_string(), &msg).await;
+    let blobs = crate::blob_store::BlobStore::new(std::env::temp_dir().join("chat-blobs-test"));
+    let result_savemsg = save_message(&db_pool, &blobs, uid.to_string(), &msg, None).await;
     assert!(result_savemsg.is_ok());
 }
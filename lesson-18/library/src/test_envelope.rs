@@ -0,0 +1,58 @@
+#[cfg(test)]
+#[test]
+fn test_envelope() {
+    use crate::envelope::*;
+    use crate::{AttachmentInfo, MessageType};
+
+    let messages = [
+        MessageType::Text("Hello".to_string()),
+        // Named as images stored without a name
+        MessageType::image("image", vec![1, 2, 3]),
+        MessageType::file("a.txt", b"abc".to_vec()),
+        MessageType::Shutdown("bye".to_string()),
+    ];
+    for message in messages {
+        let encoded = encode(&message).unwrap();
+        assert!(encoded.starts_with(MAGIC));
+        assert_eq!(encoded[MAGIC.len()], CURRENT_VERSION);
+        assert_eq!(decode(&encoded).unwrap(), message);
+
+        // Data stored before the envelope existed
+        let legacy = encode_v0(&message).unwrap();
+        assert_eq!(decode(&legacy).unwrap(), message);
+    }
+    // Layout of messages without attachments is unchanged since then
+    let text = MessageType::Text("Hello".to_string());
+    assert_eq!(
+        encode_v0(&text).unwrap(),
+        bincode::serialize(&text).unwrap()
+    );
+
+    // Caption is kept, the rest of the descriptor is computed from the content
+    let info = AttachmentInfo::new("cat.png", b"meow", Some("My cat".to_string()));
+    let captioned = MessageType::Image(info, b"meow".to_vec());
+    assert_eq!(decode(&encode(&captioned).unwrap()).unwrap(), captioned);
+    assert_eq!(captioned.to_string(), "Image cat.png: My cat");
+
+    let mut newer = MAGIC.to_vec();
+    newer.push(CURRENT_VERSION + 1);
+    assert_eq!(
+        decode(&newer),
+        Err(EnvelopeError::UnsupportedVersion(CURRENT_VERSION + 1))
+    );
+    assert_eq!(decode(&[]), Err(EnvelopeError::Truncated));
+    assert_eq!(decode(MAGIC), Err(EnvelopeError::Truncated));
+    // Unknown variant and a length far beyond the data
+    assert!(matches!(
+        decode(&[9, 0, 0, 0, 1]),
+        Err(EnvelopeError::Corrupted { version: 0, .. })
+    ));
+    assert!(matches!(
+        decode(&[1, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 0]),
+        Err(EnvelopeError::Corrupted { version: 0, .. })
+    ));
+    assert_eq!(
+        message_from_parts("video", None, None, None),
+        Err(EnvelopeError::UnknownKind("video".to_string()))
+    );
+}
@@ -0,0 +1,95 @@
+#[cfg(test)]
+#[test]
+fn test_sanitize_file_name() {
+    use crate::file_name::*;
+
+    for name in [
+        "notes.txt",
+        "image (1).png",
+        "résumé.pdf",
+        "a.b.c",
+        "CONSOLE.txt",
+    ] {
+        assert_eq!(sanitize_file_name(name), Ok(name));
+    }
+
+    let rejected = [
+        ("", FileNameError::Empty),
+        ("   ", FileNameError::Empty),
+        (".", FileNameError::Directory),
+        ("..", FileNameError::Directory),
+        ("../../.bashrc", FileNameError::PathSeparator),
+        ("/etc/passwd", FileNameError::PathSeparator),
+        ("dir/file.txt", FileNameError::PathSeparator),
+        ("..\\..\\boot.ini", FileNameError::PathSeparator),
+        ("\\\\server\\share", FileNameError::PathSeparator),
+        ("C:evil.exe", FileNameError::ForbiddenCharacter(':')),
+        ("file.txt:stream", FileNameError::ForbiddenCharacter(':')),
+        ("what?.txt", FileNameError::ForbiddenCharacter('?')),
+        (".bashrc", FileNameError::Hidden),
+        ("..hidden", FileNameError::Hidden),
+        ("nul\0.txt", FileNameError::ControlCharacter),
+        ("line\nbreak", FileNameError::ControlCharacter),
+        ("invoice\u{202e}fdp.exe", FileNameError::ControlCharacter),
+        ("trailing.", FileNameError::TrailingDotOrSpace),
+        ("trailing ", FileNameError::TrailingDotOrSpace),
+        ("CON", FileNameError::Reserved("CON".to_string())),
+        ("nul.txt", FileNameError::Reserved("nul".to_string())),
+        ("com1.tar.gz", FileNameError::Reserved("com1".to_string())),
+    ];
+    for (name, error) in rejected {
+        assert_eq!(sanitize_file_name(name), Err(error), "{:?}", name);
+    }
+    assert_eq!(
+        sanitize_file_name(&"a".repeat(MAX_FILE_NAME_LEN + 1)),
+        Err(FileNameError::TooLong)
+    );
+
+    assert_eq!(numbered_file_name("notes.txt", 2), "notes (2).txt");
+    assert_eq!(numbered_file_name("README", 1), "README (1)");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_write_received_files() {
+    use crate::{handle_stream_message, MessageType};
+
+    let root = std::env::temp_dir().join(format!("chat-files-{}", uuid::Uuid::new_v4()));
+    let file = |name: &str, content: &[u8]| MessageType::file(name, content.to_vec());
+
+    // Same name twice keeps both files
+    for content in [b"first", b"other"] {
+        let reply = handle_stream_message(file("notes.txt", content), &root).await;
+        assert!(reply.to_string().contains("written to"), "{}", reply);
+    }
+    assert_eq!(std::fs::read(root.join("notes.txt")).unwrap(), b"first");
+    assert_eq!(std::fs::read(root.join("notes (1).txt")).unwrap(), b"other");
+
+    // Nothing is written outside of the root
+    let outside = root.parent().unwrap().join(format!(
+        "chat-escape-{}",
+        root.file_name().unwrap().to_string_lossy()
+    ));
+    for name in [
+        format!("../{}", outside.file_name().unwrap().to_string_lossy()),
+        outside.to_string_lossy().to_string(),
+    ] {
+        let reply = handle_stream_message(file(&name, b"evil"), &root).await;
+        assert!(
+            reply.to_string().contains("Rejected file name"),
+            "{}",
+            reply
+        );
+    }
+    assert!(!outside.exists());
+
+    // Existing symlink is not followed
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(&outside, root.join("link.txt")).unwrap();
+        handle_stream_message(file("link.txt", b"evil"), &root).await;
+        assert!(!outside.exists());
+        assert_eq!(std::fs::read(root.join("link (1).txt")).unwrap(), b"evil");
+    }
+    std::fs::remove_dir_all(root).unwrap();
+}
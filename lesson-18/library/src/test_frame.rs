@@ -0,0 +1,16 @@
+#[cfg(test)]
+#[test]
+fn test_encode_frame() {
+    use crate::{deserialize_message, encode_frame, MessageType};
+
+    let msg = MessageType::file("dummy.txt", vec![1, 2, 3]);
+    let frame = encode_frame(&msg).unwrap();
+
+    let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    assert_eq!(len, frame.len() - 4);
+    assert_eq!(deserialize_message(&frame[4..]).unwrap(), msg);
+
+    // Clones share the same buffer instead of copying it
+    let shared = frame.clone();
+    assert_eq!(shared.as_ptr(), frame.as_ptr());
+}
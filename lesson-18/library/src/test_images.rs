@@ -0,0 +1,169 @@
+#[cfg(test)]
+#[test]
+fn test_process_image() {
+    use crate::images::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    let mut jpeg = Vec::new();
+    RgbImage::new(1024, 256)
+        .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+        .unwrap();
+    let image = process_image(&jpeg).unwrap();
+    assert_eq!((image.width, image.height), (1024, 256));
+    assert_eq!(image.format, "jpg");
+    let sizes: Vec<(u32, u32, u32)> = image
+        .thumbnails
+        .iter()
+        .map(|(t, _)| (t.max_size, t.width, t.height))
+        .collect();
+    assert_eq!(sizes, vec![(128, 128, 32), (512, 512, 128)]);
+    for (thumbnail, png) in &image.thumbnails {
+        assert_eq!(image::guess_format(png).unwrap(), ImageFormat::Png);
+        assert_eq!(thumbnail.size, png.len() as i64);
+    }
+
+    // Small images are not upscaled
+    let mut png = Vec::new();
+    RgbImage::new(64, 48)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+    let image = process_image(&png).unwrap();
+    assert!(image
+        .thumbnails
+        .iter()
+        .all(|(t, _)| (t.width, t.height) == (64, 48)));
+
+    let info = image.info("abc");
+    assert_eq!(info.thumbnails[0].id, "abc-128px");
+    assert_eq!(parse_thumbnail_id("abc-128px"), Some(("abc", 128)));
+    assert_eq!(parse_thumbnail_id("a-b-c-512px"), Some(("a-b-c", 512)));
+    assert_eq!(parse_thumbnail_id("abc-100px"), None);
+    assert_eq!(parse_thumbnail_id("abc"), None);
+    assert_eq!(thumbnail_name("cat.jpeg", 128), "cat-128px.png");
+
+    assert!(process_image(b"not an image").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_image_policy() {
+    use crate::images::*;
+    use crate::DataProcessingError;
+    use image::{ImageFormat, RgbaImage};
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    let mut png = Vec::new();
+    RgbaImage::new(800, 200)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+
+    // Nothing to change
+    let keep = ImagePolicy {
+        strip_metadata: false,
+        ..Default::default()
+    };
+    assert!(matches!(keep.apply(&png).unwrap(), Cow::Borrowed(_)));
+
+    // Downscaled to fit, keeping the aspect ratio
+    let small = ImagePolicy {
+        max_width: 400,
+        max_height: 400,
+        ..Default::default()
+    };
+    let resized = image::load_from_memory(&small.apply(&png).unwrap()).unwrap();
+    assert_eq!((resized.width(), resized.height()), (400, 100));
+
+    for (format, expected) in [
+        (OutputFormat::Jpeg, ImageFormat::Jpeg),
+        (OutputFormat::WebP, ImageFormat::WebP),
+        (OutputFormat::Png, ImageFormat::Png),
+    ] {
+        let policy = ImagePolicy {
+            format,
+            ..Default::default()
+        };
+        let converted = policy.apply(&png).unwrap();
+        assert_eq!(image::guess_format(&converted).unwrap(), expected);
+        assert_eq!(format.to_string().parse::<OutputFormat>(), Ok(format));
+    }
+
+    let tiny = ImagePolicy {
+        max_bytes: 10,
+        ..Default::default()
+    };
+    assert!(matches!(
+        tiny.apply(&png),
+        Err(DataProcessingError::ImageTooLarge(_, 10))
+    ));
+    // Undecodable images are an error, not a panic
+    assert!(matches!(
+        ImagePolicy::default().apply(b"GIF89a broken"),
+        Err(DataProcessingError::ImageError(_))
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn test_animated_images() {
+    use crate::images::*;
+    use image::codecs::gif::{GifEncoder, Repeat};
+    use image::{Delay, Frame, ImageFormat, Rgba, RgbaImage};
+    use std::borrow::Cow;
+
+    let mut gif = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut gif);
+        encoder.set_repeat(Repeat::Infinite).unwrap();
+        let frames = (0..3u8).map(|i| {
+            let buffer = RgbaImage::from_pixel(200, 100, Rgba([i * 100, 0, 0, 255]));
+            Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(100, 1))
+        });
+        encoder.encode_frames(frames).unwrap();
+    }
+
+    let image = process_image(&gif).unwrap();
+    assert_eq!(
+        (image.width, image.height, image.format.as_str()),
+        (200, 100, "gif")
+    );
+    assert_eq!((image.frames, image.duration_ms), (3, 300));
+    let info = image.info("abc");
+    assert_eq!((info.frames, info.duration_ms), (3, 300));
+
+    // Still images have a single frame
+    let mut png = Vec::new();
+    RgbaImage::new(8, 8)
+        .write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+    let image = process_image(&png).unwrap();
+    assert_eq!((image.frames, image.duration_ms), (1, 0));
+    assert!(decode_animation(&png, ImageFormat::Png).unwrap().is_none());
+
+    // Kept as sent when nothing changes, even if another format is configured
+    let keep = ImagePolicy {
+        strip_metadata: false,
+        format: OutputFormat::Jpeg,
+        ..Default::default()
+    };
+    assert!(matches!(keep.apply(&gif).unwrap(), Cow::Borrowed(_)));
+
+    // Every frame is downscaled, the animation stays
+    let small = ImagePolicy {
+        max_width: 100,
+        max_height: 100,
+        format: OutputFormat::Png,
+        ..Default::default()
+    };
+    let resized = small.apply(&gif).unwrap();
+    assert_eq!(image::guess_format(&resized).unwrap(), ImageFormat::Gif);
+    let frames = decode_animation(&resized, ImageFormat::Gif)
+        .unwrap()
+        .unwrap();
+    assert_eq!(frames.len(), 3);
+    assert!(frames
+        .iter()
+        .all(|frame| frame.buffer().dimensions() == (100, 50)));
+    assert_eq!(animation_duration_ms(&frames), 300);
+}
@@ -1,10 +1,10 @@
 #[cfg(test)]
-
 #[test]
 fn test_handle_vec_input() {
     // Test cases for handle_vec_input function
+    use crate::input_handler::{handle_vec_input, parse_get};
+    use crate::{AttachmentRequest, MessageType};
     use uuid::Uuid;
-    use crate::input_handler::handle_vec_input;
     let path = std::env::current_dir().unwrap();
     let cwd = path.to_str().unwrap();
 
@@ -12,5 +12,41 @@ fn test_handle_vec_input() {
     assert!(handle_vec_input(vec!["Hello world!".to_string()]).is_ok());
     assert!(handle_vec_input(vec![".auth".to_string(), Uuid::new_v4().to_string()]).is_ok());
     assert!(handle_vec_input(vec![".file".to_string(), format!("{}/data/dummy.txt", cwd)]).is_ok());
-    assert!(handle_vec_input(vec![".image".to_string(), format!("{}/data/image.png", cwd)]).is_ok());
+    assert!(handle_vec_input(vec![
+        ".image".to_string(),
+        format!("{}/data/image.png", cwd)
+    ])
+    .is_ok());
+    assert!(handle_vec_input(vec![".attachments".to_string()]).is_ok());
+    assert!(matches!(
+        handle_vec_input(vec![".quota".to_string()]),
+        Ok(MessageType::AttachmentRequest(AttachmentRequest::Quota))
+    ));
+    match handle_vec_input(vec![".search".to_string(), "\"hello wor\"* from:abc".to_string()]) {
+        Ok(MessageType::SearchRequest(query)) => {
+            assert_eq!(query.text, "\"hello wor\"*");
+            assert_eq!(query.uid.as_deref(), Some("abc"));
+        }
+        other => panic!("Unexpected {:?}", other),
+    }
+    assert!(handle_vec_input(vec![".search".to_string(), "from:abc".to_string()]).is_err());
+    assert!(handle_vec_input(vec![".get".to_string()]).is_err());
+    assert!(matches!(
+        handle_vec_input(vec![".get".to_string(), "abc /tmp/x".to_string()]),
+        Ok(MessageType::AttachmentRequest(AttachmentRequest::Download { id, offset: 0 })) if id == "abc"
+    ));
+    match handle_vec_input(vec![".image".to_string(), format!("{}/data/image.png | My picture", cwd)]) {
+        Ok(MessageType::Image(info, data)) => {
+            assert_eq!((info.name.as_str(), info.mime.as_str()), ("image.png", "image/png"));
+            assert_eq!(info.caption.as_deref(), Some("My picture"));
+            assert!(info.verify(&data).is_ok());
+            assert!(info.verify(&data[1..]).is_err());
+        }
+        other => panic!("Unexpected {:?}", other),
+    }
+    assert_eq!(parse_get(".get abc"), Some(("abc", None)));
+    assert_eq!(
+        parse_get(".get abc /tmp/my file.txt"),
+        Some(("abc", Some("/tmp/my file.txt")))
+    );
 }
@@ -0,0 +1,234 @@
+#[cfg(test)]
+#[tokio::test]
+async fn test_migrations() {
+    use crate::blob_store::BlobStore;
+    use crate::db_client::{connect_database_pool, DatabaseConfig};
+    use crate::migrations::*;
+
+    let config = DatabaseConfig {
+        url: "sqlite::memory:".to_string(),
+        max_connections: 1,
+        ..Default::default()
+    };
+    let pool = connect_database_pool(&config).await.unwrap();
+    let blobs =
+        BlobStore::new(std::env::temp_dir().join(format!("chat-blobs-{}", uuid::Uuid::new_v4())));
+    assert_eq!(schema_version(&pool).await.unwrap(), 0);
+    assert!(status(&pool)
+        .await
+        .unwrap()
+        .iter()
+        .all(|m| m.installed_on.is_none()));
+
+    assert_eq!(migrate_up(&pool, &blobs).await.unwrap(), latest_version());
+    assert!(status(&pool)
+        .await
+        .unwrap()
+        .iter()
+        .all(|m| m.installed_on.is_some()));
+    // Applying again does nothing
+    assert_eq!(migrate_up(&pool, &blobs).await.unwrap(), latest_version());
+
+    assert_eq!(migrate_down(&pool, 0, &blobs).await.unwrap(), 0);
+    let tables: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE name = 'messages'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(tables, 0);
+    migrate_up(&pool, &blobs).await.unwrap();
+
+    // Database migrated by a newer binary is refused
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+         VALUES (999999, 'from the future', TRUE, x'00', 0)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert!(matches!(
+        migrate_up(&pool, &blobs).await,
+        Err(MigrationError::DatabaseNewer { .. })
+    ));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_legacy_messages() {
+    use crate::blob_store::BlobStore;
+    use crate::db_client::{
+        connect_database_pool, get_attachments, get_messages_all, DatabaseConfig,
+    };
+    use crate::envelope::encode_v0;
+    use crate::migrations::*;
+    use crate::{serialize_message_as_bin, MessageType};
+
+    let config = DatabaseConfig {
+        url: "sqlite::memory:".to_string(),
+        max_connections: 1,
+        ..Default::default()
+    };
+    let pool = connect_database_pool(&config).await.unwrap();
+    let blobs =
+        BlobStore::new(std::env::temp_dir().join(format!("chat-blobs-{}", uuid::Uuid::new_v4())));
+    migrate_up(&pool, &blobs).await.unwrap();
+    migrate_down(&pool, 1, &blobs).await.unwrap();
+
+    // Messages as stored before normalization (with and without envelope), one of them corrupted
+    let text = MessageType::Text("Hello".to_string());
+    let file = MessageType::file("a.txt", b"abc".to_vec());
+    let image = MessageType::image("image.png", std::fs::read("data/image2.png").unwrap());
+    sqlx::query("INSERT INTO users (uid) VALUES ('u1')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    for (id, blob) in [
+        ("m1", serialize_message_as_bin(&text).unwrap()),
+        ("m2", encode_v0(&file).unwrap()),
+        ("m3", vec![9, 0, 0, 0, 255]),
+        ("m4", encode_v0(&image).unwrap()),
+    ] {
+        sqlx::query("INSERT INTO messages (id, uid, timestamp, message) VALUES (?, 'u1', '1', ?)")
+            .bind(id)
+            .bind(blob)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    migrate_up(&pool, &blobs).await.unwrap();
+    let kinds: Vec<(String, Option<String>, Option<String>)> =
+        sqlx::query_as("SELECT id, kind, body FROM messages ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        kinds[0],
+        (
+            "m1".to_string(),
+            Some("text".to_string()),
+            Some("Hello".to_string())
+        )
+    );
+    assert_eq!(kinds[1].1.as_deref(), Some("file"));
+    assert_eq!(kinds[2].1, None);
+    let messages = get_messages_all(&pool, &blobs).await.unwrap();
+    assert_eq!(messages.len(), 3);
+    assert!(messages.iter().any(|m| m.message == file));
+    // File content moved to the blob store
+    assert!(blobs
+        .hashes()
+        .await
+        .unwrap()
+        .contains(&BlobStore::hash(b"abc")));
+    // Image stored before got its thumbnails
+    let attachments = get_attachments(&pool).await.unwrap();
+    let info = attachments.iter().find_map(|a| a.image.clone()).unwrap();
+    assert_eq!(info.thumbnails.len(), 2);
+
+    // Going back restores the blobs
+    migrate_down(&pool, 1, &blobs).await.unwrap();
+    let blob: Vec<u8> = sqlx::query_scalar("SELECT message FROM messages WHERE id = 'm2'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    // in the format readable by older binaries
+    assert_eq!(blob, encode_v0(&file).unwrap());
+    let _ = std::fs::remove_dir_all(blobs.root());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_animation_metadata() {
+    use crate::blob_store::BlobStore;
+    use crate::db_client::{
+        auth_client, connect_database_pool, get_attachments, save_message, DatabaseConfig,
+    };
+    use crate::migrations::*;
+    use crate::MessageType;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, RgbaImage};
+
+    let config = DatabaseConfig {
+        url: "sqlite::memory:".to_string(),
+        max_connections: 1,
+        ..Default::default()
+    };
+    let pool = connect_database_pool(&config).await.unwrap();
+    let blobs =
+        BlobStore::new(std::env::temp_dir().join(format!("chat-blobs-{}", uuid::Uuid::new_v4())));
+    migrate_up(&pool, &blobs).await.unwrap();
+
+    let mut gif = Vec::new();
+    GifEncoder::new(&mut gif)
+        .encode_frames((0..2).map(|_| {
+            Frame::from_parts(
+                RgbaImage::new(16, 16),
+                0,
+                0,
+                Delay::from_numer_denom_ms(250, 1),
+            )
+        }))
+        .unwrap();
+    let uid = uuid::Uuid::new_v4();
+    auth_client(&pool, uid).await.unwrap();
+    save_message(
+        &pool,
+        &blobs,
+        uid.to_string(),
+        &MessageType::image("animation.gif", gif),
+        None,
+    )
+    .await
+    .unwrap();
+
+    // Recorded before frames were counted
+    migrate_down(&pool, IMAGE_METADATA_VERSION, &blobs)
+        .await
+        .unwrap();
+    migrate_up(&pool, &blobs).await.unwrap();
+    let info = get_attachments(&pool).await.unwrap()[0]
+        .image
+        .clone()
+        .unwrap();
+    assert_eq!((info.frames, info.duration_ms), (2, 500));
+    let _ = std::fs::remove_dir_all(blobs.root());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_storage_usage() {
+    use crate::blob_store::BlobStore;
+    use crate::db_client::{
+        auth_client, connect_database_pool, get_user, save_message, DatabaseConfig,
+    };
+    use crate::migrations::*;
+    use crate::MessageType;
+
+    let config = DatabaseConfig {
+        url: "sqlite::memory:".to_string(),
+        max_connections: 1,
+        ..Default::default()
+    };
+    let pool = connect_database_pool(&config).await.unwrap();
+    let blobs =
+        BlobStore::new(std::env::temp_dir().join(format!("chat-blobs-{}", uuid::Uuid::new_v4())));
+    migrate_up(&pool, &blobs).await.unwrap();
+    let uid = uuid::Uuid::new_v4();
+    auth_client(&pool, uid).await.unwrap();
+    for name in ["a.txt", "b.txt"] {
+        let file = MessageType::file(name, b"abc".to_vec());
+        save_message(&pool, &blobs, uid.to_string(), &file, None)
+            .await
+            .unwrap();
+    }
+
+    // Usage of attachments uploaded before it was tracked
+    migrate_down(&pool, IMAGE_ANIMATION_VERSION, &blobs)
+        .await
+        .unwrap();
+    migrate_up(&pool, &blobs).await.unwrap();
+    let user = get_user(&pool, &uid.to_string()).await.unwrap().unwrap();
+    assert_eq!((user.used_bytes, user.quota_bytes), (6, None));
+    let _ = std::fs::remove_dir_all(blobs.root());
+}
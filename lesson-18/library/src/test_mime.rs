@@ -0,0 +1,56 @@
+#[cfg(test)]
+#[test]
+fn test_sniff() {
+    use crate::mime::*;
+
+    assert_eq!(
+        sniff(&std::fs::read("data/image.png").unwrap()),
+        "image/png"
+    );
+    assert_eq!(sniff(b"%PDF-1.7\n..."), "application/pdf");
+    assert_eq!(sniff(b"PK\x03\x04\x14\x00"), "application/zip");
+    assert_eq!(sniff(b"\x7fELF\x02\x01\x01"), "application/x-executable");
+    assert_eq!(sniff(b"#!/bin/sh\nrm -rf ~"), "text/x-shellscript");
+    let mut tar = vec![0; 512];
+    tar[257..262].copy_from_slice(b"ustar");
+    assert_eq!(sniff(&tar), "application/x-tar");
+    assert_eq!(sniff("Hello, world\n\tčau".as_bytes()), TEXT_PLAIN);
+    assert_eq!(sniff(&[0, 159, 146, 150]), OCTET_STREAM);
+}
+
+#[cfg(test)]
+#[test]
+fn test_upload_policy() {
+    use crate::mime::*;
+
+    let policy = UploadPolicy::default();
+    assert!(policy.check("application/pdf").is_ok());
+    assert_eq!(
+        policy.check("application/x-executable"),
+        Err(BlockedType::Denied(
+            "application/x-executable".to_string(),
+            "application/x-executable".to_string()
+        ))
+    );
+
+    let images_only = UploadPolicy {
+        allowed_types: vec!["image/*".to_string(), "text/plain".to_string()],
+        denied_types: vec!["image/gif".to_string()],
+    };
+    assert!(images_only.check("image/png").is_ok());
+    assert!(images_only.check("text/plain").is_ok());
+    assert!(matches!(
+        images_only.check("image/gif"),
+        Err(BlockedType::Denied(..))
+    ));
+    assert_eq!(
+        images_only.check("application/zip"),
+        Err(BlockedType::NotAllowed("application/zip".to_string()))
+    );
+    assert!(matches_type("*/*", "application/zip"));
+    assert!(!matches_type("image/*", "imagex/png"));
+
+    assert!(validate_type_pattern("image/*").is_ok());
+    assert!(validate_type_pattern("image").is_err());
+    assert!(validate_type_pattern("/png").is_err());
+}
@@ -0,0 +1,192 @@
+#[cfg(test)]
+#[test]
+fn test_expired_messages() {
+    use crate::retention::{expired_messages, RetentionPolicy, RetentionRule};
+    use crate::{Message, MessageType};
+    use std::time::Duration;
+
+    let message = |id: &str, uid: &str, timestamp: u64, file: bool| Message {
+        id: id.to_string(),
+        uid: uid.to_string(),
+        timestamp: timestamp.to_string(),
+        message: MessageType::Text(id.to_string()),
+        attachment_id: file.then(|| format!("a-{}", id)),
+    };
+    let messages = vec![
+        message("old", "alice", 1_000, false),
+        message("file", "alice", 5_000, true),
+        message("text", "alice", 6_000, false),
+        message("bob", "bob", 6_000, true),
+        message("newer file", "bob", 7_000, true),
+    ];
+    let expired = |policy: RetentionPolicy| {
+        let mut ids: Vec<String> = expired_messages(&policy, &messages, 10_000)
+            .into_iter()
+            .collect();
+        ids.sort();
+        ids
+    };
+    assert!(expired(RetentionPolicy::default()).is_empty());
+
+    let by_age = RetentionPolicy {
+        messages: RetentionRule {
+            max_age: Some(Duration::from_secs(8)),
+            max_per_user: None,
+        },
+        ..Default::default()
+    };
+    assert_eq!(expired(by_age), vec!["old"]);
+
+    // Attachments have rules of their own, newest are kept
+    let attachments = RetentionPolicy {
+        attachments: RetentionRule {
+            max_age: None,
+            max_per_user: Some(1),
+        },
+        ..Default::default()
+    };
+    assert_eq!(expired(attachments), vec!["bob"]);
+    let per_user = RetentionPolicy {
+        messages: RetentionRule {
+            max_age: None,
+            max_per_user: Some(2),
+        },
+        attachments: RetentionRule {
+            max_age: Some(Duration::from_millis(4_000)),
+            max_per_user: None,
+        },
+    };
+    assert_eq!(expired(per_user), vec!["file", "old"]);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_purge_expired() {
+    use crate::blob_store::BlobStore;
+    use crate::db_client::DatabaseConfig;
+    use crate::retention::{RetentionPolicy, RetentionRule};
+    use crate::search::SearchQuery;
+    use crate::store::{open_store, StoreBackend};
+    use crate::{get_timestamp, MessageType};
+    use uuid::Uuid;
+
+    let blobs_dir = std::env::temp_dir().join(format!("chat-blobs-{}", Uuid::new_v4()));
+    for backend in [StoreBackend::Memory, StoreBackend::Sqlite] {
+        let config = DatabaseConfig {
+            backend,
+            url: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            blobs_dir: blobs_dir.clone(),
+            ..Default::default()
+        };
+        let store = open_store(&config).await.unwrap();
+        let uid = Uuid::new_v4();
+        store.auth_user(uid).await.unwrap();
+        let uid = uid.to_string();
+        for name in ["a.txt", "b.txt"] {
+            let file = MessageType::file(name, name.as_bytes().to_vec());
+            store.save_message(&uid, &file).await.unwrap();
+        }
+        let text = MessageType::Text("Hello".to_string());
+        store.save_message(&uid, &text).await.unwrap();
+
+        // Only the newest file is kept, text is not affected
+        let policy = RetentionPolicy {
+            attachments: RetentionRule {
+                max_age: None,
+                max_per_user: Some(1),
+            },
+            ..Default::default()
+        };
+        let now = get_timestamp().parse().unwrap();
+        let report = store.purge_expired(&policy, now).await.unwrap();
+        assert_eq!(
+            (report.messages, report.attachments, report.bytes),
+            (1, 1, 5),
+            "{}",
+            backend
+        );
+        let attachments = store.attachments().await.unwrap();
+        assert_eq!(attachments.len(), 1, "{}", backend);
+        assert_eq!(attachments[0].name, "b.txt");
+        let user = store.user(&uid).await.unwrap().unwrap();
+        assert_eq!(user.used_bytes, 5, "{}", backend);
+        if backend == StoreBackend::Sqlite {
+            assert_eq!(report.blobs, 1);
+            let blobs = BlobStore::new(&blobs_dir);
+            assert_eq!(blobs.hashes().await.unwrap().len(), 1);
+        }
+        let report = store.purge_expired(&policy, now).await.unwrap();
+        assert_eq!(report.messages, 0, "{}", backend);
+
+        // Everything sent before now is expired
+        let policy = RetentionPolicy {
+            messages: RetentionRule {
+                max_age: Some(std::time::Duration::ZERO),
+                max_per_user: None,
+            },
+            ..Default::default()
+        };
+        let report = store.purge_expired(&policy, now + 1).await.unwrap();
+        assert_eq!(report.messages, 2, "{}", backend);
+        assert!(store.messages().await.unwrap().is_empty());
+        let hits = store.search(&SearchQuery::new("hello")).await.unwrap();
+        assert!(hits.is_empty(), "{}", backend);
+        store.close().await;
+    }
+    let _ = std::fs::remove_dir_all(blobs_dir);
+}
+
+#[cfg(test)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_purge_while_saving() {
+    use crate::db_client::DatabaseConfig;
+    use crate::retention::{RetentionPolicy, RetentionRule};
+    use crate::store::open_store;
+    use crate::{get_timestamp, MessageType};
+    use uuid::Uuid;
+
+    let tmp = std::env::temp_dir().join(format!("chat-purge-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    let config = DatabaseConfig {
+        url: format!("sqlite:{}", tmp.join("chat.db").display()),
+        max_connections: 4,
+        blobs_dir: tmp.join("blobs"),
+        ..Default::default()
+    };
+    let store = open_store(&config).await.unwrap();
+    let uid = Uuid::new_v4();
+    store.auth_user(uid).await.unwrap();
+    let uid = uid.to_string();
+    let content = b"same content every time".to_vec();
+    let file = MessageType::file("report.txt", content.clone());
+    let policy = RetentionPolicy {
+        messages: RetentionRule {
+            max_age: Some(std::time::Duration::ZERO),
+            max_per_user: None,
+        },
+        ..Default::default()
+    };
+
+    // The last reference to the content is released while a new one is added
+    for _ in 0..50 {
+        store.save_message(&uid, &file).await.unwrap();
+        let now: u64 = get_timestamp().parse().unwrap();
+        let (purged, saved) = tokio::join!(
+            store.purge_expired(&policy, now + 1),
+            store.save_message(&uid, &file)
+        );
+        purged.unwrap();
+        saved.unwrap();
+        for attachment in store.attachments().await.unwrap() {
+            let chunk = store
+                .read_attachment(&attachment.id, 0, usize::MAX)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(chunk.data, content);
+        }
+    }
+    store.close().await;
+    let _ = std::fs::remove_dir_all(tmp);
+}
@@ -0,0 +1,139 @@
+#[cfg(test)]
+#[test]
+fn test_search_query() {
+    use crate::search::*;
+
+    let query = SearchQuery::parse(
+        "\"hello wor\"* from:abc since:2h until:500 cat*",
+        10_000_000,
+    )
+    .unwrap();
+    assert_eq!(query.text, "\"hello wor\"* cat*");
+    assert_eq!(query.uid.as_deref(), Some("abc"));
+    assert_eq!(query.since, Some(10_000_000 - 2 * 60 * 60 * 1000));
+    assert_eq!(query.until, Some(500));
+    assert!(SearchQuery::parse("since:yesterday", 0).is_err());
+
+    // Punctuation cannot break the FTS5 query
+    let terms = SearchQuery::new("Don't \"NEAR(\" x*").terms();
+    assert_eq!(fts_query(&terms), "\"don t\" \"near\" \"x\"*");
+    assert!(SearchQuery::new(" \"\" * ").terms().is_empty());
+
+    let terms = SearchQuery::new("\"quick brown\" jump*").terms();
+    let text = "The quick brown fox jumps, quick!";
+    assert_eq!(
+        match_text(&terms, text),
+        Some(vec![(4, 9), (10, 15), (20, 25)])
+    );
+    assert_eq!(match_text(&terms, "brown quick fox jumps"), None);
+
+    let hit = SearchHit {
+        message_id: "m".to_string(),
+        uid: "u".to_string(),
+        timestamp: "1".to_string(),
+        attachment_id: None,
+        snippet: text.to_string(),
+        highlights: vec![(4, 9), (20, 25), (30, 99)],
+    };
+    assert_eq!(
+        hit.highlighted("<", ">"),
+        "The <quick> brown fox <jumps>, quick!"
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_store_search() {
+    use crate::db_client::DatabaseConfig;
+    use crate::search::SearchQuery;
+    use crate::store::{open_store, StoreBackend};
+    use crate::{AttachmentInfo, MessageType};
+    use uuid::Uuid;
+
+    let blobs_dir = std::env::temp_dir().join(format!("chat-blobs-{}", Uuid::new_v4()));
+    for backend in [StoreBackend::Memory, StoreBackend::Sqlite] {
+        let config = DatabaseConfig {
+            backend,
+            url: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            blobs_dir: blobs_dir.clone(),
+            ..Default::default()
+        };
+        let store = open_store(&config).await.unwrap();
+        let alice = Uuid::new_v4().to_string();
+        let bob = Uuid::new_v4().to_string();
+        store.auth_user(alice.parse().unwrap()).await.unwrap();
+        store.auth_user(bob.parse().unwrap()).await.unwrap();
+
+        let hello = MessageType::Text("Hello wonderful world".to_string());
+        let hello_id = store.save_message(&alice, &hello).await.unwrap();
+        let other = MessageType::Text("Hello there".to_string());
+        store.save_message(&bob, &other).await.unwrap();
+        let data = b"quarterly numbers".to_vec();
+        let info = AttachmentInfo::new("report.txt", &data, Some("Budget".to_string()));
+        let file_id = store
+            .save_message(&bob, &MessageType::File(info, data))
+            .await
+            .unwrap();
+
+        let search = |text: &str| SearchQuery::new(text);
+        let hits = store.search(&search("wonderful")).await.unwrap();
+        assert_eq!(hits.len(), 1, "{}", backend);
+        assert_eq!(hits[0].message_id, hello_id);
+        assert_eq!(hits[0].uid, alice);
+        assert_eq!(hits[0].highlighted("[", "]"), "Hello [wonderful] world");
+        assert_eq!(store.search(&search("hello")).await.unwrap().len(), 2);
+        assert_eq!(store.search(&search("wonder*")).await.unwrap().len(), 1);
+        assert_eq!(
+            store
+                .search(&search("\"hello world\""))
+                .await
+                .unwrap()
+                .len(),
+            0,
+            "{}",
+            backend
+        );
+        assert_eq!(
+            store
+                .search(&search("\"hello wonder\"*"))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Names and captions of attachments are searched, content is not
+        let hits = store.search(&search("report")).await.unwrap();
+        assert_eq!(hits.len(), 1, "{}", backend);
+        assert_eq!(hits[0].message_id, file_id);
+        assert!(hits[0].attachment_id.is_some());
+        assert_eq!(store.search(&search("budget")).await.unwrap().len(), 1);
+        assert!(store.search(&search("quarterly")).await.unwrap().is_empty());
+
+        // Filters
+        let mut query = search("hello");
+        query.uid = Some(bob.clone());
+        assert_eq!(store.search(&query).await.unwrap().len(), 1, "{}", backend);
+        let mut query = search("hello");
+        query.until = Some(1);
+        assert!(
+            store.search(&query).await.unwrap().is_empty(),
+            "{}",
+            backend
+        );
+        let mut query = search("hello");
+        query.since = Some(1);
+        query.limit = 1;
+        assert_eq!(store.search(&query).await.unwrap().len(), 1, "{}", backend);
+
+        // Deleted messages are gone from the index
+        store.delete_message(&hello_id).await.unwrap();
+        assert!(store.search(&search("wonderful")).await.unwrap().is_empty());
+        store.delete_user(&bob).await.unwrap();
+        assert!(store.search(&search("hello")).await.unwrap().is_empty());
+        assert!(store.search(&search("")).await.unwrap().is_empty());
+        store.close().await;
+    }
+    let _ = std::fs::remove_dir_all(blobs_dir);
+}
@@ -0,0 +1,208 @@
+#[cfg(test)]
+#[tokio::test]
+async fn test_store() {
+    use crate::db_client::DatabaseConfig;
+    use crate::store::{open_store, StoreBackend, StoreError};
+    use crate::MessageType;
+    use uuid::Uuid;
+
+    let blobs_dir = std::env::temp_dir().join(format!("chat-blobs-{}", Uuid::new_v4()));
+    for backend in [StoreBackend::Memory, StoreBackend::Sqlite] {
+        let config = DatabaseConfig {
+            backend,
+            url: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            blobs_dir: blobs_dir.clone(),
+            ..Default::default()
+        };
+        let store = open_store(&config).await.unwrap();
+
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        store.auth_user(alice).await.unwrap();
+        store.auth_user(alice).await.unwrap();
+        store.auth_user(bob).await.unwrap();
+        assert_eq!(store.users().await.unwrap().len(), 2, "{}", backend);
+
+        let msg = MessageType::Text("Hello world".to_string());
+        let id = store.save_message(&alice.to_string(), &msg).await.unwrap();
+        store.save_message(&bob.to_string(), &msg).await.unwrap();
+        let messages = store.messages_of_user(&alice.to_string()).await.unwrap();
+        assert_eq!(messages.len(), 1, "{}", backend);
+        assert_eq!(messages[0].id, id);
+        assert_eq!(messages[0].message, msg);
+
+        // Messages can be saved only by known users
+        let unknown = store.save_message(&Uuid::new_v4().to_string(), &msg).await;
+        assert!(
+            matches!(unknown, Err(StoreError::UnknownUser(_))),
+            "{}",
+            backend
+        );
+
+        // Content of files is kept as attachment
+        let file = MessageType::file("notes.txt", b"some notes".to_vec());
+        let file_id = store.save_message(&bob.to_string(), &file).await.unwrap();
+        let attachments = store.attachments().await.unwrap();
+        assert_eq!(attachments.len(), 1, "{}", backend);
+        assert_eq!(attachments[0].name, "notes.txt");
+        assert_eq!(attachments[0].size, 10);
+        assert_eq!(attachments[0].hash.len(), 64);
+        let messages = store.messages_of_user(&bob.to_string()).await.unwrap();
+        assert!(messages.iter().any(|m| m.message == file), "{}", backend);
+        let stored = messages.iter().find(|m| m.message == file).unwrap();
+        assert_eq!(stored.attachment_id, Some(attachments[0].id.clone()));
+
+        // Attachments count against the storage of their sender
+        let user = store.user(&bob.to_string()).await.unwrap().unwrap();
+        assert_eq!(user.used_bytes, 10, "{}", backend);
+        assert_eq!(user.quota_bytes, None);
+        store.set_quota(&bob.to_string(), Some(5)).await.unwrap();
+        let user = store.user(&bob.to_string()).await.unwrap().unwrap();
+        assert_eq!(user.quota_bytes, Some(5), "{}", backend);
+        let unknown = store.set_quota(&Uuid::new_v4().to_string(), None).await;
+        assert!(
+            matches!(unknown, Err(StoreError::UnknownUser(_))),
+            "{}",
+            backend
+        );
+        assert!(store.user("missing").await.unwrap().is_none());
+
+        // Uploads over the quota are refused and not stored, the default applies unless set
+        let more = MessageType::file("more.txt", b"more".to_vec());
+        let refused = store
+            .save_message_within_quota(&bob.to_string(), &more, 0)
+            .await;
+        assert!(
+            matches!(refused, Err(StoreError::QuotaExceeded(4, _))),
+            "{}",
+            backend
+        );
+        let refused = store
+            .save_message_within_quota(&alice.to_string(), &more, 3)
+            .await;
+        assert!(
+            matches!(refused, Err(StoreError::QuotaExceeded(4, _))),
+            "{}",
+            backend
+        );
+        assert_eq!(store.attachments().await.unwrap().len(), 1, "{}", backend);
+        let user = store.user(&bob.to_string()).await.unwrap().unwrap();
+        assert_eq!(user.used_bytes, 10, "{}", backend);
+
+        // Content is read in chunks
+        let chunk = store
+            .read_attachment(&attachments[0].id, 5, 3)
+            .await
+            .unwrap();
+        let chunk = chunk.unwrap();
+        assert_eq!(chunk.data, b"not", "{}", backend);
+        assert!(!chunk.is_last());
+        let chunk = store
+            .read_attachment(&attachments[0].id, 5, 100)
+            .await
+            .unwrap();
+        assert!(chunk.unwrap().is_last(), "{}", backend);
+        assert!(store
+            .read_attachment("missing", 0, 3)
+            .await
+            .unwrap()
+            .is_none());
+        store.delete_message(&file_id).await.unwrap();
+        assert!(store.attachments().await.unwrap().is_empty(), "{}", backend);
+        let user = store.user(&bob.to_string()).await.unwrap().unwrap();
+        assert_eq!(user.used_bytes, 0, "{}", backend);
+
+        // Images get metadata and thumbnails, readable as attachments of their own
+        let png = std::fs::read("data/image.png").unwrap();
+        let image_id = store
+            .save_message(&bob.to_string(), &MessageType::image("image.png", png))
+            .await
+            .unwrap();
+        let attachments = store.attachments().await.unwrap();
+        let info = attachments[0].image.clone().unwrap();
+        assert_eq!(info.format, "png", "{}", backend);
+        assert_eq!(info.thumbnails.len(), 2, "{}", backend);
+        let thumbnail = &info.thumbnails[0];
+        assert!(thumbnail.width <= 128 && thumbnail.height <= 128);
+        let chunk = store
+            .read_attachment(&thumbnail.id, 0, usize::MAX)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.attachment.mime, "image/png", "{}", backend);
+        assert_eq!(chunk.data.len() as i64, thumbnail.size);
+        assert!(chunk.is_last());
+        store.delete_message(&image_id).await.unwrap();
+        assert!(store.attachments().await.unwrap().is_empty(), "{}", backend);
+        let deleted = store.read_attachment(&thumbnail.id, 0, 1).await.unwrap();
+        assert!(deleted.is_none(), "{}", backend);
+
+        store.delete_message(&id).await.unwrap();
+        assert_eq!(store.messages().await.unwrap().len(), 1, "{}", backend);
+        store.delete_user(&bob.to_string()).await.unwrap();
+        assert!(store.messages().await.unwrap().is_empty(), "{}", backend);
+        assert_eq!(store.users().await.unwrap().len(), 1, "{}", backend);
+        store.close().await;
+    }
+    let _ = std::fs::remove_dir_all(blobs_dir);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_blob_references() {
+    use crate::blob_store::BlobStore;
+    use crate::db_client::{connect_database_pool, DatabaseConfig};
+    use crate::migrations::migrate_up;
+    use crate::store::{MessageStore, SqliteStore, UserStore};
+    use crate::MessageType;
+    use uuid::Uuid;
+
+    let config = DatabaseConfig {
+        url: "sqlite::memory:".to_string(),
+        max_connections: 1,
+        ..Default::default()
+    };
+    let blobs = BlobStore::new(std::env::temp_dir().join(format!("chat-blobs-{}", Uuid::new_v4())));
+    let pool = connect_database_pool(&config).await.unwrap();
+    migrate_up(&pool, &blobs).await.unwrap();
+    let store = SqliteStore::new(pool, blobs.clone());
+    let uid = Uuid::new_v4();
+    store.auth_user(uid).await.unwrap();
+
+    // Identical uploads share one blob
+    let file = MessageType::file("a.txt", b"same content".to_vec());
+    let first = store.save_message(&uid.to_string(), &file).await.unwrap();
+    let second = store.save_message(&uid.to_string(), &file).await.unwrap();
+    store
+        .save_message(
+            &uid.to_string(),
+            &MessageType::image("image.png", b"other".to_vec()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(blobs.hashes().await.unwrap().len(), 2);
+
+    // Removed once nothing refers to it
+    store.delete_message(&first).await.unwrap();
+    assert_eq!(blobs.hashes().await.unwrap().len(), 2);
+    store.delete_message(&second).await.unwrap();
+    assert_eq!(
+        blobs.hashes().await.unwrap(),
+        vec![BlobStore::hash(b"other")]
+    );
+    assert_eq!(store.messages().await.unwrap().len(), 1);
+
+    // Files nothing refers to are collected
+    blobs.put(b"left behind").await.unwrap();
+    assert_eq!(
+        crate::db_client::collect_garbage(store.pool(), &blobs)
+            .await
+            .unwrap(),
+        1
+    );
+    store.delete_user(&uid.to_string()).await.unwrap();
+    assert!(blobs.hashes().await.unwrap().is_empty());
+    assert!(store.messages().await.unwrap().is_empty());
+    let _ = std::fs::remove_dir_all(blobs.root());
+}
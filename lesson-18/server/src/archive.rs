@@ -0,0 +1,89 @@
+//! `export` and `import` subcommands of the server - move chat history between stores, see `library::archive`
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use library::archive::{self, ExportFilter};
+use library::blob_store::BlobStore;
+use library::db_client::{connect_database_pool, DatabaseConfig};
+use library::get_timestamp;
+use library::migrations::{self, latest_version};
+use library::search::parse_time;
+use library::store::{SqliteStore, Store, StoreBackend};
+
+/// What to export
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ExportArgs {
+    /// Archive to write, a `.tar` file or a directory
+    pub path: PathBuf,
+    /// Only this user and its messages, can be repeated
+    #[arg(long = "user", value_name = "UID")]
+    pub uids: Vec<String>,
+    /// Only messages sent since, milliseconds since UNIX epoch or an age like 7d
+    #[arg(long)]
+    pub since: Option<String>,
+    /// Only messages sent until, milliseconds since UNIX epoch or an age like 7d
+    #[arg(long)]
+    pub until: Option<String>,
+}
+
+/// Writes users, messages and attachments of the configured database to an archive
+pub async fn export(
+    args: &ExportArgs,
+    config: &DatabaseConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if config.backend != StoreBackend::Sqlite {
+        return Err(format!("{} store has nothing to export", config.backend).into());
+    }
+    let now = get_timestamp().parse()?;
+    let filter = ExportFilter {
+        uids: args.uids.clone(),
+        since: args
+            .since
+            .as_deref()
+            .map(|since| parse_time(since, now))
+            .transpose()?,
+        until: args
+            .until
+            .as_deref()
+            .map(|until| parse_time(until, now))
+            .transpose()?,
+    };
+    let store = connect_store(config).await?;
+    let report = archive::export(&store, &args.path, &filter).await;
+    store.close().await;
+    println!("Exported {} to {}", report?, args.path.display());
+    Ok(())
+}
+
+/// Reads an archive into the configured database, messages stored already are skipped
+pub async fn import(
+    path: &Path,
+    config: &DatabaseConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if config.backend != StoreBackend::Sqlite {
+        return Err(format!("{} store would lose the import on exit", config.backend).into());
+    }
+    let store = connect_store(config).await?;
+    let report = archive::import(&store, path).await;
+    store.close().await;
+    println!("Imported {} from {}", report?, path.display());
+    Ok(())
+}
+
+/// Opens the database without migrating it, it must have the schema of this binary
+async fn connect_store(
+    config: &DatabaseConfig,
+) -> Result<SqliteStore, Box<dyn Error + Send + Sync>> {
+    let pool = connect_database_pool(config).await?;
+    let version = migrations::check_version(&pool).await?;
+    if version < latest_version() {
+        pool.close().await;
+        return Err(format!(
+            "Database schema version {} is outdated, run `migrate up` first",
+            version
+        )
+        .into());
+    }
+    Ok(SqliteStore::new(pool, BlobStore::new(&config.blobs_dir)))
+}
@@ -0,0 +1,43 @@
+//! `backup` and `restore` subcommands of the server, see `library::backup`
+use std::error::Error;
+use std::path::Path;
+
+use library::backup::{self, BackupError};
+use library::blob_store::BlobStore;
+use library::db_client::{connect_database_pool, DatabaseConfig};
+use library::store::StoreBackend;
+
+/// Copies the configured database and its blobs into a new directory, the server may keep running
+pub async fn backup(
+    dir: &Path,
+    config: &DatabaseConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if config.backend != StoreBackend::Sqlite {
+        return Err(BackupError::Unsupported(config.backend).into());
+    }
+    let pool = connect_database_pool(config).await?;
+    let report = backup::backup(&pool, &BlobStore::new(&config.blobs_dir), dir).await;
+    pool.close().await;
+    let report = report?;
+    println!("Backed up {} to {}", report, dir.display());
+    if report.missing_blobs > 0 {
+        return Err("Blobs were deleted during the backup, run it again".into());
+    }
+    Ok(())
+}
+
+/// Checks a backup and, unless `check_only`, replaces the configured database and blobs with it
+pub async fn restore(
+    dir: &Path,
+    check_only: bool,
+    config: &DatabaseConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if check_only {
+        let report = backup::verify(dir).await?;
+        println!("Backup {} is intact: {}", dir.display(), report);
+        return Ok(());
+    }
+    let report = backup::restore(dir, config).await?;
+    println!("Restored {} from {}", report, dir.display());
+    Ok(())
+}
@@ -0,0 +1,353 @@
+//! Embeddable chat server
+//!
+//! `ChatServer` is a builder - give it an address, optionally a store (or database pool) and a config,
+//! and `start()` it on the current tokio runtime. The returned `ChatServerHandle` lets the caller
+//! see connected clients, inject messages and shut the server down.
+//!
+//! Reloadable settings (`RuntimeConfig`) can be replaced while the server runs using
+//! `ChatServerHandle::reload` or a `ConfigReloader`, without dropping any connection.
+//!
+//! Messages the retention policy no longer keeps are deleted in the background, see `retention`.
+//!
+//! Shutdown is graceful - the server stops accepting connections, sends a shutdown notice to all
+//! clients and flushes their outboxes by one deadline, `ServerConfig::shutdown_grace` after the request.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! use server::ChatServer;
+//!
+//! let handle = ChatServer::new("127.0.0.1:11111".parse()?).start().await?;
+//! handle.inject(library::MessageType::Text("Hello everyone".to_string())).await?;
+//! handle.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+use library::blob_store::BlobStore;
+use library::metrics::inc_config_reload_count;
+use library::store::{open_store, SqliteStore, Store};
+use library::{encode_frame, MessageType};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::config::{RuntimeConfig, ServerConfig};
+use crate::connection::{
+    broadcast, handle_connection, report_push, shutdown_requested, Clients, Settings, BANNED_NOTICE,
+};
+use crate::retention::enforce_retention;
+
+/// How many injected messages can wait to be broadcast
+const INJECT_QUEUE_SIZE: usize = 64;
+
+/// Builder of the chat server
+pub struct ChatServer {
+    addr: SocketAddr,
+    store: Option<Arc<dyn Store>>,
+    config: ServerConfig,
+}
+
+/// Client currently connected to the server
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConnectedClient {
+    /// Address the client is connected from
+    pub addr: SocketAddr,
+    /// UID of the client, `None` until it authenticates
+    pub uid: Option<Uuid>,
+}
+
+/// Handle of a running chat server
+///
+/// Dropping the handle shuts the server down as well.
+pub struct ChatServerHandle {
+    local_addr: SocketAddr,
+    clients: Clients,
+    injector: mpsc::Sender<MessageType>,
+    shutdown: watch::Sender<bool>,
+    stopped: watch::Receiver<bool>,
+    reloader: ConfigReloader,
+}
+
+/// Replaces reloadable settings of a running server, can be cloned and moved to other tasks
+#[derive(Clone)]
+pub struct ConfigReloader {
+    settings: Arc<watch::Sender<Arc<RuntimeConfig>>>,
+    clients: Clients,
+}
+
+impl ChatServer {
+    /// New server listening on given address, with default config
+    pub fn new(addr: SocketAddr) -> Self {
+        ChatServer {
+            addr,
+            store: None,
+            config: ServerConfig::default(),
+        }
+    }
+
+    /// Use an existing database pool instead of opening a new store from `config.database`
+    ///
+    /// Attachment contents go to `config.database.blobs_dir`, so set `config` first.
+    pub fn pool(self, pool: Pool<Sqlite>) -> Self {
+        let blobs = BlobStore::new(&self.config.database.blobs_dir);
+        self.store(Arc::new(SqliteStore::new(pool, blobs)))
+    }
+
+    /// Use an existing store (shared e.g. with the webapp) instead of opening a new one
+    pub fn store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Set server config
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Binds the listener and starts accepting clients on the current tokio runtime
+    ///
+    /// Blobs left unreferenced by a previous run are removed first.
+    pub async fn start(self) -> Result<ChatServerHandle, Box<dyn Error + Send + Sync>> {
+        let (store, owns_store) = match self.store {
+            Some(store) => (store, false),
+            None => (open_store(&self.config.database).await?, true),
+        };
+        store.collect_garbage().await?;
+        let listener = TcpListener::bind(self.addr).await?;
+        let local_addr = listener.local_addr()?;
+        log::info!("Chat server listening on {}", local_addr);
+
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let (injector, inject_rx) = mpsc::channel(INJECT_QUEUE_SIZE);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (stopped_tx, stopped) = watch::channel(false);
+        let (settings, settings_rx) = watch::channel(Arc::new(self.config.runtime.clone()));
+        let reloader = ConfigReloader {
+            settings: Arc::new(settings),
+            clients: Arc::clone(&clients),
+        };
+
+        let context = ServerContext {
+            clients: Arc::clone(&clients),
+            settings: settings_rx,
+            store,
+            owns_store,
+            config: self.config,
+        };
+        tokio::spawn(serve(listener, context, inject_rx, shutdown_rx, stopped_tx));
+
+        Ok(ChatServerHandle {
+            local_addr,
+            clients,
+            injector,
+            shutdown,
+            stopped,
+            reloader,
+        })
+    }
+}
+
+impl ChatServerHandle {
+    /// Address the server actually listens on (useful when bound to port 0)
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// List of currently connected clients
+    pub fn connected_clients(&self) -> Vec<ConnectedClient> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, client)| ConnectedClient {
+                addr: *addr,
+                uid: client.uid,
+            })
+            .collect()
+    }
+
+    /// Channel to inject messages, which are broadcast to all authenticated clients
+    pub fn injector(&self) -> mpsc::Sender<MessageType> {
+        self.injector.clone()
+    }
+
+    /// Broadcast a message to all authenticated clients
+    pub async fn inject(
+        &self,
+        message: MessageType,
+    ) -> Result<(), mpsc::error::SendError<MessageType>> {
+        self.injector.send(message).await
+    }
+
+    /// Replaces reloadable settings, see `ConfigReloader::reload`
+    pub async fn reload(&self, config: RuntimeConfig) -> Vec<String> {
+        self.reloader.reload(config).await
+    }
+
+    /// Reloader which can outlive a borrow of the handle, e.g. for a signal handling task
+    pub fn reloader(&self) -> ConfigReloader {
+        self.reloader.clone()
+    }
+
+    /// Gracefully stop the server and wait until all connections are closed
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.stopped().await;
+    }
+
+    /// Wait until the server stops
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.clone();
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+}
+
+impl ConfigReloader {
+    /// Current reloadable settings
+    pub fn current(&self) -> Arc<RuntimeConfig> {
+        Arc::clone(&*self.settings.borrow())
+    }
+
+    /// Applies new settings to all connections and disconnects newly banned clients.
+    /// Returns list of changes, which is logged as well.
+    pub async fn reload(&self, config: RuntimeConfig) -> Vec<String> {
+        let changes = self.current().changes(&config);
+        inc_config_reload_count();
+        if changes.is_empty() {
+            log::info!("Config reloaded, no changes");
+        } else {
+            log::info!("Config reloaded: {}", changes.join(", "));
+        }
+        // RUST_LOG takes precedence over the config
+        if std::env::var("RUST_LOG").is_err() {
+            log::set_max_level(config.log_level);
+        }
+
+        let banned: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(addr, client)| config.is_banned(addr.ip(), client.uid))
+            .map(|(addr, client)| (*addr, Arc::clone(&client.outbox)))
+            .collect();
+        self.settings.send_replace(Arc::new(config));
+
+        if let Ok(frame) = encode_frame(&MessageType::Error(BANNED_NOTICE.to_string())) {
+            for (addr, outbox) in banned {
+                log::warn!("Disconnecting banned client {}", addr);
+                if let Ok(outcome) =
+                    tokio::time::timeout(Duration::from_secs(1), outbox.push(frame.clone())).await
+                {
+                    report_push(outcome, addr);
+                }
+                outbox.close();
+            }
+        }
+        changes
+    }
+}
+
+/// State of a running server shared with its connections
+struct ServerContext {
+    clients: Clients,
+    settings: Settings,
+    store: Arc<dyn Store>,
+    /// Store given by the caller is left open on shutdown, our own is closed
+    owns_store: bool,
+    config: ServerConfig,
+}
+
+/// Accept loop of the server
+async fn serve(
+    listener: TcpListener,
+    context: ServerContext,
+    mut inject_rx: mpsc::Receiver<MessageType>,
+    mut shutdown: watch::Receiver<bool>,
+    stopped: watch::Sender<bool>,
+) {
+    let ServerContext {
+        clients,
+        settings,
+        store,
+        owns_store,
+        config,
+    } = context;
+    let mut connections = JoinSet::new();
+    let retention = tokio::spawn(enforce_retention(
+        Arc::clone(&store),
+        settings.clone(),
+        shutdown.clone(),
+    ));
+    // Connections are told to shut down only after pending injected messages are queued
+    let (close_connections, connection_shutdown) = watch::channel(None);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, socket_addr)) => {
+                    connections.spawn(handle_connection(
+                        socket,
+                        socket_addr,
+                        Arc::clone(&clients),
+                        Arc::clone(&store),
+                        config.clone(),
+                        settings.clone(),
+                        connection_shutdown.clone(),
+                    ));
+                }
+                Err(e) => log::error!("Failed to accept connection: {}", e),
+            },
+            Some(msg) = inject_rx.recv() => {
+                broadcast(&clients, None, &msg, &connection_shutdown).await
+            }
+            // Reap finished connections, so the set does not grow forever
+            Some(_) = connections.join_next() => (),
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+
+    // The whole shutdown - notices and flushing outboxes - is bounded by one deadline
+    let deadline = Instant::now() + config.shutdown_grace;
+    drop(listener);
+    log::info!("Chat server stopped accepting connections");
+    // Deliver messages injected before the shutdown
+    inject_rx.close();
+    let delivered = tokio::time::timeout_at(deadline, async {
+        while let Some(msg) = inject_rx.recv().await {
+            broadcast(&clients, None, &msg, &connection_shutdown).await;
+        }
+    })
+    .await;
+    if delivered.is_err() {
+        log::warn!("Messages injected before the shutdown not delivered in time");
+    }
+    close_connections.send_replace(Some(deadline));
+
+    // Connections are done by the deadline, give them a moment to clean up before aborting
+    let drained = tokio::time::timeout_at(deadline + Duration::from_secs(1), async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        log::warn!("Connections not closed within grace period, aborting them");
+        connections.shutdown().await;
+    }
+    // Stops on the shutdown request, a purge in progress is finished first
+    let _ = retention.await;
+    if owns_store {
+        store.close().await;
+    }
+    log::info!("Chat server stopped");
+    stopped.send_replace(true);
+}
@@ -0,0 +1,39 @@
+//! `check-db` subcommand of the server - reports stored messages which cannot be decoded
+use std::error::Error;
+
+use library::blob_store::BlobStore;
+use library::db_client::{check_messages, connect_database_pool, DatabaseConfig};
+use library::migrations::{self, latest_version};
+use library::store::StoreBackend;
+
+/// Decodes every stored message, prints those which fail and returns their number
+pub async fn run(config: &DatabaseConfig) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    if config.backend != StoreBackend::Sqlite {
+        return Err(format!("{} store has nothing to check", config.backend).into());
+    }
+    let pool = connect_database_pool(config).await?;
+    let version = migrations::check_version(&pool).await?;
+    if version < latest_version() {
+        pool.close().await;
+        return Err(format!(
+            "Database schema version {} is outdated, run `migrate up` first",
+            version
+        )
+        .into());
+    }
+    let (total, unreadable) = check_messages(&pool, &BlobStore::new(&config.blobs_dir)).await?;
+    pool.close().await;
+
+    for message in &unreadable {
+        println!(
+            "{}  uid {}  at {}: {}",
+            message.id, message.uid, message.timestamp, message.error
+        );
+    }
+    println!(
+        "Checked {} messages, {} cannot be decoded",
+        total,
+        unreadable.len()
+    );
+    Ok(unreadable.len())
+}
@@ -0,0 +1,229 @@
+//! Runtime configuration of the chat server
+//!
+//! `ServerConfig` is fixed for the lifetime of the server. Its `runtime` part (`RuntimeConfig`)
+//! can be replaced while the server runs, e.g. on SIGHUP, without dropping any connection.
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use library::config::{AppConfig, ConfigError};
+use library::db_client::DatabaseConfig;
+use library::images::ImagePolicy;
+use library::mime::UploadPolicy;
+use library::retention::RetentionPolicy;
+use library::DEFAULT_MAX_FRAME_SIZE;
+use log::LevelFilter;
+use uuid::Uuid;
+
+use crate::outbox::SlowConsumerPolicy;
+use crate::rate_limit::RateLimit;
+
+/// Tunable settings of the chat server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// How many messages can wait in a single client's outbox
+    pub outbox_capacity: usize,
+    /// What happens when a client's outbox is full
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// Store backend (and database pool) shared by all connections
+    pub database: DatabaseConfig,
+    /// How long to wait for outboxes to be flushed when shutting down
+    pub shutdown_grace: Duration,
+    /// Settings which can be reloaded while the server runs
+    pub runtime: RuntimeConfig,
+}
+
+/// Settings which can be changed without restarting the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeConfig {
+    /// Largest frame accepted from a client, larger frames disconnect the client
+    pub max_frame_size: usize,
+    /// Messages per client
+    pub rate_limit: RateLimit,
+    /// Chunk requests of downloads per client, delayed rather than refused over the limit
+    pub attachment_rate_limit: RateLimit,
+    /// UIDs refused on authentication, connected clients are disconnected on reload
+    pub banned_uids: HashSet<Uuid>,
+    /// IP addresses refused on connect, connected clients are disconnected on reload
+    pub banned_ips: HashSet<IpAddr>,
+    /// Message of the day, sent after authentication
+    pub motd: Option<String>,
+    /// Size limits and format of received images
+    pub image_policy: ImagePolicy,
+    /// Content types of received files and images
+    pub upload_policy: UploadPolicy,
+    /// Attachment storage of a user without a quota of its own, 0 for unlimited
+    pub user_quota_bytes: u64,
+    /// Which messages are kept, expired ones are deleted every `retention_interval`
+    pub retention: RetentionPolicy,
+    pub retention_interval: Duration,
+    /// Log level (unless overridden by `RUST_LOG`)
+    pub log_level: LevelFilter,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            outbox_capacity: 64,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            database: DatabaseConfig::default(),
+            shutdown_grace: Duration::from_secs(10),
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            rate_limit: RateLimit::UNLIMITED,
+            attachment_rate_limit: RateLimit::UNLIMITED,
+            banned_uids: HashSet::new(),
+            banned_ips: HashSet::new(),
+            motd: None,
+            image_policy: ImagePolicy::default(),
+            upload_policy: UploadPolicy::default(),
+            user_quota_bytes: 0,
+            retention: RetentionPolicy::default(),
+            retention_interval: Duration::from_secs(60 * 60),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Server settings from the layered application config (see `library::config`)
+    pub fn from_app_config(config: &AppConfig) -> Result<Self, ConfigError> {
+        let slow_consumer_policy = config
+            .server
+            .slow_consumer_policy
+            .parse::<SlowConsumerPolicy>()
+            .map_err(|e| {
+                ConfigError::Validation(vec![format!("server.slow_consumer_policy: {}", e)])
+            })?;
+        Ok(ServerConfig {
+            outbox_capacity: config.server.outbox_capacity,
+            slow_consumer_policy,
+            database: config.database_config(),
+            shutdown_grace: Duration::from_secs(config.server.shutdown_grace_secs),
+            runtime: RuntimeConfig::from_app_config(config),
+        })
+    }
+
+    /// Whether settings which need a restart differ
+    pub fn needs_restart(&self, other: &ServerConfig) -> bool {
+        self.outbox_capacity != other.outbox_capacity
+            || self.slow_consumer_policy != other.slow_consumer_policy
+            || self.database != other.database
+            || self.shutdown_grace != other.shutdown_grace
+    }
+}
+
+impl RuntimeConfig {
+    /// Reloadable settings from validated application config
+    pub fn from_app_config(config: &AppConfig) -> Self {
+        RuntimeConfig {
+            max_frame_size: config.limits.max_frame_size,
+            rate_limit: RateLimit {
+                messages_per_sec: config.limits.messages_per_sec,
+                burst: config.limits.message_burst,
+            },
+            attachment_rate_limit: RateLimit {
+                messages_per_sec: config.limits.attachment_requests_per_sec,
+                burst: config.limits.attachment_request_burst,
+            },
+            banned_uids: config
+                .moderation
+                .banned_uids
+                .iter()
+                .filter_map(|uid| Uuid::try_parse(uid).ok())
+                .collect(),
+            banned_ips: config
+                .moderation
+                .banned_ips
+                .iter()
+                .filter_map(|ip| ip.parse().ok())
+                .collect(),
+            motd: config.server.motd.clone(),
+            image_policy: config.image_policy(),
+            upload_policy: config.upload_policy(),
+            user_quota_bytes: config.storage.user_quota_bytes,
+            retention: config.retention_policy(),
+            retention_interval: Duration::from_secs(config.retention.interval_secs),
+            log_level: config.log_level(),
+        }
+    }
+
+    /// Human readable list of settings changed from `self` to `new`
+    pub fn changes(&self, new: &RuntimeConfig) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.max_frame_size != new.max_frame_size {
+            changes.push(format!(
+                "max_frame_size {} -> {}",
+                self.max_frame_size, new.max_frame_size
+            ));
+        }
+        if self.rate_limit != new.rate_limit {
+            changes.push(format!(
+                "rate limit {}/s (burst {}) -> {}/s (burst {})",
+                self.rate_limit.messages_per_sec,
+                self.rate_limit.burst,
+                new.rate_limit.messages_per_sec,
+                new.rate_limit.burst
+            ));
+        }
+        if self.attachment_rate_limit != new.attachment_rate_limit {
+            changes.push(format!(
+                "attachment request limit {}/s (burst {}) -> {}/s (burst {})",
+                self.attachment_rate_limit.messages_per_sec,
+                self.attachment_rate_limit.burst,
+                new.attachment_rate_limit.messages_per_sec,
+                new.attachment_rate_limit.burst
+            ));
+        }
+        let banned = new.banned_uids.difference(&self.banned_uids).count()
+            + new.banned_ips.difference(&self.banned_ips).count();
+        let unbanned = self.banned_uids.difference(&new.banned_uids).count()
+            + self.banned_ips.difference(&new.banned_ips).count();
+        if banned + unbanned > 0 {
+            changes.push(format!("ban list +{} -{}", banned, unbanned));
+        }
+        if self.motd != new.motd {
+            changes.push(format!("motd {:?} -> {:?}", self.motd, new.motd));
+        }
+        if self.image_policy != new.image_policy {
+            changes.push(format!(
+                "image policy {:?} -> {:?}",
+                self.image_policy, new.image_policy
+            ));
+        }
+        if self.upload_policy != new.upload_policy {
+            changes.push(format!(
+                "upload policy {:?} -> {:?}",
+                self.upload_policy, new.upload_policy
+            ));
+        }
+        if self.user_quota_bytes != new.user_quota_bytes {
+            changes.push(format!(
+                "user quota {} -> {} bytes",
+                self.user_quota_bytes, new.user_quota_bytes
+            ));
+        }
+        if self.retention != new.retention || self.retention_interval != new.retention_interval {
+            changes.push(format!(
+                "retention {:?} every {:?} -> {:?} every {:?}",
+                self.retention, self.retention_interval, new.retention, new.retention_interval
+            ));
+        }
+        if self.log_level != new.log_level {
+            changes.push(format!("log level {} -> {}", self.log_level, new.log_level));
+        }
+        changes
+    }
+
+    /// Whether client connecting from `ip` with `uid` is banned
+    pub fn is_banned(&self, ip: IpAddr, uid: Option<Uuid>) -> bool {
+        self.banned_ips.contains(&ip) || uid.is_some_and(|uid| self.banned_uids.contains(&uid))
+    }
+}
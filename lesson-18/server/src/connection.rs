@@ -0,0 +1,510 @@
+//! Handling of a single client connection
+//!
+//! Every client gets its own bounded outbox, drained by a dedicated writer task.
+//! Messages are encoded into a frame only once and the same frame is shared by all recipients.
+//!
+//! On shutdown the client gets a `MessageType::Shutdown` notice, and its outbox is flushed
+//! by the deadline the server set from the grace period in `ServerConfig`. A message being processed
+//! (broadcast and saved to DB) is always finished before the connection reacts to shutdown, only
+//! waiting for a slow recipient is cut short.
+//!
+//! Reloadable settings (`RuntimeConfig`) are read anew for every message, so a config reload
+//! applies to connected clients right away.
+use bytes::Bytes;
+use library::file_name::sanitize_file_name;
+use library::images::{self, ImagePolicy};
+use library::search::{SearchQuery, DEFAULT_SEARCH_LIMIT};
+use library::store::{Store, StoreError};
+use library::{
+    encode_frame, read_from_stream_limited, write_frame_to_stream, AttachmentInfo,
+    AttachmentRequest, AttachmentResponse, DataProcessingError, MessageType, StorageQuota,
+    ATTACHMENT_CHUNK_SIZE,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use library::metrics::{
+    dec_client_count, inc_blocked_upload_count, inc_client_count, inc_msg_count,
+    inc_outbox_drop_count, inc_outbox_lag_count, inc_rate_limited_count,
+    inc_slow_client_disconnect_count,
+};
+
+use crate::config::{RuntimeConfig, ServerConfig};
+use crate::outbox::{Outbox, PushOutcome};
+use crate::rate_limit::RateLimiter;
+
+/// Connected client - its UID (once authenticated) and its outbox
+pub(crate) struct Client {
+    pub(crate) uid: Option<Uuid>,
+    pub(crate) outbox: Arc<Outbox<Bytes>>,
+}
+
+/// All currently connected clients by their socket address
+pub(crate) type Clients = Arc<Mutex<HashMap<SocketAddr, Client>>>;
+
+/// Current reloadable settings, shared by all connections
+pub(crate) type Settings = watch::Receiver<Arc<RuntimeConfig>>;
+
+/// Deadline of the shutdown once the server goes down, connections are done by then
+pub(crate) type ShutdownDeadline = watch::Receiver<Option<Instant>>;
+
+/// Notice sent to clients when the server goes down
+const SHUTDOWN_NOTICE: &str = "Server is shutting down, please reconnect later";
+/// Reply to a banned client before it is disconnected
+pub(crate) const BANNED_NOTICE: &str = "You are banned from this server";
+/// Reply to a message dropped for exceeding the rate limit
+const RATE_LIMITED_NOTICE: &str = "Rate limit exceeded, message was not delivered";
+
+/// Writer task, aborted if the connection task ends (or is aborted) before the writer finishes
+struct WriterTask(JoinHandle<()>);
+
+impl Drop for WriterTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Serves a single client until it disconnects, is disconnected for being too slow
+/// or the server shuts down
+pub(crate) async fn handle_connection(
+    socket: TcpStream,
+    socket_addr: SocketAddr,
+    clients: Clients,
+    store: Arc<dyn Store>,
+    config: ServerConfig,
+    settings: Settings,
+    mut shutdown: ShutdownDeadline,
+) {
+    if settings.borrow().is_banned(socket_addr.ip(), None) {
+        return log::warn!("Refused connection from banned address {}", socket_addr);
+    }
+    let (mut reader, writer) = socket.into_split();
+    let outbox = Arc::new(Outbox::new(
+        config.outbox_capacity,
+        config.slow_consumer_policy,
+    ));
+    clients.lock().unwrap().insert(
+        socket_addr,
+        Client {
+            uid: None,
+            outbox: Arc::clone(&outbox),
+        },
+    );
+    let mut write_task = WriterTask(tokio::spawn(drain_outbox(
+        writer,
+        Arc::clone(&outbox),
+        socket_addr,
+    )));
+    let mut writer_done = false;
+    let mut deadline = None;
+    let mut limiters = Limiters {
+        messages: RateLimiter::new(settings.borrow().rate_limit),
+        attachment_requests: RateLimiter::new(settings.borrow().attachment_rate_limit),
+    };
+
+    loop {
+        let max_frame_size = settings.borrow().max_frame_size;
+        tokio::select! {
+            result = read_from_stream_limited(&mut reader, max_frame_size) => match result {
+                Ok(msg) => {
+                    // Settings may have been reloaded while waiting for the message
+                    let current = Arc::clone(&*settings.borrow());
+                    let connection = Connection {
+                        socket_addr,
+                        clients: &clients,
+                        outbox: &outbox,
+                        store: store.as_ref(),
+                        settings: &current,
+                        shutdown: &shutdown,
+                    };
+                    handle_client_message(msg, &connection, &mut limiters).await
+                }
+                Err(e) => {
+                    log::error!("Client {} disconnected: {}", socket_addr, e);
+                    break;
+                }
+            },
+            _ = &mut write_task.0 => {
+                log::warn!("Disconnecting client {}", socket_addr);
+                writer_done = true;
+                break;
+            }
+            at = shutdown_deadline(&mut shutdown) => {
+                log::info!("Server shutting down, disconnecting client {}", socket_addr);
+                deadline = Some(at);
+                let notice = MessageType::Shutdown(SHUTDOWN_NOTICE.to_string());
+                match encode_frame(&notice) {
+                    Ok(frame) => {
+                        if tokio::time::timeout_at(at, outbox.push(frame)).await.is_err() {
+                            log::warn!("Cannot send shutdown notice to client {}", socket_addr);
+                        }
+                    }
+                    Err(e) => log::error!("Cannot encode message: {}", e),
+                }
+                break;
+            }
+        }
+    }
+
+    let client = clients.lock().unwrap().remove(&socket_addr);
+    if let Some(Client { uid: Some(_), .. }) = client {
+        dec_client_count();
+    }
+    // Let the writer send whatever is left in the outbox
+    outbox.close();
+    let deadline = deadline.unwrap_or_else(|| Instant::now() + config.shutdown_grace);
+    if !writer_done
+        && tokio::time::timeout_at(deadline, &mut write_task.0)
+            .await
+            .is_err()
+    {
+        log::warn!(
+            "Client {} did not receive {} queued messages in time",
+            socket_addr,
+            outbox.len()
+        );
+    }
+}
+
+/// Resolves once shutdown was requested (or the server handle was dropped)
+pub(crate) async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// Resolves to the deadline once the server shuts down (right away if the server is gone)
+pub(crate) async fn shutdown_deadline(shutdown: &mut ShutdownDeadline) -> Instant {
+    match shutdown.wait_for(Option::is_some).await {
+        Ok(deadline) => deadline.unwrap_or_else(Instant::now),
+        Err(_) => Instant::now(),
+    }
+}
+
+/// Rate limiters of a connection
+struct Limiters {
+    messages: RateLimiter,
+    attachment_requests: RateLimiter,
+}
+
+/// What a message handler needs to know about its connection
+struct Connection<'a> {
+    socket_addr: SocketAddr,
+    clients: &'a Clients,
+    outbox: &'a Outbox<Bytes>,
+    store: &'a dyn Store,
+    settings: &'a RuntimeConfig,
+    shutdown: &'a ShutdownDeadline,
+}
+
+/// Processes a single message received from client
+async fn handle_client_message(msg: MessageType, conn: &Connection<'_>, limiters: &mut Limiters) {
+    let socket_addr = conn.socket_addr;
+    inc_msg_count();
+    match &msg {
+        MessageType::Error(e) => log::error!("Error #0: {}", e),
+        MessageType::Auth(client_id) => {
+            log::info!("Authenticating client: {}", client_id);
+            let uid = match Uuid::try_parse(client_id) {
+                Ok(uid) => uid,
+                Err(e) => return log::error!("Wrong UID supplied by client: {}", e),
+            };
+            if conn.settings.is_banned(socket_addr.ip(), Some(uid)) {
+                log::warn!("Refused banned client {} from {}", uid, socket_addr);
+                reply(conn, MessageType::Error(BANNED_NOTICE.to_string())).await;
+                return conn.outbox.close();
+            }
+            if let Err(e) = conn.store.auth_user(uid).await {
+                return log::error!("Error #1: {}", e);
+            }
+            log::info!("Authenticated client: {}", uid);
+            if let Some(client) = conn.clients.lock().unwrap().get_mut(&socket_addr) {
+                if client.uid.replace(uid).is_none() {
+                    inc_client_count();
+                }
+            }
+            // Confirm authentication to the client only
+            reply(conn, msg.clone()).await;
+            if let Some(motd) = &conn.settings.motd {
+                reply(conn, MessageType::Text(motd.clone())).await;
+            }
+        }
+        _ => {
+            let uid = conn
+                .clients
+                .lock()
+                .unwrap()
+                .get(&socket_addr)
+                .and_then(|client| client.uid);
+            let uid = match uid {
+                Some(uid) => uid,
+                None => return log::error!("Client {} is not authenticated", socket_addr),
+            };
+            // Answered to the sender only. A download takes a request per chunk, so these have
+            // a limit of their own and are delayed rather than refused over it.
+            match msg {
+                MessageType::AttachmentRequest(request) => {
+                    let wait = limiters
+                        .attachment_requests
+                        .reserve(conn.settings.attachment_rate_limit);
+                    if !wait.is_zero() {
+                        log::debug!(
+                            "Client {} over attachment request limit, waiting {:?}",
+                            socket_addr,
+                            wait
+                        );
+                        let mut shutdown = conn.shutdown.clone();
+                        tokio::select! {
+                            _ = tokio::time::sleep(wait) => (),
+                            _ = shutdown_deadline(&mut shutdown) => return,
+                        }
+                    }
+                    let response = attachment_response(conn, &uid.to_string(), request).await;
+                    return reply(conn, response).await;
+                }
+                MessageType::AttachmentResponse(_) => {
+                    return log::warn!("Client {} sent attachment response", socket_addr)
+                }
+                _ => (),
+            }
+            if !limiters.messages.check(conn.settings.rate_limit) {
+                log::warn!(
+                    "Client {} exceeded rate limit, message dropped",
+                    socket_addr
+                );
+                inc_rate_limited_count();
+                return reply(conn, MessageType::Error(RATE_LIMITED_NOTICE.to_string())).await;
+            }
+            // Answered to the sender only as well, but a search is rate limited like any message
+            match msg {
+                MessageType::SearchRequest(query) => {
+                    return reply(conn, search_response(conn, query).await).await;
+                }
+                MessageType::SearchResponse(_) => {
+                    return log::warn!("Client {} sent search response", socket_addr)
+                }
+                _ => (),
+            }
+            if let MessageType::File(info, data) | MessageType::Image(info, data) = &msg {
+                if let Err(e) = info.verify(data) {
+                    log::warn!(
+                        "Client {} sent attachment not matching its descriptor: {}",
+                        socket_addr,
+                        e
+                    );
+                    return reply(conn, MessageType::Error(format!("Error: {}", e))).await;
+                }
+                // Descriptor matches the content, so its type is the sniffed one
+                if let Err(e) = conn.settings.upload_policy.check(&info.mime) {
+                    log::warn!("Client {} sent {:?} blocked: {}", socket_addr, info.name, e);
+                    inc_blocked_upload_count();
+                    let error = DataProcessingError::BlockedUpload(info.name.clone(), e);
+                    return reply(conn, MessageType::Error(error.to_string())).await;
+                }
+                // Other clients write received files under the sent name
+                if let Err(e) = sanitize_file_name(&info.name) {
+                    log::warn!(
+                        "Client {} sent file named {:?}: {}",
+                        socket_addr,
+                        info.name,
+                        e
+                    );
+                    let error = DataProcessingError::RejectedFileName(info.name.clone(), e);
+                    return reply(conn, MessageType::Error(error.to_string())).await;
+                }
+            }
+            // Images are delivered and stored as the image policy requires
+            let msg = match msg {
+                MessageType::Image(info, data) => {
+                    match apply_image_policy(info, data, conn.settings.image_policy.clone()).await {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            log::warn!("Client {} sent image refused: {}", socket_addr, e);
+                            return reply(conn, MessageType::Error(format!("Error: {}", e))).await;
+                        }
+                    }
+                }
+                msg => msg,
+            };
+            // Saved first, an attachment over the quota of its sender is not broadcast.
+            // Counted as stored, after the image policy changed the size.
+            let saved = conn
+                .store
+                .save_message_within_quota(&uid.to_string(), &msg, conn.settings.user_quota_bytes)
+                .await;
+            match saved {
+                Ok(_) => (),
+                Err(e @ StoreError::QuotaExceeded(..)) => {
+                    log::warn!("Client {} sent attachment refused: {}", socket_addr, e);
+                    return reply(conn, MessageType::Error(format!("Error: {}", e))).await;
+                }
+                Err(e) => log::error!("Cannot save message: {}", e),
+            }
+            broadcast(conn.clients, Some(socket_addr), &msg, conn.shutdown).await;
+        }
+    }
+}
+
+/// Image message complying with the policy, decoded and re-encoded off the async runtime
+///
+/// A converted image gets a new descriptor, named with the extension of its new format.
+async fn apply_image_policy(
+    info: AttachmentInfo,
+    data: Vec<u8>,
+    policy: ImagePolicy,
+) -> Result<MessageType, DataProcessingError> {
+    tokio::task::spawn_blocking(move || match policy.apply(&data)? {
+        Cow::Borrowed(_) => Ok(MessageType::Image(info, data)),
+        Cow::Owned(processed) => {
+            let name = images::with_format_extension(&info.name, &processed);
+            let info = AttachmentInfo::new(&name, &processed, info.caption);
+            Ok(MessageType::Image(info, processed))
+        }
+    })
+    .await
+    .map_err(|e| DataProcessingError::Io(e.into()))?
+}
+
+/// Storage used by attachments of the user and its quota
+async fn storage_quota(
+    conn: &Connection<'_>,
+    uid: &str,
+) -> Result<StorageQuota, DataProcessingError> {
+    match conn.store.user(uid).await {
+        Ok(Some(user)) => Ok(StorageQuota::of(&user, conn.settings.user_quota_bytes)),
+        Ok(None) => Err(DataProcessingError::NotFound(uid.to_string())),
+        Err(e) => {
+            log::error!("Cannot read storage of user {}: {}", uid, e);
+            Err(DataProcessingError::NotFound(uid.to_string()))
+        }
+    }
+}
+
+/// Answers a request of user `uid` for the list of attachments, a chunk of one, or its quota
+async fn attachment_response(
+    conn: &Connection<'_>,
+    uid: &str,
+    request: AttachmentRequest,
+) -> MessageType {
+    let store = conn.store;
+    let response = match request {
+        AttachmentRequest::Quota => {
+            return match storage_quota(conn, uid).await {
+                Ok(quota) => MessageType::AttachmentResponse(AttachmentResponse::Quota(quota)),
+                Err(e) => MessageType::Error(format!("Error: {}", e)),
+            }
+        }
+        AttachmentRequest::List => store.attachments().await.map(AttachmentResponse::List),
+        AttachmentRequest::Download { id, offset } => store
+            .read_attachment(&id, offset, ATTACHMENT_CHUNK_SIZE)
+            .await
+            .map(|chunk| chunk.map_or(AttachmentResponse::NotFound(id), AttachmentResponse::Chunk)),
+    };
+    match response {
+        Ok(response) => MessageType::AttachmentResponse(response),
+        Err(e) => {
+            log::error!("Cannot read attachments: {}", e);
+            MessageType::Error("Cannot read attachments".to_string())
+        }
+    }
+}
+
+/// Answers a search of a client, with at most `DEFAULT_SEARCH_LIMIT` messages
+async fn search_response(conn: &Connection<'_>, mut query: SearchQuery) -> MessageType {
+    query.limit = query.limit.min(DEFAULT_SEARCH_LIMIT);
+    match conn.store.search(&query).await {
+        Ok(hits) => MessageType::SearchResponse(hits),
+        Err(e) => {
+            log::error!("Cannot search messages: {}", e);
+            MessageType::Error("Cannot search messages".to_string())
+        }
+    }
+}
+
+/// Queues a message to the client itself
+async fn reply(conn: &Connection<'_>, msg: MessageType) {
+    match encode_frame(&msg) {
+        Ok(frame) => push(conn.outbox, frame, conn.socket_addr, conn.shutdown).await,
+        Err(e) => log::error!("Cannot encode message: {}", e),
+    }
+}
+
+/// Encodes message once and queues the frame into outboxes of all authenticated clients,
+/// except the sender (if any)
+pub(crate) async fn broadcast(
+    clients: &Clients,
+    sender: Option<SocketAddr>,
+    msg: &MessageType,
+    shutdown: &ShutdownDeadline,
+) {
+    let frame = match encode_frame(msg) {
+        Ok(frame) => frame,
+        Err(e) => return log::error!("Cannot encode message: {}", e),
+    };
+    let recipients: Vec<(SocketAddr, Arc<Outbox<Bytes>>)> = clients
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(addr, client)| Some(**addr) != sender && client.uid.is_some())
+        .map(|(addr, client)| (*addr, Arc::clone(&client.outbox)))
+        .collect();
+
+    for (addr, outbox) in recipients {
+        push(&outbox, frame.clone(), addr, shutdown).await;
+    }
+}
+
+/// Queues a frame into an outbox, giving up when the server shuts down meanwhile -
+/// a blocking outbox of a slow client would hold up the shutdown
+async fn push(outbox: &Outbox<Bytes>, frame: Bytes, addr: SocketAddr, shutdown: &ShutdownDeadline) {
+    let mut shutdown = shutdown.clone();
+    tokio::select! {
+        biased;
+        outcome = outbox.push(frame) => report_push(outcome, addr),
+        _ = shutdown_deadline(&mut shutdown) => {
+            log::warn!("Server shutting down, message to client {} dropped", addr)
+        }
+    }
+}
+
+/// Reports outcome of pushing into an outbox to logs and metrics
+pub(crate) fn report_push(outcome: PushOutcome, addr: SocketAddr) {
+    if outcome.lagged() {
+        inc_outbox_lag_count();
+    }
+    match outcome {
+        PushOutcome::DroppedOldest => {
+            log::warn!("Client {} is lagging, dropped oldest message", addr);
+            inc_outbox_drop_count();
+        }
+        PushOutcome::Disconnected => {
+            log::warn!("Client {} is lagging, disconnecting", addr);
+            inc_slow_client_disconnect_count();
+        }
+        PushOutcome::QueuedAfterWait => {
+            log::warn!("Client {} is lagging, sender was blocked", addr)
+        }
+        PushOutcome::Queued | PushOutcome::Closed => (),
+    }
+}
+
+/// Writer task of a client - sends queued messages until the outbox is closed
+async fn drain_outbox(
+    mut writer: OwnedWriteHalf,
+    outbox: Arc<Outbox<Bytes>>,
+    socket_addr: SocketAddr,
+) {
+    while let Some(frame) = outbox.pop().await {
+        if let Err(e) = write_frame_to_stream(&mut writer, &frame).await {
+            log::error!("Disconnecting client {}: {}", socket_addr, e);
+            outbox.close();
+            break;
+        }
+    }
+}
@@ -2,161 +2,263 @@
 //!
 //! # Usage
 //!
+//! ```bash
+//! cargo run --bin server -- [--host <hostname>] [--port <port>] [--config <file>] [--set <section>.<key>=<value>]
+//! cargo run --bin server -- --host :: --port 11111
+//! cargo run --bin server -- --help
+//! cargo run --bin server -- migrate status|up|down [--to <version>]
+//! cargo run --bin server -- check-db
+//! cargo run --bin server -- export <dir or file.tar> [--user <uid>]... [--since <time>] [--until <time>]
+//! cargo run --bin server -- import <dir or file.tar>
+//! cargo run --bin server -- backup <dir>
+//! cargo run --bin server -- restore <dir> [--check]
 //! ```
-//! cargo run --bin server <hostname> <port>
-//! ```
 //!
+//! Settings are read from the layered config, see `library::config`; named flags take precedence.
+//! Host can be a hostname, IPv4 or IPv6 address.
+//!
+//! The server can also be embedded into another application (like the webapp) using `ChatServer`,
+//! which runs on the caller's tokio runtime and returns a `ChatServerHandle`.
+//!
+//! Standalone server shuts down gracefully on ctrl-c or SIGTERM. On SIGHUP it reloads the config
+//! and applies settings which do not need a restart (rate limits, max frame size, ban lists, MOTD
+//! and log level) to all connections.
 //!
-use library::db_client::{auth_client, save_message, setup_database_pool};
-use library::{get_addr, read_from_stream, write_to_stream, MessageType};
-use std::collections::HashMap;
-use std::env;
+//! Pending database migrations are applied at startup, see `library::migrations`. The `migrate`
+//! subcommand shows the schema status or moves it up/down without starting the server.
+//! The `check-db` subcommand lists stored messages which cannot be decoded (exit code 1 if any).
+//! The `export` and `import` subcommands move users, messages and attachments between databases,
+//! see `library::archive`.
+//! The `backup` subcommand copies the database and blob store while the server runs, `restore`
+//! (with the server stopped) swaps a backup in after checking its integrity, see `library::backup`.
+//!
+use clap::{Parser, Subcommand};
+use library::address::{resolve, with_host_port};
+use library::config::{AppConfig, ConfigArgs};
+use log::LevelFilter;
 use std::error::Error;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use tokio::net::TcpListener;
-use tokio::sync::{broadcast, Mutex as TokioMutex};
-use uuid::Uuid;
+use std::path::PathBuf;
 
-use library::metrics::{dec_client_count, inc_client_count, inc_msg_count};
+pub mod archive;
+pub mod backup;
+pub mod chat_server;
+pub mod check_db;
+pub mod config;
+mod connection;
+pub mod counters;
+pub mod migrate;
+pub mod outbox;
+pub mod rate_limit;
+mod retention;
+mod test_chat_server;
+mod test_outbox;
+mod test_rate_limit;
 
-#[tokio::main]
-pub async fn server_main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let _ = simple_logger::SimpleLogger::new().env().init();
-    let _ = dotenvy::dotenv();
-
-    let (addr, _) = get_addr(env::args().collect()).unwrap();
-    let listener = TcpListener::bind(addr).await?;
-    let (tx, _rx) = broadcast::channel(10);
-    let clients: Arc<Mutex<HashMap<SocketAddr, Uuid>>> = Arc::new(Mutex::new(HashMap::new()));
-
-    loop {
-        let clients = Arc::clone(&clients);
-        let (socket, socket_addr) = listener.accept().await?;
-        let tx = tx.clone();
-        let mut rx = tx.subscribe();
+pub use chat_server::{ChatServer, ChatServerHandle, ConfigReloader, ConnectedClient};
+use config::ServerConfig;
 
-        tokio::spawn(async move {
-            let (mut reader, writer) = socket.into_split();
-            let db_pool = setup_database_pool().await;
-            match &db_pool {
-                Ok(pool) => {
-                    log::info!("Connected to database: {:?}", pool);
-                }
-                Err(e) => {
-                    log::error!("Failed to connect to database: {}", e);
-                    return;
-                }
-            };
-            let db_pool = db_pool.unwrap();
-            let writer_mutex = Arc::new(TokioMutex::new(writer));
-            loop {
-                let clients = Arc::clone(&clients);
-                let tx = tx.clone();
-                let db_pool = db_pool.clone();
-                let writer_mutex = writer_mutex.clone();
-                tokio::select! {
-                    result = read_from_stream(&mut reader) => tokio::spawn(async move {
-                        match &result {
-                            Ok(msg) => {
-                                inc_msg_count();
-                                match &msg {
-                                    MessageType::Error(e) => {
-                                        return log::error!("Error #0: {}", e)
-                                    }
-                                    MessageType::Auth(client_id) => {
-                                        log::info!("Authenticating client: {}", client_id);
-                                        let uid = Uuid::try_parse(client_id);
-                                        if uid.is_err() {
-                                            return log::error!("Wrong UID supplied by client: {}", uid.unwrap_err());
-                                        } else {
-                                            match auth_client(&db_pool, uid.unwrap()).await {
-                                                Ok(uid) => {
-                                                    log::info!("Authenticated client: {}", uid.to_string());
+/// Chat server - broadcasts messages of every client to all the others
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Hostname, IPv4 or IPv6 address to listen on [default: from server.address]
+    #[arg(long)]
+    host: Option<String>,
+    /// Port to listen on [default: from server.address]
+    #[arg(long)]
+    port: Option<u16>,
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-                                                    let msg = result.unwrap();
-                                                    let addr = socket_addr.to_string().clone();
-                                                    let broadcast_msg = (addr, msg);
-                                                    if tx.send(broadcast_msg).is_err() {
-                                                        //break;
-                                                        unimplemented!()
-                                                    }
-                                                    clients.lock().unwrap().insert(socket_addr, Uuid::try_parse(&uid.to_string()).unwrap());
-                                                    inc_client_count();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show or change the database schema version, without starting the server
+    Migrate {
+        #[command(subcommand)]
+        action: migrate::MigrateAction,
+    },
+    /// Report stored messages which cannot be decoded, without starting the server
+    CheckDb,
+    /// Write users, messages and attachments to an archive, without starting the server
+    Export(archive::ExportArgs),
+    /// Read an archive written by `export`, skipping messages stored already
+    Import {
+        /// Archive to read, a `.tar` file or a directory
+        path: PathBuf,
+    },
+    /// Copy the database and attachment contents into a new directory, the server may keep running
+    Backup {
+        /// Directory to create
+        dir: PathBuf,
+    },
+    /// Replace the database and attachment contents with a backup, with the server stopped
+    Restore {
+        /// Directory written by `backup`
+        dir: PathBuf,
+        /// Only check integrity of the backup, change nothing
+        #[arg(long)]
+        check: bool,
+    },
+}
 
-                                                },
-                                                Err(e) => {
-                                                    return log::error!("Error #1: {}", e)
-                                                }
-                                            };
-                                        }
-                                    }
-                                    _ => {
-                                        let msg = result.unwrap();
-                                        let addr = socket_addr.to_string().clone();
-                                        let broadcast_msg = (addr, msg.clone());
+/// Prints error and exits with the same code as for invalid arguments
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(2);
+}
 
-                                        if tx.send(broadcast_msg).is_err() {
-                                            //break;
-                                            unimplemented!()
-                                        } else {
-                                            let uid = &clients.lock().unwrap().get(&socket_addr).unwrap().clone();
-                                            // Save message to DB
-                                            match save_message(&db_pool, uid.to_string(), &msg).await.is_err() {
-                                                false => (),
-                                                true => {
-                                                    log::error!("Cannot save message to DB");
-                                                }
-                                            };
+/// Standalone server - reads layered config (file, env, command line), runs until stopped
+#[tokio::main]
+pub async fn server_main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let _ = dotenvy::dotenv();
+    let args = Args::parse();
+    let app_config = AppConfig::load_or_exit(&args.config);
+    let address = with_host_port(&app_config.server.address, args.host.as_deref(), args.port)
+        .unwrap_or_else(|e| exit_with(e));
+    init_logger(app_config.log_level());
+    match &args.command {
+        Some(Command::Migrate { action }) => {
+            if let Err(e) = migrate::run(action, &app_config.database_config()).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::CheckDb) => match check_db::run(&app_config.database_config()).await {
+            Ok(0) => return Ok(()),
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Command::Export(export)) => {
+            if let Err(e) = archive::export(export, &app_config.database_config()).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Import { path }) => {
+            if let Err(e) = archive::import(path, &app_config.database_config()).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Backup { dir }) => {
+            if let Err(e) = backup::backup(dir, &app_config.database_config()).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Restore { dir, check }) => {
+            if let Err(e) = backup::restore(dir, *check, &app_config.database_config()).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    let config = ServerConfig::from_app_config(&app_config).unwrap_or_else(|e| exit_with(e));
+    log::info!("Server config: {:?}", config);
 
-                                        }
+    if app_config.metrics.enabled {
+        let metrics_addr = app_config.metrics.address.parse()?;
+        tokio::spawn(async move {
+            if let Err(e) = counters::serve_metrics(metrics_addr).await {
+                log::error!("Cannot serve metrics: {}", e);
+            }
+        });
+    }
 
-                                    },
-                                }
-                            },
-                            Err(e) => {
-                                dec_client_count();
-                                return log::error!("Error #2: {}", e)
-                            }
-                        };
-                    }),
-                    result = rx.recv() => tokio::spawn(async move {
-                        let received = match result {
-                            Ok(received) => received,
-                            Err(e) => {
-                                eprintln!("Failed to receive broadcast message: {}", e);
-                                //continue;
-                                unimplemented!()
-                            }
-                        };
+    let addr = resolve(&address).await.unwrap_or_else(|e| exit_with(e));
+    let handle = match ChatServer::new(addr).config(config.clone()).start().await {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Cannot start server: {}", e);
+            std::process::exit(1);
+        }
+    };
+    tokio::spawn(reload_on_sighup(handle.reloader(), args.config, config));
+    tokio::select! {
+        _ = handle.stopped() => (),
+        _ = shutdown_signal() => {
+            log::info!("Shutdown signal received");
+            handle.shutdown().await;
+        }
+    }
+    Ok(())
+}
 
-                        let (recv_socket_addr, msg) = received;
+/// Logs with given level, which can be changed by a config reload. `RUST_LOG` takes precedence.
+pub fn init_logger(level: LevelFilter) {
+    let _ = simple_logger::SimpleLogger::new()
+        .with_level(LevelFilter::Trace)
+        .env()
+        .init();
+    if std::env::var("RUST_LOG").is_err() {
+        log::set_max_level(level);
+    }
+}
 
-                        let send_msg = match &msg {
-                            MessageType::Auth(s) => {
-                                log::info!("Authenticating client: {}", s);
-                                recv_socket_addr.as_str() == socket_addr.to_string()
-                            }
-                            _ => {
-                                recv_socket_addr.as_str() != socket_addr.to_string()
-                            }
-                        };
+/// Reloads config on every SIGHUP (on unix) and applies reloadable settings to the running server.
+/// Invalid config is reported and ignored, the server keeps its current settings.
+pub async fn reload_on_sighup(
+    reloader: ConfigReloader,
+    args: ConfigArgs,
+    mut config: ServerConfig,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => return log::error!("Cannot listen for SIGHUP: {}", e),
+        };
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading config");
+            let new_config = AppConfig::load(&args)
+                .and_then(|app_config| ServerConfig::from_app_config(&app_config));
+            match new_config {
+                Ok(new_config) => {
+                    if config.needs_restart(&new_config) {
+                        log::warn!("Some changed settings take effect only after restart");
+                    }
+                    reloader.reload(new_config.runtime.clone()).await;
+                    config = new_config;
+                }
+                Err(e) => log::error!("Config not reloaded: {}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (reloader, args, &mut config);
+}
 
-                        if send_msg {
-                            let mut writer = writer_mutex.lock().await;
-                            let result = write_to_stream(&mut writer, &msg).await;
-                            drop(writer);
-                            match result {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    log::error!("Disconnecting client: {}", e);
-                                },
-                            }
-                        }
-                    }),
-                };
+/// Resolves on ctrl-c (or SIGTERM on unix)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
             }
-        });
-        //clients = clients.clone();
+            Err(e) => {
+                log::error!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
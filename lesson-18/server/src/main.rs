@@ -3,10 +3,11 @@
 //! # Usage
 //!
 //! ```
-//! cargo run --bin server <hostname> <port>
+//! cargo run --bin server -- --host <hostname> --port <port> [--config chat.toml]
 //! ```
 //!
-//!
+//! Subcommands work on the configured database instead of serving:
+//! `migrate`, `check-db`, `export`, `import`, `backup` and `restore`, see `--help`.
 use server::server_main;
 use std::error::Error;
 
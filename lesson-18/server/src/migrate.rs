@@ -0,0 +1,69 @@
+//! `migrate` subcommand of the server - inspect and change the database schema version
+use std::error::Error;
+
+use clap::Subcommand;
+use library::blob_store::BlobStore;
+use library::db_client::{connect_database_pool, DatabaseConfig};
+use library::migrations::{self, latest_version};
+use library::store::StoreBackend;
+
+/// What to do with the schema
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum MigrateAction {
+    /// Show applied and pending migrations
+    Status,
+    /// Apply all pending migrations
+    Up,
+    /// Revert the last applied migration, or all migrations newer than `--to`
+    Down {
+        /// Version to revert to, 0 reverts everything
+        #[arg(long)]
+        to: Option<i64>,
+    },
+}
+
+/// Runs the migrate subcommand against the configured database
+pub async fn run(
+    action: &MigrateAction,
+    config: &DatabaseConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if config.backend != StoreBackend::Sqlite {
+        return Err(format!("{} store has no schema to migrate", config.backend).into());
+    }
+    let pool = connect_database_pool(config).await?;
+    let blobs = BlobStore::new(&config.blobs_dir);
+    match action {
+        MigrateAction::Status => {
+            for migration in migrations::status(&pool).await? {
+                println!(
+                    "{:>6}  {:<30} {}",
+                    migration.version,
+                    migration.description,
+                    migration
+                        .installed_on
+                        .map(|at| format!("applied {}", at))
+                        .unwrap_or_else(|| "pending".to_string())
+                );
+            }
+            println!(
+                "Database schema version {}, this binary supports up to {}",
+                migrations::schema_version(&pool).await?,
+                latest_version()
+            );
+        }
+        MigrateAction::Up => {
+            let version = migrations::migrate_up(&pool, &blobs).await?;
+            println!("Database schema version {}", version);
+        }
+        MigrateAction::Down { to } => {
+            let target = match to {
+                Some(to) => *to,
+                None => migrations::previous_version(&pool).await?,
+            };
+            let version = migrations::migrate_down(&pool, target, &blobs).await?;
+            println!("Database schema version {}", version);
+        }
+    }
+    pool.close().await;
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! Bounded per-client outbound queue
+//!
+//! Every connected client owns one `Outbox`. The connection reading side pushes messages into
+//! the outboxes of all recipients, while a dedicated writer task drains its own outbox into the socket.
+//! When an outbox is full, the configured `SlowConsumerPolicy` decides what happens.
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+/// What to do when a client does not read its messages fast enough and its outbox is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room for the new one
+    #[default]
+    DropOldest,
+    /// Disconnect the slow client
+    Disconnect,
+    /// Wait until the client catches up (slows down the sender)
+    Block,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "drop_oldest" | "drop-oldest" | "dropoldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            "block" => Ok(SlowConsumerPolicy::Block),
+            other => Err(format!("Unknown slow consumer policy: {}", other)),
+        }
+    }
+}
+
+impl Display for SlowConsumerPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlowConsumerPolicy::DropOldest => write!(f, "drop_oldest"),
+            SlowConsumerPolicy::Disconnect => write!(f, "disconnect"),
+            SlowConsumerPolicy::Block => write!(f, "block"),
+        }
+    }
+}
+
+/// Result of pushing a message into an outbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// Message was queued without any trouble
+    Queued,
+    /// Outbox was full, message was queued after waiting for free space
+    QueuedAfterWait,
+    /// Outbox was full, the oldest message was dropped and the new one queued
+    DroppedOldest,
+    /// Outbox was full, the client is being disconnected and the message was discarded
+    Disconnected,
+    /// Outbox is already closed, the message was discarded
+    Closed,
+}
+
+impl PushOutcome {
+    /// True if the outbox was full when the message arrived
+    pub fn lagged(&self) -> bool {
+        matches!(
+            self,
+            PushOutcome::QueuedAfterWait | PushOutcome::DroppedOldest | PushOutcome::Disconnected
+        )
+    }
+}
+
+/// Bounded queue of outbound messages of a single client
+pub struct Outbox<T> {
+    queue: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    closed: AtomicBool,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl<T> Outbox<T> {
+    /// Creates a new outbox; capacity is at least 1
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        let capacity = capacity.max(1);
+        Outbox {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            policy,
+            closed: AtomicBool::new(false),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// Queue a message for the client, applying the slow consumer policy if the outbox is full
+    pub async fn push(&self, item: T) -> PushOutcome {
+        let mut waited = false;
+        let mut item = Some(item);
+        loop {
+            let notified = self.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if self.is_closed() {
+                    return PushOutcome::Closed;
+                }
+                if queue.len() < self.capacity {
+                    queue.push_back(item.take().expect("item is pushed only once"));
+                    drop(queue);
+                    self.readable.notify_one();
+                    return match waited {
+                        true => PushOutcome::QueuedAfterWait,
+                        false => PushOutcome::Queued,
+                    };
+                }
+                match self.policy {
+                    SlowConsumerPolicy::DropOldest => {
+                        queue.pop_front();
+                        queue.push_back(item.take().expect("item is pushed only once"));
+                        drop(queue);
+                        self.readable.notify_one();
+                        return PushOutcome::DroppedOldest;
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        queue.clear();
+                        drop(queue);
+                        self.close();
+                        return PushOutcome::Disconnected;
+                    }
+                    SlowConsumerPolicy::Block => waited = true,
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Take the next message; waits until one is available.
+    /// Returns `None` once the outbox is closed and all queued messages were taken.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let notified = self.readable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(item) = queue.pop_front() {
+                    drop(queue);
+                    self.writable.notify_one();
+                    return Some(item);
+                }
+                if self.is_closed() {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Close the outbox - no new messages are accepted, already queued ones can still be taken
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.readable.notify_waiters();
+        self.writable.notify_waiters();
+    }
+
+    /// True once the outbox was closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// True if no messages are waiting to be sent
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,89 @@
+//! Per-client message rate limiting
+//!
+//! A token bucket - every message takes one token, tokens are refilled at `messages_per_sec`
+//! up to `burst`. Limits are passed on every check, so they can change while the client is connected.
+use std::time::{Duration, Instant};
+
+/// Rate limit of a single client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Messages per second on average, 0 means unlimited
+    pub messages_per_sec: f64,
+    /// Messages that can be sent at once
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// No limit at all
+    pub const UNLIMITED: RateLimit = RateLimit {
+        messages_per_sec: 0.0,
+        burst: 1,
+    };
+
+    /// Whether messages are not limited at all
+    pub fn is_unlimited(&self) -> bool {
+        self.messages_per_sec <= 0.0
+    }
+}
+
+/// Token bucket of a single client
+#[derive(Debug)]
+pub struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Full bucket, a client can send `burst` messages right away
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            tokens: f64::from(limit.burst),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token for one message, returns false if the client is over the limit
+    pub fn check(&mut self, limit: RateLimit) -> bool {
+        self.check_at(limit, Instant::now())
+    }
+
+    pub(crate) fn check_at(&mut self, limit: RateLimit, now: Instant) -> bool {
+        if limit.is_unlimited() {
+            return true;
+        }
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes a token for one message even if the client is over the limit,
+    /// returns how long to wait until the token is refilled
+    pub fn reserve(&mut self, limit: RateLimit) -> Duration {
+        self.reserve_at(limit, Instant::now())
+    }
+
+    pub(crate) fn reserve_at(&mut self, limit: RateLimit, now: Instant) -> Duration {
+        if limit.is_unlimited() {
+            return Duration::ZERO;
+        }
+        self.refill(limit, now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / limit.messages_per_sec)
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * limit.messages_per_sec).min(f64::from(limit.burst));
+    }
+}
@@ -0,0 +1,51 @@
+//! Background task deleting messages the retention policy no longer keeps
+//!
+//! Runs when the server starts, every `RuntimeConfig::retention_interval` and whenever the config
+//! is reloaded, always with the current policy. Nothing is done while the policy keeps everything.
+use std::sync::Arc;
+
+use library::get_timestamp;
+use library::metrics::add_purged;
+use library::retention::RetentionPolicy;
+use library::store::Store;
+use tokio::sync::watch;
+
+use crate::connection::{shutdown_requested, Settings};
+
+/// Enforces the policy until the server shuts down
+pub(crate) async fn enforce_retention(
+    store: Arc<dyn Store>,
+    mut settings: Settings,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let (policy, interval) = {
+            let settings = settings.borrow_and_update();
+            (settings.retention, settings.retention_interval)
+        };
+        if policy.is_enabled() {
+            purge(store.as_ref(), &policy).await;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => (),
+            Ok(_) = settings.changed() => (),
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+}
+
+/// Deletes expired messages once, reports what was deleted in the log and metrics
+async fn purge(store: &dyn Store, policy: &RetentionPolicy) {
+    let now = get_timestamp().parse().unwrap_or_default();
+    match store.purge_expired(policy, now).await {
+        Ok(report) => {
+            add_purged(report.messages, report.attachments, report.bytes);
+            if report.messages > 0 {
+                log::info!("Retention policy deleted {}", report);
+            } else {
+                log::debug!("Retention policy deleted nothing");
+            }
+        }
+        Err(e) => log::error!("Cannot delete expired messages: {}", e),
+    }
+}
@@ -0,0 +1,325 @@
+#![cfg(test)]
+use std::sync::Arc;
+use std::time::Duration;
+
+use library::retention::{RetentionPolicy, RetentionRule};
+use library::search::SearchQuery;
+use library::store::{MemoryStore, MessageStore};
+use library::{
+    read_from_stream, write_to_stream, AttachmentRequest, AttachmentResponse, MessageType,
+    StorageQuota, ATTACHMENT_CHUNK_SIZE,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::config::{RuntimeConfig, ServerConfig};
+use crate::outbox::SlowConsumerPolicy;
+use crate::rate_limit::RateLimit;
+use crate::{ChatServer, ChatServerHandle};
+
+async fn connect(addr: std::net::SocketAddr) -> (OwnedReadHalf, OwnedWriteHalf) {
+    connect_as(addr, Uuid::new_v4()).await
+}
+
+async fn connect_as(addr: std::net::SocketAddr, uid: Uuid) -> (OwnedReadHalf, OwnedWriteHalf) {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    let auth = MessageType::Auth(uid.to_string());
+    write_to_stream(&mut writer, &auth).await.unwrap();
+    assert_eq!(read_from_stream(&mut reader).await.unwrap(), auth);
+    (reader, writer)
+}
+
+async fn start_server() -> ChatServerHandle {
+    ChatServer::new("127.0.0.1:0".parse().unwrap())
+        .store(Arc::new(MemoryStore::default()))
+        .start()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_chat_server() {
+    let handle = start_server().await;
+
+    let (mut reader_a, mut writer_a) = connect(handle.local_addr()).await;
+    let (mut reader_b, _writer_b) = connect(handle.local_addr()).await;
+    assert_eq!(handle.connected_clients().len(), 2);
+
+    // Message is broadcast to everyone except the sender
+    let msg = MessageType::Text("Hello".to_string());
+    write_to_stream(&mut writer_a, &msg).await.unwrap();
+    assert_eq!(read_from_stream(&mut reader_b).await.unwrap(), msg);
+
+    // Injected message is broadcast to everyone
+    let injected = MessageType::Text("Hello from server".to_string());
+    handle.inject(injected.clone()).await.unwrap();
+    assert_eq!(read_from_stream(&mut reader_a).await.unwrap(), injected);
+    assert_eq!(read_from_stream(&mut reader_b).await.unwrap(), injected);
+
+    // File which could escape the files dir of other clients is refused
+    let evil = MessageType::file("../../.bashrc", b"rm -rf ~".to_vec());
+    write_to_stream(&mut writer_a, &evil).await.unwrap();
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Error(e) if e.contains("Rejected file name")
+    ));
+
+    // Image which cannot be decoded is refused as well
+    let broken = MessageType::image("image.png", b"not an image".to_vec());
+    write_to_stream(&mut writer_a, &broken).await.unwrap();
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Error(e) if e.contains("Cannot process image")
+    ));
+
+    // So is content not matching its descriptor
+    let tampered = match MessageType::file("notes.txt", b"notes".to_vec()) {
+        MessageType::File(info, _) => MessageType::File(info, b"other notes".to_vec()),
+        _ => unreachable!(),
+    };
+    write_to_stream(&mut writer_a, &tampered).await.unwrap();
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Error(e) if e.contains("does not match its checksum")
+    ));
+
+    // Executables are blocked by default, whatever their name
+    let program = MessageType::file("cat.jpg", b"\x7fELF\x02\x01\x01".to_vec());
+    write_to_stream(&mut writer_a, &program).await.unwrap();
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Error(e) if e.contains("blocked: type application/x-executable is denied")
+    ));
+
+    // Search is answered to the asking client only
+    let search = MessageType::SearchRequest(SearchQuery::new("hel*"));
+    write_to_stream(&mut writer_a, &search).await.unwrap();
+    match read_from_stream(&mut reader_a).await.unwrap() {
+        MessageType::SearchResponse(hits) => {
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].highlighted("[", "]"), "[Hello]");
+        }
+        other => panic!("Unexpected response {:?}", other),
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+        .await
+        .unwrap();
+    assert!(handle.connected_clients().is_empty());
+    // Clients are notified, then disconnected
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Shutdown(_)
+    ));
+    assert!(read_from_stream(&mut reader_a).await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_deadline() {
+    let config = ServerConfig {
+        outbox_capacity: 1,
+        slow_consumer_policy: SlowConsumerPolicy::Block,
+        shutdown_grace: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let handle = ChatServer::new("127.0.0.1:0".parse().unwrap())
+        .store(Arc::new(MemoryStore::default()))
+        .config(config)
+        .start()
+        .await
+        .unwrap();
+
+    // Clients which never read end up blocking each other's messages
+    let (_reader_a, writer_a) = connect(handle.local_addr()).await;
+    let (_reader_b, writer_b) = connect(handle.local_addr()).await;
+    let msg = MessageType::Text("x".repeat(1024 * 1024));
+    for mut writer in [writer_a, writer_b] {
+        let msg = msg.clone();
+        tokio::spawn(async move { while write_to_stream(&mut writer, &msg).await.is_ok() {} });
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Blocked messages are given up, connections are done by the deadline and not aborted
+    let started = std::time::Instant::now();
+    handle.shutdown().await;
+    assert!(
+        started.elapsed() < Duration::from_millis(1800),
+        "{:?}",
+        started.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn test_attachments() {
+    let handle = start_server().await;
+    let (mut reader_a, mut writer_a) = connect(handle.local_addr()).await;
+    let (mut reader_b, mut writer_b) = connect(handle.local_addr()).await;
+
+    let content: Vec<u8> = (0..ATTACHMENT_CHUNK_SIZE * 2 + 100)
+        .map(|i| i as u8)
+        .collect();
+    let file = MessageType::file("data.bin", content.clone());
+    write_to_stream(&mut writer_a, &file).await.unwrap();
+    assert_eq!(read_from_stream(&mut reader_b).await.unwrap(), file);
+
+    let request = MessageType::AttachmentRequest(AttachmentRequest::List);
+    write_to_stream(&mut writer_b, &request).await.unwrap();
+    let attachments = match read_from_stream(&mut reader_b).await.unwrap() {
+        MessageType::AttachmentResponse(AttachmentResponse::List(attachments)) => attachments,
+        other => panic!("Unexpected response {:?}", other),
+    };
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(attachments[0].name, "data.bin");
+
+    // Content is pulled chunk by chunk, requests are not broadcast
+    let mut downloaded = Vec::new();
+    loop {
+        let request = MessageType::AttachmentRequest(AttachmentRequest::Download {
+            id: attachments[0].id.clone(),
+            offset: downloaded.len() as u64,
+        });
+        write_to_stream(&mut writer_b, &request).await.unwrap();
+        let chunk = match read_from_stream(&mut reader_b).await.unwrap() {
+            MessageType::AttachmentResponse(AttachmentResponse::Chunk(chunk)) => chunk,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(chunk.offset, downloaded.len() as u64);
+        assert!(chunk.data.len() <= ATTACHMENT_CHUNK_SIZE);
+        downloaded.extend_from_slice(&chunk.data);
+        if chunk.is_last() {
+            break;
+        }
+    }
+    assert_eq!(downloaded, content);
+
+    let request = MessageType::AttachmentRequest(AttachmentRequest::Download {
+        id: "missing".to_string(),
+        offset: 0,
+    });
+    write_to_stream(&mut writer_b, &request).await.unwrap();
+    assert_eq!(
+        read_from_stream(&mut reader_b).await.unwrap(),
+        MessageType::AttachmentResponse(AttachmentResponse::NotFound("missing".to_string()))
+    );
+
+    // Uploads count against the quota of their sender
+    let config = RuntimeConfig {
+        user_quota_bytes: content.len() as u64 + 10,
+        ..Default::default()
+    };
+    handle.reload(config).await;
+    let request = MessageType::AttachmentRequest(AttachmentRequest::Quota);
+    write_to_stream(&mut writer_a, &request).await.unwrap();
+    assert_eq!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::AttachmentResponse(AttachmentResponse::Quota(StorageQuota {
+            used_bytes: content.len() as u64,
+            quota_bytes: Some(content.len() as u64 + 10),
+        }))
+    );
+    let file = MessageType::file("more.bin", vec![1; 100]);
+    write_to_stream(&mut writer_a, &file).await.unwrap();
+    match read_from_stream(&mut reader_a).await.unwrap() {
+        MessageType::Error(e) => assert!(e.contains("exceeds the storage quota"), "{}", e),
+        other => panic!("Unexpected response {:?}", other),
+    }
+
+    // Client A got none of the requests or responses of client B
+    handle.shutdown().await;
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Shutdown(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let handle = start_server().await;
+    let banned_uid = Uuid::new_v4();
+    let (mut reader_a, mut writer_a) = connect(handle.local_addr()).await;
+    let (mut reader_b, _writer_b) = connect_as(handle.local_addr(), banned_uid).await;
+
+    let config = RuntimeConfig {
+        rate_limit: RateLimit {
+            messages_per_sec: 0.001,
+            burst: 1,
+        },
+        motd: Some("Welcome".to_string()),
+        banned_uids: [banned_uid].into(),
+        ..Default::default()
+    };
+    let changes = handle.reload(config).await;
+    assert_eq!(changes.len(), 3, "{:?}", changes);
+
+    // Banned client is disconnected right away
+    assert!(matches!(
+        read_from_stream(&mut reader_b).await.unwrap(),
+        MessageType::Error(_)
+    ));
+    assert!(read_from_stream(&mut reader_b).await.is_err());
+
+    // Rate limit applies to connected clients
+    let msg = MessageType::Text("Hello".to_string());
+    write_to_stream(&mut writer_a, &msg).await.unwrap();
+    write_to_stream(&mut writer_a, &msg).await.unwrap();
+    assert!(matches!(
+        read_from_stream(&mut reader_a).await.unwrap(),
+        MessageType::Error(_)
+    ));
+
+    // New clients get MOTD after authentication
+    let (mut reader_c, _writer_c) = connect(handle.local_addr()).await;
+    assert_eq!(
+        read_from_stream(&mut reader_c).await.unwrap(),
+        MessageType::Text("Welcome".to_string())
+    );
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_retention() {
+    let store = Arc::new(MemoryStore::default());
+    let handle = ChatServer::new("127.0.0.1:0".parse().unwrap())
+        .store(store.clone())
+        .start()
+        .await
+        .unwrap();
+    let (mut reader_a, mut writer_a) = connect(handle.local_addr()).await;
+    for text in ["First", "Second", "Third"] {
+        let msg = MessageType::Text(text.to_string());
+        write_to_stream(&mut writer_a, &msg).await.unwrap();
+    }
+    // Answered after the messages above were stored
+    let query = MessageType::SearchRequest(SearchQuery::new("first"));
+    write_to_stream(&mut writer_a, &query).await.unwrap();
+    read_from_stream(&mut reader_a).await.unwrap();
+    assert_eq!(store.messages().await.unwrap().len(), 3);
+
+    // Changed policy is enforced right away, only the newest message is kept
+    let config = RuntimeConfig {
+        retention: RetentionPolicy {
+            messages: RetentionRule {
+                max_age: None,
+                max_per_user: Some(1),
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert_eq!(handle.reload(config).await.len(), 1);
+    let kept = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let messages = store.messages().await.unwrap();
+            if messages.len() == 1 {
+                return messages;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(kept[0].message, MessageType::Text("Third".to_string()));
+    handle.shutdown().await;
+}
@@ -0,0 +1,44 @@
+#![cfg(test)]
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::outbox::{Outbox, PushOutcome, SlowConsumerPolicy};
+
+#[tokio::test]
+async fn test_outbox_drop_oldest() {
+    let outbox = Outbox::new(2, SlowConsumerPolicy::DropOldest);
+    assert_eq!(outbox.push(1).await, PushOutcome::Queued);
+    assert_eq!(outbox.push(2).await, PushOutcome::Queued);
+    assert_eq!(outbox.push(3).await, PushOutcome::DroppedOldest);
+    assert_eq!(outbox.len(), 2);
+    assert_eq!(outbox.pop().await, Some(2));
+    assert_eq!(outbox.pop().await, Some(3));
+}
+
+#[tokio::test]
+async fn test_outbox_disconnect() {
+    let outbox = Outbox::new(1, SlowConsumerPolicy::Disconnect);
+    assert_eq!(outbox.push(1).await, PushOutcome::Queued);
+    assert_eq!(outbox.push(2).await, PushOutcome::Disconnected);
+    assert!(outbox.is_closed());
+    assert_eq!(outbox.push(3).await, PushOutcome::Closed);
+    assert_eq!(outbox.pop().await, None);
+}
+
+#[tokio::test]
+async fn test_outbox_block() {
+    let outbox = Arc::new(Outbox::new(1, SlowConsumerPolicy::Block));
+    assert_eq!(outbox.push(1).await, PushOutcome::Queued);
+
+    let sender = Arc::clone(&outbox);
+    let blocked = tokio::spawn(async move { sender.push(2).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!blocked.is_finished());
+
+    assert_eq!(outbox.pop().await, Some(1));
+    assert_eq!(blocked.await.unwrap(), PushOutcome::QueuedAfterWait);
+    assert_eq!(outbox.pop().await, Some(2));
+
+    outbox.close();
+    assert_eq!(outbox.pop().await, None);
+}
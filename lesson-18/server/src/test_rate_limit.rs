@@ -0,0 +1,37 @@
+#![cfg(test)]
+use std::time::{Duration, Instant};
+
+use crate::rate_limit::{RateLimit, RateLimiter};
+
+#[test]
+fn test_rate_limiter() {
+    let limit = RateLimit {
+        messages_per_sec: 2.0,
+        burst: 3,
+    };
+    let mut limiter = RateLimiter::new(limit);
+    let start = Instant::now();
+    // Burst is allowed, then the client has to wait
+    for _ in 0..3 {
+        assert!(limiter.check_at(limit, start));
+    }
+    assert!(!limiter.check_at(limit, start));
+    assert!(limiter.check_at(limit, start + Duration::from_millis(500)));
+    assert!(!limiter.check_at(limit, start + Duration::from_millis(600)));
+
+    // Limits can change between checks
+    assert!(limiter.check_at(RateLimit::UNLIMITED, start + Duration::from_millis(600)));
+
+    // Reserved tokens are never refused, the client waits for them instead
+    let mut limiter = RateLimiter::new(limit);
+    for _ in 0..3 {
+        assert_eq!(limiter.reserve_at(limit, start), Duration::ZERO);
+    }
+    assert_eq!(limiter.reserve_at(limit, start), Duration::from_millis(500));
+    assert_eq!(
+        limiter.reserve_at(limit, start),
+        Duration::from_millis(1000)
+    );
+    let later = start + Duration::from_millis(1000);
+    assert_eq!(limiter.reserve_at(limit, later), Duration::from_millis(500));
+}
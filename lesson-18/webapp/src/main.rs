@@ -2,39 +2,57 @@
 //!
 //! # Usage
 //!
+//! ```bash
+//! cargo run --bin webapp -- [--host <hostname>] [--port <port>] [--config <file>] [--set <section>.<key>=<value>]
 //! ```
-//! cargo run --bin webapp
-//! ```
+//!
+//! `--host` and `--port` set where the embedded chat server listens.
 //!
 //! The webapp runs on port 8000. It has very simple interface allowing to:
 //! - view and delete data of users from the db
 //! - delete specific messages
+//! - search messages, with highlighted matches (as JSON at `/search`)
+//! - preview images and download attachments
 //! - load testing data into DB
+//! - back up database and attachment contents while running (`POST /backup`)
+//! - see connected clients and send announcements to them
+//!
+//! The chat server is embedded and runs on the same runtime as the webapp.
+//! It is shut down gracefully together with the webapp (see `[default.shutdown]` in Rocket.toml),
+//! and reloads its config on SIGHUP like the standalone server.
+
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use handlebars::{handlebars_helper, Handlebars};
-use sqlx::{types::Uuid, Pool, Sqlite};
+use sqlx::types::Uuid;
 
 use rocket::response::content::RawHtml;
 use rocket::{
     self,
     form::FromForm,
     get,
-    http::Status,
+    http::{ContentType, Status},
     launch, post, routes,
     serde::{json::Json, Serialize},
     uri, State,
 };
-use rocket::{form::Form, response::Redirect};
+use rocket::{fairing::AdHoc, figment::Figment, form::Form, response::Redirect};
 use rocket_dyn_templates::{handlebars, Template};
 
-use library::db_client::{
-    auth_client, delete_message as db_delete_message, delete_user as db_delete_user,
-    get_messages_all as db_get_messages, get_messages_user as db_get_messages_user,
-    get_users as db_get_users, setup_database_pool,
-};
-use library::{db_client::save_message, metrics, Message, MessageType, User};
+use clap::Parser;
+use library::address::{resolve, with_host_port};
+use library::backup::{BackupError, BackupReport};
+use library::config::{AppConfig, ConfigArgs, WebappSection};
+use library::images::{self, THUMBNAIL_SIZES};
+use library::search::{parse_time, SearchHit, SearchQuery};
+use library::store::{open_store, Store, StoreError};
+use library::{get_timestamp, metrics, Attachment, Message, MessageType, StorageQuota, User};
 
-use server::server_main;
+use server::{
+    config::ServerConfig, counters::serve_metrics, init_logger, reload_on_sighup, ChatServer,
+    ChatServerHandle, ConnectedClient,
+};
 
 fn generate_random_message() -> String {
     use rand::{thread_rng, Rng};
@@ -46,17 +64,19 @@ fn generate_random_message() -> String {
 
 /// Dummy function to create test users and messages
 #[post("/generate_test_data")]
-async fn generate_test_data(db: &State<Pool<Sqlite>>) -> Result<Redirect, Status> {
+async fn generate_test_data(store: &State<Arc<dyn Store>>) -> Result<Redirect, Status> {
     for _i in 0..3 {
-        let _res = auth_client(db, Uuid::new_v4()).await;
+        let _res = store.auth_user(Uuid::new_v4()).await;
     }
 
-    let users = db_get_users(db).await.unwrap();
+    let users = store
+        .users()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
     for user in users {
         let uid = user.uid;
-        let _res = auth_client(db, Uuid::new_v4());
         let msg = MessageType::Text(generate_random_message());
-        let res = save_message(db, uid.to_string(), &msg).await;
+        let res = store.save_message(&uid, &msg).await;
         if res.is_err() {
             println!("Error saving message: {:?}", res);
         }
@@ -66,18 +86,50 @@ async fn generate_test_data(db: &State<Pool<Sqlite>>) -> Result<Redirect, Status
 
 /// Returns all messages of all users
 #[get("/messages")]
-async fn get_messages(db: &State<Pool<Sqlite>>) -> Json<Vec<Message>> {
-    let db_get_messages = db_get_messages(db).await.unwrap();
+async fn get_messages(store: &State<Arc<dyn Store>>) -> Result<Json<Vec<Message>>, Status> {
+    let messages = store
+        .messages()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
 
-    Json(db_get_messages)
+    Ok(Json(messages))
     // Retrieve messages from the database
 }
 
+/// Returns all attachments (files and images) without their content
+#[get("/attachments")]
+async fn get_attachments(store: &State<Arc<dyn Store>>) -> Result<Json<Vec<Attachment>>, Status> {
+    let attachments = store
+        .attachments()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(attachments))
+}
+
+/// Returns content of an attachment, thumbnails of images included (see `library::images`)
+#[get("/attachments/<id>")]
+async fn get_attachment(
+    id: &str,
+    store: &State<Arc<dyn Store>>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let chunk = store
+        .read_attachment(id, 0, usize::MAX)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+    let content_type =
+        ContentType::parse_flexible(&chunk.attachment.mime).unwrap_or(ContentType::Binary);
+    Ok((content_type, chunk.data))
+}
+
 /// Returns all users
 #[get("/users")]
-async fn get_users(db: &State<Pool<Sqlite>>) -> Json<Vec<User>> {
-    let db_get_users = db_get_users(db).await.unwrap();
-    Json(db_get_users)
+async fn get_users(store: &State<Arc<dyn Store>>) -> Result<Json<Vec<User>>, Status> {
+    let users = store
+        .users()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(users))
     // Retrieve messages from the database
 }
 
@@ -90,15 +142,69 @@ struct UserForm {
 #[post("/delete_user", data = "<user_form>")]
 async fn delete_user(
     user_form: Form<UserForm>,
-    db: &State<Pool<Sqlite>>,
+    store: &State<Arc<dyn Store>>,
 ) -> Result<Redirect, Status> {
-    let res = db_delete_user(user_form.uid.clone(), db).await;
+    let res = store.delete_user(&user_form.uid).await;
     match res {
         Ok(_) => Ok(Redirect::to(uri!(index))),
         Err(_) => Err(Status::InternalServerError),
     }
 }
 
+#[derive(FromForm)]
+struct QuotaForm {
+    uid: String,
+    /// Bytes, 0 for unlimited, empty for the default of the server
+    quota_bytes: String,
+}
+
+/// Overrides storage quota of a user
+#[post("/set_quota", data = "<quota_form>")]
+async fn set_quota(
+    quota_form: Form<QuotaForm>,
+    store: &State<Arc<dyn Store>>,
+) -> Result<Redirect, Status> {
+    let quota_bytes = match quota_form.quota_bytes.trim() {
+        "" => None,
+        quota => Some(
+            quota
+                .parse::<i64>()
+                .ok()
+                .filter(|quota| *quota >= 0)
+                .ok_or(Status::BadRequest)?,
+        ),
+    };
+    match store.set_quota(&quota_form.uid, quota_bytes).await {
+        Ok(_) => Ok(Redirect::to(uri!(index))),
+        Err(StoreError::UnknownUser(_)) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Returns clients currently connected to the chat server
+#[get("/clients")]
+async fn get_clients(chat_server: &State<ChatServerHandle>) -> Json<Vec<ConnectedClient>> {
+    Json(chat_server.connected_clients())
+}
+
+#[derive(FromForm)]
+struct AnnounceForm {
+    text: String,
+}
+
+/// Sends a text message to all connected clients
+#[post("/announce", data = "<announce_form>")]
+async fn announce(
+    announce_form: Form<AnnounceForm>,
+    chat_server: &State<ChatServerHandle>,
+) -> Result<Redirect, Status> {
+    let msg = MessageType::Text(announce_form.text.clone());
+    match chat_server.inject(msg).await {
+        Ok(_) => Ok(Redirect::to(uri!(index))),
+        Err(_) => Err(Status::ServiceUnavailable),
+    }
+}
+
 #[derive(FromForm)]
 struct DeleteMessageForm {
     id: String,
@@ -108,9 +214,9 @@ struct DeleteMessageForm {
 #[post("/delete_message", data = "<user_form>")]
 async fn delete_message(
     user_form: Form<DeleteMessageForm>,
-    db: &State<Pool<Sqlite>>,
+    store: &State<Arc<dyn Store>>,
 ) -> Result<Redirect, Status> {
-    let res = db_delete_message(user_form.id.clone(), db).await;
+    let res = store.delete_message(&user_form.id).await;
     match res {
         Ok(_) => Ok(Redirect::to(uri!(index))),
         Err(_) => Err(Status::InternalServerError),
@@ -119,12 +225,143 @@ async fn delete_message(
 
 /// Show only messages from given user by uid
 #[get("/filter_messages?<uid>")]
-async fn filter_messages(uid: String, db: &State<Pool<Sqlite>>) -> RawHtml<Template> {
+async fn filter_messages(
+    uid: String,
+    store: &State<Arc<dyn Store>>,
+    chat_server: &State<ChatServerHandle>,
+) -> Result<RawHtml<Template>, Status> {
     //-> Json<Vec<Message>> {
-    let users = db_get_users(db).await.unwrap();
-    let messages = db_get_messages_user(db, uid).await.unwrap();
-    let context = Context { users, messages };
-    RawHtml(Template::render("index", context))
+    let users = store
+        .users()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let messages = store
+        .messages_of_user(&uid)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let clients = chat_server.connected_clients();
+    let context = Context {
+        users: user_rows(users, chat_server),
+        messages,
+        clients,
+        search: None,
+    };
+    Ok(RawHtml(Template::render("index", context)))
+}
+
+/// Empty fields of the search form are sent as well
+fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|value| !value.is_empty())
+}
+
+/// Query from request parameters - `q` may contain filters as well, see `library::search`
+fn search_query(
+    q: &str,
+    uid: Option<String>,
+    since: Option<&str>,
+    until: Option<&str>,
+) -> Result<SearchQuery, Status> {
+    let now = get_timestamp().parse().unwrap_or_default();
+    let mut query = SearchQuery::parse(q, now).map_err(|_| Status::BadRequest)?;
+    if let Some(uid) = non_empty(uid.as_deref()) {
+        query.uid = Some(uid.to_string());
+    }
+    if let Some(since) = non_empty(since) {
+        query.since = Some(parse_time(since, now).map_err(|_| Status::BadRequest)?);
+    }
+    if let Some(until) = non_empty(until) {
+        query.until = Some(parse_time(until, now).map_err(|_| Status::BadRequest)?);
+    }
+    Ok(query)
+}
+
+/// Returns messages matching the query, best matches first
+#[get("/search?<q>&<uid>&<since>&<until>&<limit>")]
+async fn search_json(
+    q: &str,
+    uid: Option<String>,
+    since: Option<&str>,
+    until: Option<&str>,
+    limit: Option<u32>,
+    store: &State<Arc<dyn Store>>,
+) -> Result<Json<Vec<SearchHit>>, Status> {
+    let mut query = search_query(q, uid, since, until)?;
+    if let Some(limit) = limit {
+        query.limit = limit;
+    }
+    let hits = store
+        .search(&query)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(hits))
+}
+
+/// Shows messages matching the query above all messages
+#[get("/search_messages?<q>&<uid>&<since>&<until>")]
+async fn search_messages(
+    q: &str,
+    uid: Option<String>,
+    since: Option<&str>,
+    until: Option<&str>,
+    store: &State<Arc<dyn Store>>,
+    chat_server: &State<ChatServerHandle>,
+) -> Result<RawHtml<Template>, Status> {
+    let query = search_query(q, uid, since, until)?;
+    let hits = store
+        .search(&query)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let users = store
+        .users()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let messages = store
+        .messages()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let context = Context {
+        users: user_rows(users, chat_server),
+        messages,
+        clients: chat_server.connected_clients(),
+        search: Some(SearchResults {
+            query: q.to_string(),
+            hits: hits.into_iter().map(HitRow::from).collect(),
+        }),
+    };
+    Ok(RawHtml(Template::render("index", context)))
+}
+
+/// Where backups are written, `storage.backups_dir`
+struct BackupsDir(PathBuf);
+
+/// Backup written by `/backup`
+#[derive(Serialize)]
+struct BackupInfo {
+    path: PathBuf,
+    #[serde(flatten)]
+    report: BackupReport,
+}
+
+/// Backs up database and blobs into a new directory under `storage.backups_dir`
+#[post("/backup")]
+async fn backup(
+    store: &State<Arc<dyn Store>>,
+    backups_dir: &State<BackupsDir>,
+) -> Result<Json<BackupInfo>, Status> {
+    let path = backups_dir.0.join(get_timestamp());
+    match store.backup(&path).await {
+        Ok(report) => {
+            log::info!("Backed up {} to {}", report, path.display());
+            Ok(Json(BackupInfo { path, report }))
+        }
+        Err(BackupError::Unsupported(_)) => Err(Status::NotImplemented),
+        // Two backups started in the same millisecond
+        Err(BackupError::Exists(_)) => Err(Status::Conflict),
+        Err(e) => {
+            log::error!("Backup to {} failed: {}", path.display(), e);
+            Err(Status::InternalServerError)
+        }
+    }
 }
 
 //#[derive(Responder)]
@@ -140,36 +377,188 @@ async fn get_metrics_endpoint() -> Result<String, Status> {
     Ok(data)
 }
 
+/// User with its storage usage, as shown in the users table
+#[derive(Serialize)]
+struct UserRow {
+    uid: String,
+    used_bytes: i64,
+    /// Effective quota and where it comes from
+    quota: String,
+}
+
+fn user_rows(users: Vec<User>, chat_server: &ChatServerHandle) -> Vec<UserRow> {
+    let default_bytes = chat_server.reloader().current().user_quota_bytes;
+    users
+        .into_iter()
+        .map(|user| {
+            let limit = match StorageQuota::of(&user, default_bytes).quota_bytes {
+                Some(bytes) => format!("{} bytes", bytes),
+                None => "unlimited".to_string(),
+            };
+            let source = match user.quota_bytes {
+                Some(_) => "set by admin",
+                None => "default",
+            };
+            UserRow {
+                uid: user.uid,
+                used_bytes: user.used_bytes,
+                quota: format!("{} ({})", limit, source),
+            }
+        })
+        .collect()
+}
+
+/// Part of a snippet, highlighted if it matches the search
+#[derive(Serialize)]
+struct SnippetPart {
+    text: String,
+    highlight: bool,
+}
+
+/// Search hit with its snippet split for the template, which escapes each part
+#[derive(Serialize)]
+struct HitRow {
+    #[serde(flatten)]
+    hit: SearchHit,
+    parts: Vec<SnippetPart>,
+}
+
+impl From<SearchHit> for HitRow {
+    fn from(hit: SearchHit) -> Self {
+        let parts = hit
+            .segments()
+            .into_iter()
+            .map(|(text, highlight)| SnippetPart {
+                text: text.to_string(),
+                highlight,
+            })
+            .collect();
+        HitRow { hit, parts }
+    }
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    query: String,
+    hits: Vec<HitRow>,
+}
+
 #[derive(Serialize)]
 struct Context {
-    users: Vec<User>,
+    users: Vec<UserRow>,
     messages: Vec<Message>,
+    clients: Vec<ConnectedClient>,
+    search: Option<SearchResults>,
 }
 
 #[get("/")]
-async fn index(db: &State<Pool<Sqlite>>) -> RawHtml<Template> {
-    let users = db_get_users(db).await.unwrap();
-    let messages = db_get_messages(db).await.unwrap();
-    let context = Context { users, messages };
-    RawHtml(Template::render("index", context))
+async fn index(
+    store: &State<Arc<dyn Store>>,
+    chat_server: &State<ChatServerHandle>,
+) -> Result<RawHtml<Template>, Status> {
+    let users = store
+        .users()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let messages = store
+        .messages()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let clients = chat_server.connected_clients();
+    let context = Context {
+        users: user_rows(users, chat_server),
+        messages,
+        clients,
+        search: None,
+    };
+    Ok(RawHtml(Template::render("index", context)))
 }
 
 handlebars_helper!(message_as_str: |msg: MessageType| msg.to_string());
+handlebars_helper!(thumbnail_id: |id: String| images::thumbnail_id(&id, THUMBNAIL_SIZES[0]));
+
+/// Rocket settings - Rocket.toml, overridden by whatever is set in the `[webapp]` config section
+fn rocket_figment(config: &WebappSection) -> Figment {
+    let mut figment = rocket::Config::figment();
+    if let Some(address) = &config.address {
+        figment = figment.merge(("address", address));
+    }
+    if let Some(port) = config.port {
+        figment = figment.merge(("port", port));
+    }
+    if let Some(template_dir) = &config.template_dir {
+        figment = figment.merge(("template_dir", template_dir));
+    }
+    figment
+}
+
+/// Chat webapp - web interface with an embedded chat server
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Hostname, IPv4 or IPv6 address the chat server listens on [default: from server.address]
+    #[arg(long)]
+    host: Option<String>,
+    /// Port the chat server listens on [default: from server.address]
+    #[arg(long)]
+    port: Option<u16>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Prints error and exits with the same code as for invalid arguments
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(2);
+}
 
 #[launch]
 async fn webapp() -> _ {
-    simple_logger::SimpleLogger::new().env().init().unwrap();
     let _dotenv = dotenvy::dotenv();
+    let args = Args::parse();
+    let config = AppConfig::load_or_exit(&args.config);
+    let address = with_host_port(&config.server.address, args.host.as_deref(), args.port)
+        .unwrap_or_else(|e| exit_with(e));
+    let server_config = ServerConfig::from_app_config(&config).unwrap_or_else(|e| exit_with(e));
+    init_logger(config.log_level());
     metrics::init_counters();
+    if config.metrics.enabled {
+        let metrics_addr = config.metrics.address.parse().unwrap();
+        rocket::tokio::spawn(async move {
+            if let Err(e) = serve_metrics(metrics_addr).await {
+                log::error!("Cannot serve metrics: {}", e);
+            }
+        });
+    }
 
-    rocket::tokio::task::spawn_blocking(|| server_main());
+    // One store shared by the webapp and the embedded chat server
+    let store = open_store(&server_config.database)
+        .await
+        .unwrap_or_else(|e| exit_with(e));
+    let chat_server = ChatServer::new(resolve(&address).await.unwrap_or_else(|e| exit_with(e)))
+        .store(Arc::clone(&store))
+        .config(server_config.clone())
+        .start()
+        .await
+        .unwrap_or_else(|e| exit_with(e));
+    rocket::tokio::spawn(reload_on_sighup(
+        chat_server.reloader(),
+        args.config,
+        server_config,
+    ));
 
-    rocket::build()
+    let template_dir = config
+        .webapp
+        .template_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("./webapp/templates"));
+    rocket::custom(rocket_figment(&config.webapp))
         //.attach(Template::fairing())
         .attach(Template::custom(move |engines| {
             let mut handlebars = Handlebars::new();
-            let _ = handlebars.register_templates_directory(".hbs", "./webapp/templates");
+            let _ = handlebars.register_templates_directory(".hbs", &template_dir);
             handlebars.register_helper("message_as_str", Box::new(message_as_str));
+            handlebars.register_helper("thumbnail_id", Box::new(thumbnail_id));
             engines.handlebars = handlebars;
         }))
         .mount(
@@ -177,13 +566,33 @@ async fn webapp() -> _ {
             routes![
                 index,
                 get_messages,
+                get_attachments,
+                get_attachment,
                 get_users,
+                get_clients,
+                announce,
                 delete_user,
+                set_quota,
                 delete_message,
                 filter_messages,
+                search_json,
+                search_messages,
                 generate_test_data,
+                backup,
                 get_metrics_endpoint
             ],
         )
-        .manage(setup_database_pool().await.unwrap())
+        .manage(store)
+        .manage(chat_server)
+        .manage(BackupsDir(config.storage.backups_dir.clone()))
+        .attach(AdHoc::on_shutdown("Chat server shutdown", |rocket| {
+            Box::pin(async move {
+                if let Some(chat_server) = rocket.state::<ChatServerHandle>() {
+                    chat_server.shutdown().await;
+                }
+                if let Some(store) = rocket.state::<Arc<dyn Store>>() {
+                    store.close().await;
+                }
+            })
+        }))
 }